@@ -1,4 +1,2 @@
 #!/usr/bin/bash
-cargo run --release -- $1 -f $2 --name out --tile-width 500 --tile-height 500 &&
-ffmpeg -i out_%4d.png -framerate 60 $(basename $1).mp4 #&&
-#rm out_*.png
+cargo run --release -- $1 -f $2 --tile-width 500 --tile-height 500 --video $(basename $1).mp4
@@ -0,0 +1,309 @@
+use crate::output::{channels, commit_partial, partial_path, png_samples, Sink};
+use crate::quantize::{build_palette, remap};
+use crate::settings::{BitDepth, PaletteMode, Settings};
+use anyhow::{ensure, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Alpha below this is treated as fully transparent in GIF output
+const GIF_ALPHA_THRESHOLD: u8 = 128;
+
+/// Animated GIF writer. With a global palette, frames are held until `finish()` so that the
+/// palette can represent all of them. The animation is written to a partial file which only
+/// replaces `path` once complete
+pub struct GifSink {
+    cfg: Settings,
+    path: PathBuf,
+    file: Option<BufWriter<File>>,
+    encoder: Option<gif::Encoder<BufWriter<File>>>,
+    pending: Vec<Vec<u8>>,
+}
+
+impl GifSink {
+    pub fn new(cfg: &Settings, path: &Path) -> Result<Self> {
+        ensure!(
+            cfg.width <= u16::MAX as u32 && cfg.height <= u16::MAX as u32,
+            "GIF dimensions are limited to {}x{}",
+            u16::MAX,
+            u16::MAX
+        );
+        ensure!(
+            cfg.gif_palette == PaletteMode::PerFrame || cfg.frame_count().is_some(),
+            "A global GIF palette needs a finite number of frames"
+        );
+
+        let file = File::create(partial_path(path))
+            .with_context(|| format!("Failed to create animation {}", path.display()))?;
+
+        Ok(Self {
+            cfg: cfg.clone(),
+            path: path.to_path_buf(),
+            file: Some(BufWriter::new(file)),
+            encoder: None,
+            pending: vec![],
+        })
+    }
+
+    /// The encoder, writing the header with `global_palette` on first use
+    fn encoder(&mut self, global_palette: &[u8]) -> Result<&mut gif::Encoder<BufWriter<File>>> {
+        if self.encoder.is_none() {
+            let file = self.file.take().expect("GIF file already consumed");
+            let (width, height) = (self.cfg.width as u16, self.cfg.height as u16);
+            let mut encoder = gif::Encoder::new(file, width, height, global_palette)?;
+            encoder.set_repeat(match self.cfg.loop_count {
+                0 => gif::Repeat::Infinite,
+                n => gif::Repeat::Finite(n),
+            })?;
+            self.encoder = Some(encoder);
+        }
+        Ok(self.encoder.as_mut().unwrap())
+    }
+
+    /// Convert to 8-bit RGB pixels and an opacity mask
+    fn split_pixels(&self, data: &[u8]) -> (Vec<[u8; 3]>, Option<Vec<bool>>) {
+        let bytes_per_channel = self.cfg.depth.bytes_per_channel();
+        let channels = channels(&self.cfg);
+
+        // Most significant byte of each sample
+        let msb = if bytes_per_channel == 2 && cfg!(target_endian = "little") {
+            1
+        } else {
+            0
+        };
+        let sample = |px: &[u8], c: usize| px[c * bytes_per_channel + msb];
+
+        let pixels = data.chunks_exact(channels * bytes_per_channel);
+        let rgb = pixels
+            .clone()
+            .map(|px| [sample(px, 0), sample(px, 1), sample(px, 2)])
+            .collect();
+        let opaque = match channels {
+            4 => Some(
+                pixels
+                    .map(|px| sample(px, 3) >= GIF_ALPHA_THRESHOLD)
+                    .collect(),
+            ),
+            _ => None,
+        };
+
+        (rgb, opaque)
+    }
+
+    /// Palette size, leaving room for a transparent entry when there is alpha
+    fn max_colors(&self) -> usize {
+        if channels(&self.cfg) == 4 {
+            255
+        } else {
+            256
+        }
+    }
+
+    /// Flattened palette, with the transparent entry appended when there is alpha
+    fn flat_palette(&self, palette: &[[u8; 3]]) -> Vec<u8> {
+        let mut flat: Vec<u8> = palette.iter().flatten().copied().collect();
+        if channels(&self.cfg) == 4 {
+            flat.extend_from_slice(&[0; 3]);
+        }
+        flat
+    }
+
+    /// Quantize and write a frame. The palette is stored with the frame if `local` is set, and
+    /// is otherwise assumed to be the global palette
+    fn write_indexed(
+        &mut self,
+        rgb: &[[u8; 3]],
+        opaque: Option<Vec<bool>>,
+        palette: &[[u8; 3]],
+        local: bool,
+    ) -> Result<()> {
+        let width = self.cfg.width as usize;
+        let mut indices = remap(rgb, width, palette, self.cfg.gif_dither);
+
+        // The transparent color is the entry just past the palette
+        let transparent = opaque.map(|opaque| {
+            let idx = palette.len() as u8;
+            for (index, opaque) in indices.iter_mut().zip(opaque) {
+                if !opaque {
+                    *index = idx;
+                }
+            }
+            idx
+        });
+
+        let (num, den) = self.cfg.frame_rate();
+        let mut frame = gif::Frame::from_indexed_pixels(
+            self.cfg.width as u16,
+            self.cfg.height as u16,
+            &indices,
+            transparent,
+        );
+        frame.delay = ((100 * den) as f32 / num as f32).round() as u16;
+        if transparent.is_some() {
+            frame.dispose = gif::DisposalMethod::Background;
+        }
+
+        let flat_palette = self.flat_palette(palette);
+        if local {
+            frame.palette = Some(flat_palette);
+            self.encoder(&[])?.write_frame(&frame)?;
+        } else {
+            self.encoder(&flat_palette)?.write_frame(&frame)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Sink for GifSink {
+    fn write_frame(&mut self, _frame_idx: usize, data: &[u8]) -> Result<()> {
+        match self.cfg.gif_palette {
+            PaletteMode::Global => self.pending.push(data.to_vec()),
+            PaletteMode::PerFrame => {
+                let (rgb, opaque) = self.split_pixels(data);
+                let palette = build_palette(&rgb, self.max_colors(), self.cfg.quantizer);
+                self.write_indexed(&rgb, opaque, &palette, true)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if !self.pending.is_empty() {
+            let frames: Vec<_> = std::mem::take(&mut self.pending)
+                .iter()
+                .map(|data| self.split_pixels(data))
+                .collect();
+
+            let all_pixels: Vec<[u8; 3]> =
+                frames.iter().flat_map(|(rgb, _)| rgb).copied().collect();
+            let palette = build_palette(&all_pixels, self.max_colors(), self.cfg.quantizer);
+            drop(all_pixels);
+
+            for (rgb, opaque) in frames {
+                self.write_indexed(&rgb, opaque, &palette, false)?;
+            }
+        }
+
+        // Releasing the encoder writes the trailer
+        if let Some(encoder) = self.encoder.take() {
+            encoder.into_inner()?.flush()?;
+            commit_partial(&self.path)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Animated PNG writer. The number of frames is declared up front, so the animation only
+/// replaces `path` once all of them have been written
+pub struct ApngSink {
+    path: PathBuf,
+    writer: Option<png::Writer<BufWriter<File>>>,
+    sixteen_bit: bool,
+    frames: usize,
+    written: usize,
+}
+
+impl ApngSink {
+    pub fn new(cfg: &Settings, path: &Path) -> Result<Self> {
+        let frames = cfg
+            .frame_count()
+            .context("APNG output needs a finite number of frames")?;
+
+        let file = File::create(partial_path(path))
+            .with_context(|| format!("Failed to create animation {}", path.display()))?;
+
+        let mut encoder = png::Encoder::new(BufWriter::new(file), cfg.width, cfg.height);
+        encoder.set_color(match channels(cfg) {
+            4 => png::ColorType::Rgba,
+            _ => png::ColorType::Rgb,
+        });
+        let sixteen_bit = cfg.depth == BitDepth::Sixteen;
+        encoder.set_depth(if sixteen_bit {
+            png::BitDepth::Sixteen
+        } else {
+            png::BitDepth::Eight
+        });
+        encoder.set_animated(frames as u32, cfg.loop_count as u32)?;
+
+        // Frame delay in seconds is the reciprocal of the frame rate
+        let (num, den) = cfg.frame_rate();
+        let (delay_num, delay_den) = fit_u16(den, num);
+        encoder.set_frame_delay(delay_num, delay_den)?;
+
+        let writer = encoder.write_header()?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            writer: Some(writer),
+            sixteen_bit,
+            frames,
+            written: 0,
+        })
+    }
+}
+
+/// Scale a fraction down until both parts fit in a u16
+fn fit_u16(mut num: u32, mut den: u32) -> (u16, u16) {
+    while num > u16::MAX as u32 || den > u16::MAX as u32 {
+        num = num.div_ceil(2);
+        den = den.div_ceil(2);
+    }
+    (num as u16, den.max(1) as u16)
+}
+
+impl Sink for ApngSink {
+    fn write_frame(&mut self, _frame_idx: usize, data: &[u8]) -> Result<()> {
+        let writer = self.writer.as_mut().context("APNG already finished")?;
+        writer.write_image_data(&png_samples(data, self.sixteen_bit))?;
+        self.written += 1;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        if let Some(writer) = self.writer.take() {
+            // An APNG with fewer frames than it declares is invalid
+            ensure!(
+                self.written == self.frames,
+                "Only {} of the {} frames of {} were rendered, so it was not written",
+                self.written,
+                self.frames,
+                self.path.display()
+            );
+            writer.finish()?;
+            commit_partial(&self.path)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_apng_frames() {
+        let dir = std::env::temp_dir().join(format!("bosrender_apng_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.png");
+        let cfg = Settings::from_iter(&["bosrender", "a.frag", "-w", "2", "-h", "2", "-f", "2"]);
+        let frame = [7; 2 * 2 * 3];
+
+        // Stopping short of the declared frames leaves no animation behind
+        let mut apng = ApngSink::new(&cfg, &path).unwrap();
+        apng.write_frame(0, &frame).unwrap();
+        assert!(apng.finish().is_err());
+        assert!(!path.exists());
+
+        let mut apng = ApngSink::new(&cfg, &path).unwrap();
+        apng.write_frame(0, &frame).unwrap();
+        apng.write_frame(1, &frame).unwrap();
+        apng.finish().unwrap();
+        assert!(path.exists());
+        assert!(!partial_path(&path).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
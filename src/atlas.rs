@@ -0,0 +1,365 @@
+use crate::output::{bytes_per_pixel, channels, write_image, Sink};
+use crate::settings::Settings;
+use crate::tiles::blit;
+use anyhow::{ensure, Context, Result};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+/// 3x5 bitmaps of the digits 0-9, row-major from the top left, one bit per pixel
+const DIGITS: [u16; 10] = [
+    0b111_101_101_101_111,
+    0b010_110_010_010_111,
+    0b111_001_111_100_111,
+    0b111_001_111_001_111,
+    0b101_101_111_001_001,
+    0b111_100_111_001_111,
+    0b111_100_111_101_111,
+    0b111_001_001_001_001,
+    0b111_101_111_101_111,
+    0b111_101_111_001_111,
+];
+
+/// Places each frame into a cell of a grid image, written along with a JSON descriptor once all
+/// frames have arrived
+pub struct AtlasSink {
+    cfg: Settings,
+    path: PathBuf,
+    layout: AtlasLayout,
+    image: Vec<u8>,
+    frames: Vec<usize>,
+}
+
+/// Cell and grid dimensions of an atlas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AtlasLayout {
+    pub cell: (usize, usize),
+    pub columns: usize,
+    pub rows: usize,
+    pub padding: usize,
+}
+
+impl AtlasLayout {
+    pub fn new(frame: (usize, usize), frames: usize, cfg: &Settings) -> Self {
+        let columns = cfg
+            .atlas_columns
+            .unwrap_or_else(|| (frames as f32).sqrt().ceil() as usize)
+            .max(1);
+        let scale = |v: usize| ((v as f32 * cfg.atlas_scale).round() as usize).max(1);
+
+        Self {
+            cell: (scale(frame.0), scale(frame.1)),
+            columns,
+            rows: frames.div_ceil(columns),
+            padding: cfg.atlas_padding,
+        }
+    }
+
+    /// Dimensions of the whole atlas
+    pub fn size(&self) -> (usize, usize) {
+        (
+            self.columns * (self.cell.0 + self.padding) + self.padding,
+            self.rows * (self.cell.1 + self.padding) + self.padding,
+        )
+    }
+
+    /// Top-left corner of the `slot`th cell
+    pub fn cell_pos(&self, slot: usize) -> (usize, usize) {
+        (
+            self.padding + (slot % self.columns) * (self.cell.0 + self.padding),
+            self.padding + (slot / self.columns) * (self.cell.1 + self.padding),
+        )
+    }
+}
+
+impl AtlasSink {
+    pub fn new(cfg: &Settings, path: &Path) -> Result<Self> {
+        let frames = cfg
+            .frame_count()
+            .context("Atlas output needs a finite number of frames")?;
+        ensure!(cfg.atlas_scale > 0., "Atlas scale must be positive");
+
+        let layout = AtlasLayout::new((cfg.width as _, cfg.height as _), frames, cfg);
+        let (width, height) = layout.size();
+
+        Ok(Self {
+            cfg: cfg.clone(),
+            path: path.to_path_buf(),
+            layout,
+            image: vec![0; width * height * bytes_per_pixel(cfg)],
+            frames: vec![],
+        })
+    }
+
+    fn write_descriptor(&self) -> Result<()> {
+        let (width, height) = self.layout.size();
+        let (cell_width, cell_height) = self.layout.cell;
+        let image_name = self
+            .path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let mut json = String::new();
+        writeln!(json, "{{")?;
+        writeln!(json, "  \"image\": {},", json_string(&image_name))?;
+        writeln!(json, "  \"width\": {},", width)?;
+        writeln!(json, "  \"height\": {},", height)?;
+        writeln!(json, "  \"columns\": {},", self.layout.columns)?;
+        writeln!(json, "  \"rows\": {},", self.layout.rows)?;
+        writeln!(json, "  \"padding\": {},", self.layout.padding)?;
+        // Frames play for one video frame each, in seconds; "time" is the shader's time
+        let (num, den) = self.cfg.frame_rate();
+        let duration = den as f64 / num as f64;
+        writeln!(json, "  \"frames\": [")?;
+        for (slot, &frame_idx) in self.frames.iter().enumerate() {
+            let (x, y) = self.layout.cell_pos(slot);
+            let separator = if slot + 1 == self.frames.len() {
+                ""
+            } else {
+                ","
+            };
+            write!(
+                json,
+                "    {{ \"index\": {}, \"time\": {}, \"duration\": {}, ",
+                frame_idx,
+                self.cfg.frame_time(frame_idx),
+                duration,
+            )?;
+            writeln!(
+                json,
+                "\"x\": {}, \"y\": {}, \"w\": {}, \"h\": {} }}{}",
+                x, y, cell_width, cell_height, separator
+            )?;
+        }
+        writeln!(json, "  ]")?;
+        writeln!(json, "}}")?;
+
+        let path = self.path.with_extension("json");
+        std::fs::write(&path, json)
+            .with_context(|| format!("Failed to write atlas descriptor {}", path.display()))
+    }
+}
+
+impl Sink for AtlasSink {
+    fn write_frame(&mut self, frame_idx: usize, data: &[u8]) -> Result<()> {
+        let slot = self.frames.len();
+        ensure!(
+            slot < self.layout.columns * self.layout.rows,
+            "More frames than atlas cells"
+        );
+
+        let frame_dims = (self.cfg.width as usize, self.cfg.height as usize);
+        let channels = channels(&self.cfg);
+        let bytes_per_channel = self.cfg.depth.bytes_per_channel();
+
+        let mut cell = if self.layout.cell == frame_dims {
+            data.to_vec()
+        } else {
+            downscale(
+                data,
+                frame_dims,
+                self.layout.cell,
+                channels,
+                bytes_per_channel,
+            )
+        };
+
+        if self.cfg.atlas_labels {
+            draw_label(
+                &mut cell,
+                self.layout.cell,
+                channels,
+                bytes_per_channel,
+                &frame_idx.to_string(),
+            );
+        }
+
+        blit(
+            &cell,
+            &mut self.image,
+            self.layout.cell_pos(slot),
+            self.layout.size(),
+            self.layout.cell,
+            channels * bytes_per_channel,
+        );
+        self.frames.push(frame_idx);
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        let (width, height) = self.layout.size();
+        write_image(
+            &self.cfg,
+            (width as u32, height as u32),
+            &self.image,
+            &self.path,
+        )?;
+        self.write_descriptor()
+    }
+}
+
+/// Resize an image by averaging the source pixels covered by each destination pixel
+pub fn downscale(
+    data: &[u8],
+    (width, height): (usize, usize),
+    (new_width, new_height): (usize, usize),
+    channels: usize,
+    bytes_per_channel: usize,
+) -> Vec<u8> {
+    let pixel_size = channels * bytes_per_channel;
+    let read = |idx: usize| -> u32 {
+        match bytes_per_channel {
+            1 => data[idx] as u32,
+            _ => u16::from_ne_bytes([data[idx], data[idx + 1]]) as u32,
+        }
+    };
+
+    // Source span covered by destination pixel `i` along an axis
+    let span = |i: usize, src: usize, dst: usize| {
+        let start = i * src / dst;
+        let end = ((i + 1) * src / dst).max(start + 1).min(src);
+        start..end
+    };
+
+    let mut out = Vec::with_capacity(new_width * new_height * pixel_size);
+    for y in 0..new_height {
+        let rows = span(y, height, new_height);
+        for x in 0..new_width {
+            let cols = span(x, width, new_width);
+            let count = (rows.len() * cols.len()) as u32;
+            for c in 0..channels {
+                let mut sum = 0;
+                for sy in rows.clone() {
+                    for sx in cols.clone() {
+                        sum += read((sy * width + sx) * pixel_size + c * bytes_per_channel);
+                    }
+                }
+                let value = (sum + count / 2) / count;
+                match bytes_per_channel {
+                    1 => out.push(value as u8),
+                    _ => out.extend_from_slice(&(value as u16).to_ne_bytes()),
+                }
+            }
+        }
+    }
+
+    out
+}
+
+/// Draw `text` (digits only) in opaque white with a dark shadow at the top left of an image
+fn draw_label(
+    image: &mut [u8],
+    (width, height): (usize, usize),
+    channels: usize,
+    bytes_per_channel: usize,
+    text: &str,
+) {
+    let scale = (height / 64).max(1);
+    let margin = 2 * scale;
+    let pixel_size = channels * bytes_per_channel;
+    let color_size = 3 * bytes_per_channel;
+
+    let mut fill = |x: usize, y: usize, value: u8| {
+        for py in y * scale..(y + 1) * scale {
+            for px in x * scale..(x + 1) * scale {
+                let (px, py) = (px + margin, py + margin);
+                if px < width && py < height {
+                    let pixel = &mut image[(py * width + px) * pixel_size..][..pixel_size];
+                    let (color, alpha) = pixel.split_at_mut(color_size);
+                    color.iter_mut().for_each(|b| *b = value);
+                    alpha.iter_mut().for_each(|b| *b = 0xFF);
+                }
+            }
+        }
+    };
+
+    for (shadow, value) in [(1, 0x00), (0, 0xFF)] {
+        for (position, digit) in text.chars().filter_map(|c| c.to_digit(10)).enumerate() {
+            let bitmap = DIGITS[digit as usize];
+            for row in 0..5 {
+                for col in 0..3 {
+                    if bitmap >> (14 - (row * 3 + col)) & 1 == 1 {
+                        fill(position * 4 + col + shadow, row + shadow, value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32).unwrap(),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    #[test]
+    fn test_layout() {
+        let cfg = Settings::from_iter(&[
+            "bosrender",
+            "a.frag",
+            "--atlas-scale",
+            "0.5",
+            "--atlas-padding",
+            "2",
+        ]);
+        let layout = AtlasLayout::new((100, 50), 10, &cfg);
+        assert_eq!(layout.cell, (50, 25));
+        assert_eq!((layout.columns, layout.rows), (4, 3));
+        assert_eq!(layout.size(), (4 * 52 + 2, 3 * 27 + 2));
+        assert_eq!(layout.cell_pos(0), (2, 2));
+        assert_eq!(layout.cell_pos(5), (2 + 52, 2 + 27));
+    }
+
+    #[test]
+    fn test_downscale_averages() {
+        #[rustfmt::skip]
+        let data = [
+            0, 10,   20, 30,
+            40, 50,  60, 70,
+        ];
+        // 2x2 pixels, two channels each, down to a single pixel
+        let out = downscale(&data, (2, 2), (1, 1), 2, 1);
+        assert_eq!(out, vec![30, 40]);
+
+        let wide: Vec<u8> = [1000u16, 3000]
+            .iter()
+            .flat_map(|v| v.to_ne_bytes())
+            .collect();
+        let out = downscale(&wide, (2, 1), (1, 1), 1, 2);
+        assert_eq!(out, 2000u16.to_ne_bytes().to_vec());
+    }
+
+    #[test]
+    fn test_label_is_opaque() {
+        let (width, height) = (16, 8);
+        let mut image = vec![0; width * height * 4];
+        draw_label(&mut image, (width, height), 4, 1, "1");
+
+        // The top of the "1" glyph is at column 1 of the glyph, offset by the margin
+        let idx = (2 * width + 3) * 4;
+        assert_eq!(&image[idx..idx + 4], &[0xFF; 4]);
+
+        // Untouched pixels stay transparent
+        assert_eq!(&image[..4], &[0; 4]);
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+    }
+}
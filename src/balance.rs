@@ -0,0 +1,128 @@
+use std::time::Duration;
+
+/// Weight of the latest measurement in each device's smoothed cost
+const SMOOTHING: f64 = 0.25;
+
+/// Spreads tiles across devices of different speeds. Each tile goes to the device expected to
+/// finish it first, given the pixels already queued on it and how long it has been taking per
+/// pixel, even if that means waiting for the device to have room. Devices not measured yet are
+/// assumed to be as fast as the average
+pub struct Balancer {
+    /// Tiles each device can have in flight
+    capacity: usize,
+    devices: Vec<Load>,
+}
+
+#[derive(Default)]
+struct Load {
+    tiles: usize,
+    pixels: u64,
+    /// Smoothed seconds per pixel
+    cost: Option<f64>,
+}
+
+impl Balancer {
+    pub fn new(devices: usize, capacity: usize) -> Self {
+        Self {
+            capacity,
+            devices: (0..devices).map(|_| Load::default()).collect(),
+        }
+    }
+
+    /// Number of tiles all devices can have in flight together
+    pub fn capacity(&self) -> usize {
+        self.capacity * self.devices.len()
+    }
+
+    /// Device to render a tile of `pixels` pixels on, or `None` if the device that would finish it
+    /// first is full, so the tile is better off waiting for it than queued on a slower one
+    pub fn pick(&self, pixels: u64) -> Option<usize> {
+        let known: Vec<f64> = self.devices.iter().filter_map(|load| load.cost).collect();
+        let average = match known.len() {
+            0 => 1.,
+            n => known.iter().sum::<f64>() / n as f64,
+        };
+
+        self.devices
+            .iter()
+            .enumerate()
+            .map(|(device, load)| {
+                let finish = (load.pixels + pixels) as f64 * load.cost.unwrap_or(average);
+                (device, finish)
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(device, _)| device)
+            .filter(|&device| self.has_room(device))
+    }
+
+    /// Whether a device can take another tile
+    pub fn has_room(&self, device: usize) -> bool {
+        self.devices[device].tiles < self.capacity
+    }
+
+    /// Note that a tile of `pixels` pixels was submitted to `device`
+    pub fn submitted(&mut self, device: usize, pixels: u64) {
+        let load = &mut self.devices[device];
+        load.tiles += 1;
+        load.pixels += pixels;
+    }
+
+    /// Note that a tile of `pixels` pixels came back from `device`, having taken `time` on it if
+    /// that was measured
+    pub fn finished(&mut self, device: usize, pixels: u64, time: Option<Duration>) {
+        let load = &mut self.devices[device];
+        load.tiles -= 1;
+        load.pixels -= pixels;
+        if let Some(time) = time.filter(|_| pixels > 0) {
+            let cost = time.as_secs_f64() / pixels as f64;
+            load.cost = Some(match load.cost {
+                Some(old) => old + SMOOTHING * (cost - old),
+                None => cost,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_balancer() {
+        let mut balancer = Balancer::new(2, 8);
+        assert_eq!(balancer.capacity(), 16);
+
+        // Unmeasured devices take turns
+        assert_eq!(balancer.pick(100), Some(0));
+        balancer.submitted(0, 100);
+        assert_eq!(balancer.pick(100), Some(1));
+        balancer.submitted(1, 100);
+
+        // Device 1 turns out 4.5 times slower, so device 0 gets most of the work
+        balancer.finished(0, 100, Some(Duration::from_millis(10)));
+        balancer.finished(1, 100, Some(Duration::from_millis(45)));
+        let mut counts = [0; 2];
+        for _ in 0..6 {
+            let device = balancer.pick(100).unwrap();
+            balancer.submitted(device, 100);
+            counts[device] += 1;
+        }
+        assert_eq!(counts, [5, 1]);
+
+        // Full devices are passed over, unless it's quicker to wait for them
+        let mut balancer = Balancer::new(2, 1);
+        balancer.submitted(0, 1);
+        assert_eq!(balancer.pick(1), Some(1));
+        balancer.submitted(1, 1);
+        assert_eq!(balancer.pick(1), None);
+        assert!(!balancer.has_room(1));
+        balancer.finished(0, 1, Some(Duration::from_millis(1)));
+        balancer.finished(1, 1, Some(Duration::from_millis(10)));
+        balancer.submitted(0, 1);
+        assert_eq!(balancer.pick(1), None);
+        assert_eq!(balancer.pick(10), None);
+        balancer.finished(0, 1, None);
+        balancer.submitted(0, 20);
+        assert_eq!(balancer.pick(1), Some(1));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::settings::Settings;
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use std::path::Path;
+use std::time::Duration;
+use structopt::StructOpt;
+use toml::value::{Table, Value};
+
+/// A render listed in a batch manifest
+#[derive(Debug)]
+pub struct Job {
+    /// The job's name, or its shader's file stem if it has none
+    pub label: String,
+    pub cfg: Settings,
+}
+
+/// How a job went
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Status {
+    Finished,
+    /// Interrupted, with the frames in progress finished
+    Stopped,
+    /// Not started, as an earlier job was interrupted
+    Skipped,
+    Failed(String),
+}
+
+/// A job's status, how many frames it finished and how long it took
+#[derive(Debug, Clone)]
+pub struct Outcome {
+    pub label: String,
+    pub status: Status,
+    pub frames: usize,
+    pub time: Duration,
+}
+
+/// Read the jobs of a TOML batch manifest
+pub fn load(path: &Path) -> Result<Vec<Job>> {
+    let text = std::fs::read_to_string(path)
+        .with_context(|| format!("Failed to read manifest {}", path.display()))?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    parse(&text, dir).with_context(|| format!("In manifest {}", path.display()))
+}
+
+/// Jobs of a manifest whose shader paths are relative to `dir`. Each `[[job]]` table has a
+/// `shader` and any settings to change, named as their command line options are but with
+/// underscores, and a `uniforms` table. The `[defaults]` table holds settings for every job
+pub fn parse(text: &str, dir: &Path) -> Result<Vec<Job>> {
+    let manifest: Table = toml::from_str(text)?;
+    if let Some(key) = manifest
+        .keys()
+        .find(|key| *key != "defaults" && *key != "job")
+    {
+        bail!("Unknown manifest section \"{}\"", key);
+    }
+
+    let defaults = match manifest.get("defaults") {
+        Some(Value::Table(defaults)) => defaults.clone(),
+        Some(_) => bail!("[defaults] must be a table"),
+        None => Table::new(),
+    };
+    let jobs = match manifest.get("job") {
+        Some(Value::Array(jobs)) if !jobs.is_empty() => jobs,
+        _ => bail!("Manifest has no [[job]] tables"),
+    };
+
+    jobs.iter()
+        .enumerate()
+        .map(|(idx, job)| {
+            let job = job.as_table().context("Jobs must be tables")?;
+            job_settings(&defaults, job, dir).with_context(|| format!("In job {}", idx + 1))
+        })
+        .collect()
+}
+
+/// Settings of a job, laid over the defaults
+fn job_settings(defaults: &Table, job: &Table, dir: &Path) -> Result<Job> {
+    let mut table = defaults.clone();
+    table.remove("uniforms");
+    let mut uniforms = uniform_table(defaults)?;
+    for (key, value) in job {
+        if key == "uniforms" {
+            uniforms.extend(uniform_table(job)?);
+        } else {
+            table.insert(key.clone(), value.clone());
+        }
+    }
+
+    let shader = match table.remove("shader") {
+        Some(Value::String(shader)) => dir.join(shader),
+        Some(_) => bail!("shader must be a path"),
+        None => bail!("Job has no shader"),
+    };
+    let mut args = vec!["bosrender".into(), shader.to_string_lossy().into_owned()];
+    for (key, value) in &table {
+        ensure!(key != "coordinator", "Batch jobs can't be distributed");
+        ensure!(
+            key != "progress",
+            "Progress is shown for the whole batch, set it on the command line"
+        );
+        let option = format!("--{}", key.replace('_', "-"));
+        match value {
+            Value::Boolean(true) => args.push(option),
+            Value::Boolean(false) => (),
+            value => args.push(format!("{}={}", option, argument(key, value)?)),
+        }
+    }
+    for (name, value) in &uniforms {
+        args.push(format!("--uniform={}={}", name, argument(name, value)?));
+    }
+
+    let cfg = Settings::from_iter_safe(&args).map_err(|e| anyhow!(e.message))?;
+    let label = match table.get("name") {
+        Some(Value::String(name)) => name.clone(),
+        _ => shader
+            .file_stem()
+            .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned()),
+    };
+    Ok(Job { label, cfg })
+}
+
+/// The `uniforms` table of a job or the defaults
+fn uniform_table(table: &Table) -> Result<Table> {
+    match table.get("uniforms") {
+        Some(Value::Table(uniforms)) => Ok(uniforms.clone()),
+        Some(_) => bail!("uniforms must be a table"),
+        None => Ok(Table::new()),
+    }
+}
+
+/// A setting's value as a command line argument, with the items of arrays separated by commas
+fn argument(key: &str, value: &Value) -> Result<String> {
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Array(items) => items
+            .iter()
+            .map(|item| match item {
+                Value::Array(_) | Value::Table(_) => bail!("{} can't have nested arrays", key),
+                item => argument(key, item),
+            })
+            .collect::<Result<Vec<_>>>()?
+            .join(","),
+        _ => bail!("Unsupported value for {}", key),
+    })
+}
+
+/// A table of each job's outcome and timing, followed by a total
+pub fn summary(outcomes: &[Outcome]) -> String {
+    let width = outcomes
+        .iter()
+        .map(|outcome| outcome.label.chars().count())
+        .max()
+        .unwrap_or(0)
+        .max("Job".len());
+
+    let mut table = format!(
+        "{:<width$}  {:<8}  {:>6}  {:>8}\n",
+        "Job",
+        "Result",
+        "Frames",
+        "Time",
+        width = width
+    );
+    for outcome in outcomes {
+        let (result, error) = match &outcome.status {
+            Status::Finished => ("ok", None),
+            Status::Stopped => ("stopped", None),
+            Status::Skipped => ("skipped", None),
+            Status::Failed(error) => ("failed", Some(error)),
+        };
+        table += &format!(
+            "{:<width$}  {:<8}  {:>6}  {:>7.1}s",
+            outcome.label,
+            result,
+            outcome.frames,
+            outcome.time.as_secs_f32(),
+            width = width
+        );
+        if let Some(error) = error {
+            table += "  ";
+            table += error;
+        }
+        table += "\n";
+    }
+
+    let finished = outcomes
+        .iter()
+        .filter(|outcome| outcome.status == Status::Finished)
+        .count();
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome.status, Status::Failed(_)))
+        .count();
+    let time: Duration = outcomes.iter().map(|outcome| outcome.time).sum();
+    table += &format!(
+        "{} jobs: {} finished, {} failed, in {:.1}s",
+        outcomes.len(),
+        finished,
+        failed,
+        time.as_secs_f32()
+    );
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = r#"
+            [defaults]
+            width = 640
+            output = "release"
+            tiff_deflate = true
+
+            [defaults.uniforms]
+            u_speed = 2.0
+
+            [[job]]
+            shader = "a_spot.frag"
+            height = 360
+            crop = [0, 0, 320, 180]
+
+            [[job]]
+            shader = "shaders/default.frag"
+            name = "hd"
+            width = 1920
+            tiff_deflate = false
+            pattern = "%n_%w.png"
+            uniforms = { u_color = [1, 0.5, 0] }
+        "#;
+        let jobs = parse(manifest, Path::new("dir")).unwrap();
+        assert_eq!(jobs.len(), 2);
+
+        let (a, hd) = (&jobs[0], &jobs[1]);
+        assert_eq!(a.label, "a_spot");
+        assert_eq!(a.cfg.shader, Path::new("dir/a_spot.frag"));
+        assert_eq!((a.cfg.width, a.cfg.height), (640, 360));
+        assert_eq!(a.cfg.output, Path::new("release"));
+        assert!(a.cfg.tiff_deflate);
+        assert_eq!(a.cfg.crop.map(|crop| crop.width), Some(320));
+        assert_eq!(a.cfg.uniforms.len(), 1);
+
+        assert_eq!(hd.label, "hd");
+        assert_eq!((hd.cfg.width, hd.cfg.height), (1920, 1080));
+        assert!(!hd.cfg.tiff_deflate);
+        assert_eq!(hd.cfg.pattern, "%n_%w.png");
+        assert_eq!(hd.cfg.uniforms.len(), 2);
+        assert_eq!(hd.cfg.uniforms[0].values, vec![1., 0.5, 0.]);
+
+        assert!(parse("[[job]]\nwidth = 5", Path::new("")).is_err());
+        assert!(parse("[[job]]\nshader = \"a\"\nno_such = 1", Path::new("")).is_err());
+        assert!(parse("[defaults]\nwidth = 5", Path::new("")).is_err());
+        assert!(parse("[jobs]", Path::new("")).is_err());
+    }
+
+    #[test]
+    fn test_summary() {
+        let outcome = |label: &str, status, frames, secs| Outcome {
+            label: label.into(),
+            status,
+            frames,
+            time: Duration::from_secs_f32(secs),
+        };
+        let outcomes = [
+            outcome("a_spot", Status::Finished, 60, 12.3),
+            outcome("hd", Status::Failed("Bad shader".into()), 0, 0.5),
+            outcome("x", Status::Skipped, 0, 0.),
+        ];
+        assert_eq!(
+            summary(&outcomes),
+            "Job     Result    Frames      Time\n\
+             a_spot  ok            60     12.3s\n\
+             hd      failed         0      0.5s  Bad shader\n\
+             x       skipped        0      0.0s\n\
+             3 jobs: 1 finished, 1 failed, in 12.8s"
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::{bail, Context, Result};
+use bosrender::distributed::{Reply, Worker};
+use bosrender::renderer::Renderer;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use structopt::StructOpt;
+
+/// Render frames for a bosrender coordinator
+#[derive(StructOpt)]
+struct Args {
+    /// Address the coordinator is serving on, e.g. "render-box:7878"
+    coordinator: String,
+
+    /// Vulkan devices to render on, as with bosrender's --devices. The coordinator's own choice
+    /// is for its machine, so it isn't used here
+    #[structopt(long, value_name = "indices", use_delimiter = true)]
+    devices: Vec<usize>,
+}
+
+/// Frames asked for ahead of the one being rendered, so the GPU isn't idle between frames
+const OUTSTANDING: usize = 2;
+
+fn main() -> Result<()> {
+    let args = Args::from_args();
+    let mut worker = Worker::connect(&args.coordinator)?;
+
+    let dir = std::env::temp_dir().join(format!("bosrender_worker_{}", std::process::id()));
+    let mut cfg = worker.setup().settings(&dir)?;
+    cfg.devices = args.devices;
+    println!(
+        "Connected to {}, rendering {}",
+        args.coordinator,
+        worker.setup().shader_name
+    );
+
+    // Frames given to us but not yet taken by the renderer
+    let queue = Arc::new(Mutex::new(VecDeque::new()));
+    let renderer_queue = queue.clone();
+    let work_order = Box::new(std::iter::from_fn(move || {
+        renderer_queue.lock().unwrap().pop_front().map(Ok)
+    }));
+    let renderer = Renderer::with_work_order(cfg, work_order);
+    std::fs::remove_dir_all(&dir).ok();
+    let mut renderer = match renderer {
+        Ok(renderer) => renderer,
+        Err(e) => {
+            worker.fail(&format!("{:#}", e))?;
+            return Err(e);
+        }
+    };
+
+    let mut outstanding = 0;
+    let mut done = false;
+    loop {
+        while !done && outstanding < OUTSTANDING {
+            match worker.request(outstanding == 0)? {
+                Reply::Job(frame_idx) => {
+                    queue.lock().unwrap().push_back(frame_idx);
+                    outstanding += 1;
+                }
+                Reply::NoWork => break,
+                Reply::Done => done = true,
+            }
+        }
+        if outstanding == 0 {
+            break;
+        }
+
+        match renderer.next_frame() {
+            Ok(Some(frame)) => {
+                worker.send_frame(&frame).context("Sending frame")?;
+                outstanding -= 1;
+                println!("Rendered frame {}", frame.index);
+            }
+            Ok(None) => bail!("Renderer finished with frames outstanding"),
+            Err(e) => {
+                worker.fail(&format!("{:#}", e))?;
+                return Err(e);
+            }
+        }
+    }
+
+    println!("Finished!");
+    Ok(())
+}
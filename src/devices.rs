@@ -0,0 +1,88 @@
+use anyhow::{bail, Context, Result};
+use erupt::{cstr, vk, DeviceLoader, EntryLoader, InstanceLoader};
+use gpu_alloc::{Config, GpuAllocator};
+use std::ffi::{CStr, CString};
+use std::sync::{Arc, Mutex};
+use watertender::Core;
+
+/// Build a core on the `index`th physical device, in the order the Vulkan loader lists them.
+/// This is `headless_backend::build_core`, which picks a device itself, with the choice made by
+/// the caller instead
+pub fn build_core(validation: bool, index: usize) -> Result<Core> {
+    let entry = EntryLoader::new()?;
+
+    let application_name = CString::new("bosrender")?;
+    let app_info = vk::ApplicationInfoBuilder::new()
+        .application_name(&application_name)
+        .api_version(vk::make_version(1, 1, 0));
+    let mut layers = vec![];
+    if validation {
+        layers.push(cstr!("VK_LAYER_KHRONOS_validation"));
+    }
+    let create_info = vk::InstanceCreateInfoBuilder::new()
+        .application_info(&app_info)
+        .enabled_layer_names(&layers);
+    let instance = InstanceLoader::new(&entry, &create_info, None)?;
+
+    let physical_devices = unsafe { instance.enumerate_physical_devices(None) }.result()?;
+    let physical_device = match physical_devices.get(index) {
+        Some(&physical_device) => physical_device,
+        None => {
+            let names: Vec<String> = physical_devices
+                .iter()
+                .enumerate()
+                .map(|(index, &device)| format!("{}: {}", index, device_name(&instance, device)))
+                .collect();
+            bail!(
+                "There is no Vulkan device {}, the devices are {}",
+                index,
+                names.join(", ")
+            );
+        }
+    };
+
+    let queue_family =
+        unsafe { instance.get_physical_device_queue_family_properties(physical_device, None) }
+            .iter()
+            .position(|family| family.queue_flags.contains(vk::QueueFlags::GRAPHICS))
+            .with_context(|| {
+                format!(
+                    "Vulkan device {} ({}) has no graphics queue",
+                    index,
+                    device_name(&instance, physical_device)
+                )
+            })? as u32;
+
+    let priorities = [1.0];
+    let queue_create_infos = [vk::DeviceQueueCreateInfoBuilder::new()
+        .queue_family_index(queue_family)
+        .queue_priorities(&priorities)];
+    let features = vk::PhysicalDeviceFeaturesBuilder::new();
+    let create_info = vk::DeviceCreateInfoBuilder::new()
+        .queue_create_infos(&queue_create_infos)
+        .enabled_features(&features)
+        .enabled_layer_names(&layers);
+    let device = DeviceLoader::new(&instance, physical_device, &create_info, None)?;
+    let queue = unsafe { device.get_device_queue(queue_family, 0, None) };
+
+    let properties = unsafe { gpu_alloc_erupt::device_properties(&instance, physical_device)? };
+    let allocator = GpuAllocator::new(Config::i_am_prototyping(), properties);
+
+    Ok(Core {
+        queue,
+        queue_family,
+        device: Arc::new(device),
+        physical_device,
+        instance: Arc::new(instance),
+        allocator: Mutex::new(allocator),
+        entry: Arc::new(entry),
+    })
+}
+
+/// Name the driver reports for a physical device
+fn device_name(instance: &InstanceLoader, device: vk::PhysicalDevice) -> String {
+    let properties = unsafe { instance.get_physical_device_properties(device) };
+    unsafe { CStr::from_ptr(properties.device_name.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,977 @@
+use crate::frame::Frame;
+use crate::output::tile_bytes_per_pixel;
+use crate::resume::WorkOrder;
+use crate::settings::Settings;
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+
+/// Workers must be the same build as the coordinator, since shaders are compiled with its header
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Workers a frame may fail on before the whole render is given up
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Frames handed out past the oldest unfinished one. Finished frames wait for it so that they are
+/// written in order, so this bounds how many are held in memory
+const MAX_AHEAD: usize = 16;
+
+/// Time the coordinator waits to hear from a worker before handing its frames to others
+#[cfg(not(test))]
+const READ_TIMEOUT: Duration = Duration::from_secs(60);
+#[cfg(test)]
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// How often workers show they're still there, well within `READ_TIMEOUT`
+const KEEPALIVE_INTERVAL: Duration = Duration::from_millis(READ_TIMEOUT.as_millis() as u64 / 6);
+
+/// Largest control message accepted, to fail fast on a stream that isn't ours
+const MAX_CONTROL_BYTES: u64 = 64 << 10;
+
+/// Largest setup accepted, which carries the shader source
+const MAX_SETUP_BYTES: u64 = 16 << 20;
+
+/// Bytes of a frame message besides its pixels: kind, index, samples, pixel length and checksum
+const FRAME_HEADER_BYTES: u64 = 1 + 4 * 8;
+
+/// FNV-1a hash of a sequence of byte strings, each prefixed with its length so that moving bytes
+/// between them changes the hash
+pub fn checksum(parts: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+    for part in parts {
+        for &byte in (part.len() as u64).to_le_bytes().iter().chain(part.iter()) {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+/// Everything a worker needs to render exactly as the coordinator would: its command line and
+/// the shader source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setup {
+    /// Coordinator arguments, without the program name or --coordinator
+    pub args: Vec<String>,
+    pub shader_name: String,
+    pub shader: Vec<u8>,
+}
+
+impl Setup {
+    /// Setup for the coordinator's settings, parsed from `args` which start with the program name
+    pub fn new(cfg: &Settings, args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut args = args.into_iter().skip(1);
+        let mut kept = vec![];
+        while let Some(arg) = args.next() {
+            if arg == "--coordinator" {
+                args.next();
+            } else if !arg.starts_with("--coordinator=") {
+                kept.push(arg);
+            }
+        }
+
+        let shader = std::fs::read(&cfg.shader)
+            .with_context(|| format!("Failed to read shader {}", cfg.shader.display()))?;
+        ensure!(
+            (shader.len() as u64) < MAX_SETUP_BYTES - MAX_CONTROL_BYTES,
+            "Shader {} is too large to send to workers",
+            cfg.shader.display()
+        );
+        let shader_name = cfg
+            .shader
+            .file_name()
+            .context("Shader path has no file name")?
+            .to_string_lossy()
+            .into_owned();
+
+        Ok(Self {
+            args: kept,
+            shader_name,
+            shader,
+        })
+    }
+
+    /// Checksum of the build and everything that affects rendering
+    pub fn checksum(&self) -> u64 {
+        let mut parts = vec![
+            VERSION.as_bytes(),
+            self.shader_name.as_bytes(),
+            &self.shader,
+        ];
+        parts.extend(self.args.iter().map(|arg| arg.as_bytes()));
+        checksum(&parts)
+    }
+
+    /// Write the shader into `dir` and parse the settings, pointed at it. The shader's name
+    /// comes from the coordinator, so anything but a plain file name is refused
+    pub fn settings(&self, dir: &Path) -> Result<Settings> {
+        ensure!(
+            Path::new(&self.shader_name).file_name() == Some(self.shader_name.as_ref()),
+            "Invalid shader name {:?}",
+            self.shader_name
+        );
+        let args = std::iter::once("bosrender").chain(self.args.iter().map(String::as_str));
+        let mut cfg = Settings::from_iter_safe(args)?;
+        cfg.shader = dir.join(&self.shader_name);
+        cfg.coordinator = None;
+        std::fs::create_dir_all(dir)?;
+        std::fs::write(&cfg.shader, &self.shader)
+            .with_context(|| format!("Failed to write shader {}", cfg.shader.display()))?;
+        Ok(cfg)
+    }
+}
+
+/// Messages between the coordinator and its workers
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Message {
+    /// Worker introduces itself
+    Hello {
+        version: String,
+    },
+    /// Coordinator turns a worker away
+    Reject {
+        reason: String,
+    },
+    /// What to render, and its checksum
+    Setup {
+        setup: Setup,
+        checksum: u64,
+    },
+    /// Worker's checksum of the setup it received
+    Ready {
+        checksum: u64,
+    },
+    /// Worker asks for a frame, waiting for one if `wait` rather than being told there's none
+    Request {
+        wait: bool,
+    },
+    Job {
+        frame_idx: usize,
+    },
+    NoWork,
+    /// Every frame is finished, or the render was stopped
+    Done,
+    /// A rendered frame at the tile depth, and the checksum of its pixels
+    Frame {
+        frame_idx: usize,
+        samples: u32,
+        pixels: Vec<u8>,
+        checksum: u64,
+    },
+    /// Worker can't go on
+    Failed {
+        reason: String,
+    },
+    /// Worker is still there, though it may be a while rendering
+    Keepalive,
+}
+
+/// Send a message as its length followed by its encoding
+fn send(stream: &mut impl Write, message: &Message) -> Result<()> {
+    let mut body = vec![];
+    let put_u64 = |body: &mut Vec<u8>, v: u64| body.extend_from_slice(&v.to_le_bytes());
+    let put_bytes = |body: &mut Vec<u8>, bytes: &[u8]| {
+        put_u64(body, bytes.len() as u64);
+        body.extend_from_slice(bytes);
+    };
+
+    match message {
+        Message::Hello { version } => {
+            body.push(0);
+            put_bytes(&mut body, version.as_bytes());
+        }
+        Message::Reject { reason } => {
+            body.push(1);
+            put_bytes(&mut body, reason.as_bytes());
+        }
+        Message::Setup { setup, checksum } => {
+            body.push(2);
+            put_u64(&mut body, setup.args.len() as u64);
+            for arg in &setup.args {
+                put_bytes(&mut body, arg.as_bytes());
+            }
+            put_bytes(&mut body, setup.shader_name.as_bytes());
+            put_bytes(&mut body, &setup.shader);
+            put_u64(&mut body, *checksum);
+        }
+        Message::Ready { checksum } => {
+            body.push(3);
+            put_u64(&mut body, *checksum);
+        }
+        Message::Request { wait } => body.extend_from_slice(&[4, *wait as u8]),
+        Message::Job { frame_idx } => {
+            body.push(5);
+            put_u64(&mut body, *frame_idx as u64);
+        }
+        Message::NoWork => body.push(6),
+        Message::Done => body.push(7),
+        Message::Frame {
+            frame_idx,
+            samples,
+            pixels,
+            checksum,
+        } => {
+            body.push(8);
+            put_u64(&mut body, *frame_idx as u64);
+            put_u64(&mut body, *samples as u64);
+            put_bytes(&mut body, pixels);
+            put_u64(&mut body, *checksum);
+        }
+        Message::Failed { reason } => {
+            body.push(9);
+            put_bytes(&mut body, reason.as_bytes());
+        }
+        Message::Keepalive => body.push(10),
+    }
+
+    stream.write_all(&(body.len() as u64).to_le_bytes())?;
+    stream.write_all(&body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Receive a message written by `send`, accepting frames of up to `frame_bytes` of pixels. The
+/// length is checked against the limit for the message's kind before anything is allocated
+fn receive(stream: &mut impl Read, frame_bytes: usize) -> Result<Message> {
+    let (mut len, mut kind) = ([0; 8], [0]);
+    stream.read_exact(&mut len)?;
+    stream.read_exact(&mut kind)?;
+    let (len, kind) = (u64::from_le_bytes(len), kind[0]);
+    let max_len = match kind {
+        2 => MAX_SETUP_BYTES,
+        8 => FRAME_HEADER_BYTES + frame_bytes as u64,
+        _ => MAX_CONTROL_BYTES,
+    };
+    ensure!(
+        len > 0 && len <= max_len,
+        "Bad length {} for message kind {}",
+        len,
+        kind
+    );
+    let mut body = vec![0; len as usize - 1];
+    stream.read_exact(&mut body)?;
+
+    let mut fields = Fields(&body);
+    let message = match kind {
+        0 => Message::Hello {
+            version: fields.string()?,
+        },
+        1 => Message::Reject {
+            reason: fields.string()?,
+        },
+        2 => {
+            let args = (0..fields.u64()?)
+                .map(|_| fields.string())
+                .collect::<Result<_>>()?;
+            Message::Setup {
+                setup: Setup {
+                    args,
+                    shader_name: fields.string()?,
+                    shader: fields.bytes()?,
+                },
+                checksum: fields.u64()?,
+            }
+        }
+        3 => Message::Ready {
+            checksum: fields.u64()?,
+        },
+        4 => Message::Request {
+            wait: fields.take(1)?[0] != 0,
+        },
+        5 => Message::Job {
+            frame_idx: fields.u64()? as usize,
+        },
+        6 => Message::NoWork,
+        7 => Message::Done,
+        8 => Message::Frame {
+            frame_idx: fields.u64()? as usize,
+            samples: fields.u64()? as u32,
+            pixels: fields.bytes()?,
+            checksum: fields.u64()?,
+        },
+        9 => Message::Failed {
+            reason: fields.string()?,
+        },
+        10 => Message::Keepalive,
+        kind => bail!("Unknown message kind {}", kind),
+    };
+    ensure!(fields.0.is_empty(), "Message has trailing bytes");
+    Ok(message)
+}
+
+/// The rest of a message body, read a field at a time
+struct Fields<'a>(&'a [u8]);
+
+impl<'a> Fields<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        ensure!(self.0.len() >= n, "Message is truncated");
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u64()?;
+        ensure!(len <= self.0.len() as u64, "Message is truncated");
+        Ok(self.take(len as usize)?.to_vec())
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8(self.bytes()?)?)
+    }
+}
+
+/// What the coordinator has for a worker asking for a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reply {
+    Job(usize),
+    /// Nothing right now, but frames may still fail elsewhere and need rendering again
+    NoWork,
+    Done,
+}
+
+/// A change in the distributed render
+#[derive(Debug)]
+pub enum Poll {
+    /// The next frame in order
+    Frame(Frame),
+    /// Nothing yet
+    Pending,
+    /// Every frame has been returned
+    Finished,
+}
+
+/// How the distributed render is going
+#[derive(Debug, Clone, Copy)]
+pub struct Status {
+    pub frames_done: usize,
+    /// Number of frames to render, unless endless
+    pub frames: Option<usize>,
+    pub workers: usize,
+}
+
+/// Hands frames out to workers connecting over TCP, and collects the rendered frames back in
+/// order. Frames held by a worker that fails or goes away are handed to another
+pub struct Coordinator {
+    shared: Arc<Shared>,
+    frames: Option<usize>,
+}
+
+/// Coordinator state shared with its connections
+struct Shared {
+    cfg: Settings,
+    setup: Setup,
+    checksum: u64,
+    frame_bytes: usize,
+    state: Mutex<State>,
+    /// Notified whenever frames are handed out, returned or requeued
+    changed: Condvar,
+}
+
+struct State {
+    work_order: WorkOrder,
+    /// Whether the work order has run out
+    exhausted: bool,
+    /// Position in the output order of the next frame taken from the work order
+    next_seq: usize,
+    /// Frames to hand out again, by position
+    retry: BTreeMap<usize, usize>,
+    /// Frames being rendered, by position, with the connection rendering them
+    assigned: HashMap<usize, (usize, usize)>,
+    /// Number of times each frame has failed
+    attempts: HashMap<usize, u32>,
+    /// Frames waiting for earlier ones before they can be returned, by position
+    finished: BTreeMap<usize, Frame>,
+    /// Position of the next frame to return
+    next_out: usize,
+    frames_done: usize,
+    workers: usize,
+    error: Option<Error>,
+}
+
+impl Coordinator {
+    /// Serve the frames of `work_order` to workers connecting on `listener`
+    pub fn start(
+        cfg: &Settings,
+        setup: Setup,
+        listener: TcpListener,
+        work_order: WorkOrder,
+    ) -> Result<Self> {
+        let region = cfg.region();
+        let frame_bytes =
+            region.width as usize * region.height as usize * tile_bytes_per_pixel(cfg);
+        let frames = work_order.size_hint().1;
+        let shared = Arc::new(Shared {
+            cfg: cfg.clone(),
+            checksum: setup.checksum(),
+            setup,
+            frame_bytes,
+            state: Mutex::new(State {
+                work_order,
+                exhausted: false,
+                next_seq: 0,
+                retry: BTreeMap::new(),
+                assigned: HashMap::new(),
+                attempts: HashMap::new(),
+                finished: BTreeMap::new(),
+                next_out: 0,
+                frames_done: 0,
+                workers: 0,
+                error: None,
+            }),
+            changed: Condvar::new(),
+        });
+
+        let accepting = shared.clone();
+        thread::spawn(move || {
+            for (conn, stream) in listener.incoming().enumerate() {
+                if let Ok(stream) = stream {
+                    let shared = accepting.clone();
+                    thread::spawn(move || shared.serve(conn, stream));
+                }
+            }
+        });
+
+        Ok(Self { shared, frames })
+    }
+
+    /// Wait up to `timeout` for the next frame in order
+    pub fn poll(&self, timeout: Duration) -> Result<Poll> {
+        let mut state = self.shared.lock();
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(error) = state.error.take() {
+                return Err(error);
+            }
+            let next_out = state.next_out;
+            if let Some(frame) = state.finished.remove(&next_out) {
+                state.next_out += 1;
+                state.frames_done += 1;
+                // Returning a frame lets workers get further ahead
+                self.shared.changed.notify_all();
+                return Ok(Poll::Frame(frame));
+            }
+            if state.exhausted && state.retry.is_empty() && state.assigned.is_empty() {
+                return Ok(Poll::Finished);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(Poll::Pending);
+            }
+            state = self
+                .shared
+                .changed
+                .wait_timeout(state, deadline - now)
+                .unwrap()
+                .0;
+        }
+    }
+
+    /// Hand out no more frames, but collect those already handed out
+    pub fn stop(&self) {
+        let mut state = self.shared.lock();
+        state.work_order = Box::new(std::iter::empty());
+        state.exhausted = true;
+        self.shared.changed.notify_all();
+    }
+
+    pub fn status(&self) -> Status {
+        let state = self.shared.lock();
+        Status {
+            frames_done: state.frames_done,
+            frames: self.frames,
+            workers: state.workers,
+        }
+    }
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Talk to one worker until it leaves or goes quiet, then hand out again whatever it was
+    /// rendering
+    fn serve(&self, conn: usize, mut stream: TcpStream) {
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_read_timeout(Some(READ_TIMEOUT));
+        let reason = match self.handshake(&mut stream) {
+            Ok(true) => {
+                self.lock().workers += 1;
+                let reason = match self.converse(conn, &mut stream) {
+                    Ok(()) => "the worker left".into(),
+                    Err(e) => format!("{:#}", e),
+                };
+                self.lock().workers -= 1;
+                reason
+            }
+            Ok(false) | Err(_) => return,
+        };
+
+        let mut state = self.lock();
+        let held: Vec<usize> = state
+            .assigned
+            .iter()
+            .filter(|(_, &(_, holder))| holder == conn)
+            .map(|(&seq, _)| seq)
+            .collect();
+        for seq in held {
+            self.fail(&mut state, seq, &reason);
+        }
+    }
+
+    /// Send the setup and check the worker got it intact. False if the worker was turned away
+    fn handshake(&self, stream: &mut TcpStream) -> Result<bool> {
+        match receive(stream, self.frame_bytes)? {
+            Message::Hello { version } if version == VERSION => (),
+            Message::Hello { version } => {
+                let reason = format!("Worker is version {}, but need {}", version, VERSION);
+                send(stream, &Message::Reject { reason })?;
+                return Ok(false);
+            }
+            _ => bail!("Expected a hello"),
+        }
+
+        send(
+            stream,
+            &Message::Setup {
+                setup: self.setup.clone(),
+                checksum: self.checksum,
+            },
+        )?;
+
+        match receive(stream, self.frame_bytes)? {
+            Message::Ready { checksum } if checksum == self.checksum => Ok(true),
+            Message::Ready { .. } => {
+                let reason = "Worker's setup doesn't match the coordinator's".into();
+                send(stream, &Message::Reject { reason })?;
+                Ok(false)
+            }
+            _ => bail!("Expected the worker to be ready"),
+        }
+    }
+
+    /// Answer requests and take frames from a worker, until it says it failed or is done
+    fn converse(&self, conn: usize, stream: &mut TcpStream) -> Result<()> {
+        loop {
+            let message = match receive(stream, self.frame_bytes) {
+                Err(e) if is_timeout(&e) => bail!(
+                    "the worker sent nothing for {} seconds",
+                    READ_TIMEOUT.as_secs_f32()
+                ),
+                message => message?,
+            };
+            match message {
+                Message::Request { wait } => {
+                    let reply = self.next_job(conn, wait);
+                    let message = match reply {
+                        Reply::Job(frame_idx) => Message::Job { frame_idx },
+                        Reply::NoWork => Message::NoWork,
+                        Reply::Done => Message::Done,
+                    };
+                    send(stream, &message)?;
+                    if reply == Reply::Done {
+                        return Ok(());
+                    }
+                }
+                Message::Frame {
+                    frame_idx,
+                    samples,
+                    pixels,
+                    checksum: sum,
+                } => self.finish(conn, frame_idx, samples, pixels, sum)?,
+                Message::Failed { reason } => bail!("Worker failed: {}", reason),
+                Message::Keepalive => (),
+                _ => bail!("Unexpected message from worker"),
+            }
+        }
+    }
+
+    /// A frame for the worker, waiting for one if `wait`
+    fn next_job(&self, conn: usize, wait: bool) -> Reply {
+        let mut state = self.lock();
+        loop {
+            if state.error.is_some() {
+                return Reply::Done;
+            }
+
+            let job = if let Some((&seq, &frame_idx)) = state.retry.iter().next() {
+                state.retry.remove(&seq);
+                Some((seq, frame_idx))
+            } else if !state.exhausted && state.next_seq < state.next_out + MAX_AHEAD {
+                match state.work_order.next() {
+                    Some(Ok(frame_idx)) => {
+                        let seq = state.next_seq;
+                        state.next_seq += 1;
+                        Some((seq, frame_idx))
+                    }
+                    Some(Err(e)) => {
+                        state.error = Some(e);
+                        self.changed.notify_all();
+                        return Reply::Done;
+                    }
+                    None => {
+                        state.exhausted = true;
+                        self.changed.notify_all();
+                        continue;
+                    }
+                }
+            } else {
+                None
+            };
+
+            if let Some((seq, frame_idx)) = job {
+                state.assigned.insert(seq, (frame_idx, conn));
+                return Reply::Job(frame_idx);
+            }
+            if state.exhausted && state.assigned.is_empty() {
+                return Reply::Done;
+            }
+            if !wait {
+                return Reply::NoWork;
+            }
+            state = self.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Take a rendered frame from a worker, or hand it out again if it was damaged on the way
+    fn finish(
+        &self,
+        conn: usize,
+        frame_idx: usize,
+        samples: u32,
+        pixels: Vec<u8>,
+        sum: u64,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        let seq = state
+            .assigned
+            .iter()
+            .find(|(_, &assignment)| assignment == (frame_idx, conn))
+            .map(|(&seq, _)| seq)
+            .ok_or_else(|| anyhow!("Worker returned frame {}, which it wasn't given", frame_idx))?;
+
+        if pixels.len() != self.frame_bytes || checksum(&[&pixels]) != sum {
+            self.fail(&mut state, seq, "the frame was damaged on the way");
+            return Ok(());
+        }
+
+        state.assigned.remove(&seq);
+        let frame = Frame {
+            index: frame_idx,
+            time: self.cfg.frame_time(frame_idx),
+            samples,
+            pixels,
+        };
+        state.finished.insert(seq, frame);
+        self.changed.notify_all();
+        Ok(())
+    }
+
+    /// Hand a frame out again, or give up on the render if it has failed too often
+    fn fail(&self, state: &mut State, seq: usize, reason: &str) {
+        if let Some((frame_idx, _)) = state.assigned.remove(&seq) {
+            let attempts = state.attempts.entry(seq).or_insert(0);
+            *attempts += 1;
+            if *attempts >= MAX_ATTEMPTS {
+                state.error = Some(anyhow!(
+                    "Frame {} failed on {} workers, last because {}",
+                    frame_idx,
+                    attempts,
+                    reason
+                ));
+            } else {
+                state.retry.insert(seq, frame_idx);
+            }
+            self.changed.notify_all();
+        }
+    }
+}
+
+/// Whether reading failed because nothing arrived in time
+fn is_timeout(error: &Error) -> bool {
+    let kind = error
+        .downcast_ref::<std::io::Error>()
+        .map(std::io::Error::kind);
+    matches!(kind, Some(ErrorKind::WouldBlock | ErrorKind::TimedOut))
+}
+
+/// A connection to a coordinator, taking frames to render and returning them. A keepalive is
+/// sent every so often for as long as the connection is open, so the coordinator knows the
+/// worker is still rendering
+pub struct Worker {
+    stream: TcpStream,
+    /// Writing half, shared with the thread sending keepalives
+    writer: Arc<Mutex<TcpStream>>,
+    setup: Setup,
+}
+
+impl Worker {
+    /// Connect and receive the setup, checking it arrived intact
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<Self> {
+        let mut stream = TcpStream::connect(addr).context("Connecting to the coordinator")?;
+        stream.set_nodelay(true)?;
+        send(
+            &mut stream,
+            &Message::Hello {
+                version: VERSION.into(),
+            },
+        )?;
+
+        let setup = match receive(&mut stream, 0)? {
+            Message::Setup { setup, checksum } => {
+                ensure!(setup.checksum() == checksum, "Setup was damaged on the way");
+                setup
+            }
+            Message::Reject { reason } => bail!("Coordinator turned us away: {}", reason),
+            _ => bail!("Unexpected message from coordinator"),
+        };
+        send(
+            &mut stream,
+            &Message::Ready {
+                checksum: setup.checksum(),
+            },
+        )?;
+
+        let writer = Arc::new(Mutex::new(stream.try_clone()?));
+        let keepalive = Arc::downgrade(&writer);
+        thread::spawn(move || loop {
+            thread::sleep(KEEPALIVE_INTERVAL);
+            let sent = keepalive
+                .upgrade()
+                .map(|writer| send(&mut *writer.lock().unwrap(), &Message::Keepalive));
+            if !matches!(sent, Some(Ok(()))) {
+                break;
+            }
+        });
+
+        Ok(Self {
+            stream,
+            writer,
+            setup,
+        })
+    }
+
+    fn send(&self, message: &Message) -> Result<()> {
+        send(&mut *self.writer.lock().unwrap(), message)
+    }
+
+    /// What to render
+    pub fn setup(&self) -> &Setup {
+        &self.setup
+    }
+
+    /// Ask for a frame to render, waiting for one if `wait`
+    pub fn request(&mut self, wait: bool) -> Result<Reply> {
+        self.send(&Message::Request { wait })?;
+        match receive(&mut self.stream, 0)? {
+            Message::Job { frame_idx } => Ok(Reply::Job(frame_idx)),
+            Message::NoWork => Ok(Reply::NoWork),
+            Message::Done => Ok(Reply::Done),
+            Message::Reject { reason } => bail!("Coordinator turned us away: {}", reason),
+            _ => bail!("Unexpected message from coordinator"),
+        }
+    }
+
+    /// Return a rendered frame
+    pub fn send_frame(&mut self, frame: &Frame) -> Result<()> {
+        let message = Message::Frame {
+            frame_idx: frame.index,
+            samples: frame.samples,
+            checksum: checksum(&[&frame.pixels]),
+            pixels: frame.pixels.clone(),
+        };
+        self.send(&message)
+    }
+
+    /// Tell the coordinator this worker can't go on, so its frames go to others
+    pub fn fail(self, reason: &str) -> Result<()> {
+        let reason = reason.into();
+        self.send(&Message::Failed { reason })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> Settings {
+        Settings::from_iter(&["bosrender", "a_spot.frag", "-w", "4", "-h", "2"])
+    }
+
+    #[test]
+    fn test_messages() {
+        let setup = Setup::new(
+            &settings(),
+            [
+                "bosrender",
+                "x.frag",
+                "--coordinator",
+                "a:1",
+                "-f",
+                "3",
+                "--coordinator=b:2",
+            ]
+            .iter()
+            .map(|arg| arg.to_string()),
+        )
+        .unwrap();
+        assert_eq!(setup.args, vec!["x.frag", "-f", "3"]);
+        assert_eq!(setup.shader_name, "a_spot.frag");
+
+        // Shader names can't point outside the directory they're written to
+        let dir = std::env::temp_dir().join(format!("bosrender_setup_{}", std::process::id()));
+        for name in ["../escaped.frag", "/tmp/escaped.frag", "a/b.frag", "..", ""] {
+            let setup = Setup {
+                shader_name: name.into(),
+                ..setup.clone()
+            };
+            assert!(setup.settings(&dir).is_err(), "{:?}", name);
+        }
+        assert!(!dir.exists());
+
+        let messages = vec![
+            Message::Setup {
+                checksum: setup.checksum(),
+                setup: setup.clone(),
+            },
+            Message::Request { wait: true },
+            Message::Frame {
+                frame_idx: 7,
+                samples: 4,
+                pixels: vec![1, 2, 3],
+                checksum: 9,
+            },
+            Message::Done,
+        ];
+        let mut stream = vec![];
+        for message in &messages {
+            send(&mut stream, message).unwrap();
+        }
+        let mut reader = &stream[..];
+        for message in &messages {
+            assert_eq!(&receive(&mut reader, 3).unwrap(), message);
+        }
+        let mut truncated = vec![];
+        send(&mut truncated, &messages[0]).unwrap();
+        truncated.pop();
+        assert!(receive(&mut &truncated[..], 3).is_err());
+
+        // Frames larger than expected and control messages claiming to be huge are refused
+        let mut frame = vec![];
+        send(&mut frame, &messages[2]).unwrap();
+        assert!(receive(&mut &frame[..], 2).is_err());
+        let mut huge = (1u64 << 40).to_le_bytes().to_vec();
+        huge.push(4);
+        assert!(receive(&mut &huge[..], 3).is_err());
+
+        let mut changed = setup.clone();
+        changed.shader.push(b' ');
+        assert_ne!(changed.checksum(), setup.checksum());
+        assert_ne!(checksum(&[b"ab", b"c"]), checksum(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn test_workers() {
+        let cfg = settings();
+        let setup = Setup::new(&cfg, vec!["bosrender".into()]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let frames = Box::new((0..6).map(Ok));
+        let coordinator = Coordinator::start(&cfg, setup, listener, frames).unwrap();
+
+        // A worker that goes away with a frame, which must be handed to another
+        let mut flaky = Worker::connect(addr).unwrap();
+        assert_eq!(flaky.request(true).unwrap(), Reply::Job(0));
+        drop(flaky);
+
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                thread::spawn(move || {
+                    let mut worker = Worker::connect(addr).unwrap();
+                    while let Reply::Job(frame_idx) = worker.request(true).unwrap() {
+                        let frame = Frame {
+                            index: frame_idx,
+                            time: 0.,
+                            samples: 1,
+                            pixels: vec![frame_idx as u8; 4 * 2 * 3],
+                        };
+                        worker.send_frame(&frame).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let mut indices = vec![];
+        loop {
+            match coordinator.poll(Duration::from_secs(5)).unwrap() {
+                Poll::Frame(frame) => {
+                    assert_eq!(frame.pixels, vec![frame.index as u8; 4 * 2 * 3]);
+                    indices.push(frame.index);
+                }
+                Poll::Pending => panic!("Timed out"),
+                Poll::Finished => break,
+            }
+        }
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 5]);
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(coordinator.status().frames_done, 6);
+    }
+
+    #[test]
+    fn test_silent_worker() {
+        let cfg = settings();
+        let setup = Setup::new(&cfg, vec!["bosrender".into()]).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let frames = Box::new((0..1).map(Ok));
+        let coordinator = Coordinator::start(&cfg, setup, listener, frames).unwrap();
+
+        // A worker that takes a frame and goes quiet without leaving, so the frame is handed on
+        let mut silent = TcpStream::connect(addr).unwrap();
+        let hello = Message::Hello {
+            version: VERSION.into(),
+        };
+        send(&mut silent, &hello).unwrap();
+        let checksum = match receive(&mut silent, 0).unwrap() {
+            Message::Setup { checksum, .. } => checksum,
+            message => panic!("Expected the setup, got {:?}", message),
+        };
+        send(&mut silent, &Message::Ready { checksum }).unwrap();
+        send(&mut silent, &Message::Request { wait: true }).unwrap();
+        assert_eq!(
+            receive(&mut silent, 0).unwrap(),
+            Message::Job { frame_idx: 0 }
+        );
+
+        // A worker that takes longer than the timeout over it, but keeps in touch meanwhile
+        let mut worker = Worker::connect(addr).unwrap();
+        assert_eq!(worker.request(true).unwrap(), Reply::Job(0));
+        thread::sleep(READ_TIMEOUT * 3);
+        let frame = Frame {
+            index: 0,
+            time: 0.,
+            samples: 1,
+            pixels: vec![0; 4 * 2 * 3],
+        };
+        worker.send_frame(&frame).unwrap();
+        match coordinator.poll(Duration::from_secs(5)).unwrap() {
+            Poll::Frame(frame) => assert_eq!(frame.index, 0),
+            poll => panic!("Expected the frame, got {:?}", poll),
+        }
+    }
+}
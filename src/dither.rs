@@ -0,0 +1,325 @@
+use anyhow::{bail, Error};
+use std::str::FromStr;
+
+/// Side of the tiled blue noise threshold map
+const BLUE_NOISE_SIZE: usize = 64;
+
+/// Side of the Bayer threshold matrix
+const BAYER_SIZE: usize = 8;
+
+/// Dithering applied when reducing 16-bit samples to 8-bit output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    /// Ordered dithering with an 8x8 Bayer matrix
+    Bayer,
+    /// Ordered dithering with a tiled 64x64 blue noise map
+    BlueNoise,
+    /// Floyd-Steinberg error diffusion along whole image rows
+    ErrorDiffusion,
+}
+
+impl FromStr for Dither {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bayer" => Ok(Dither::Bayer),
+            "blue-noise" => Ok(Dither::BlueNoise),
+            "error-diffusion" => Ok(Dither::ErrorDiffusion),
+            _ => bail!(
+                "Unknown dither \"{}\", expected bayer, blue-noise or error-diffusion",
+                s
+            ),
+        }
+    }
+}
+
+/// Reduces rows of 16-bit samples to 8 bits. Ordered thresholds depend only on the pixel's
+/// position in the full image, so tiles, frames and cropped regions line up. Error diffusion
+/// carries its error from row to row, so rows must arrive in order, starting from the top of each
+/// frame
+pub struct Ditherer {
+    method: Dither,
+    width: usize,
+    channels: usize,
+    /// Side and row-major contents of the threshold map, in [0, 1)
+    thresholds: (usize, Vec<f32>),
+    /// Error diffused into the next row
+    errors: Vec<f32>,
+    /// Image row expected next, unless at the start of a frame
+    next_row: Option<usize>,
+}
+
+impl Ditherer {
+    pub fn new(method: Dither, width: usize, channels: usize) -> Self {
+        let (size, ranks) = match method {
+            Dither::Bayer => (BAYER_SIZE, bayer(BAYER_SIZE)),
+            Dither::BlueNoise => (BLUE_NOISE_SIZE, blue_noise(BLUE_NOISE_SIZE)),
+            Dither::ErrorDiffusion => (1, vec![0]),
+        };
+        let count = ranks.len() as f32;
+        let thresholds = ranks.iter().map(|&r| (r as f32 + 0.5) / count).collect();
+
+        Self {
+            method,
+            width,
+            channels,
+            thresholds: (size, thresholds),
+            errors: vec![0.; width * channels],
+            next_row: None,
+        }
+    }
+
+    /// Forget the error carried from the previous frame
+    pub fn start_frame(&mut self) {
+        self.errors.iter_mut().for_each(|e| *e = 0.);
+        self.next_row = None;
+    }
+
+    /// Reduce whole rows of native-endian 16-bit samples. `(x, y)` is where the first sample lies
+    /// in the full image, which differs from the top left when cropping
+    pub fn rows(&mut self, data: &[u8], (x, y): (usize, usize)) -> Vec<u8> {
+        let x0 = x;
+        let row_len = self.width * self.channels;
+        let mut out = Vec::with_capacity(data.len() / 2);
+        let samples: Vec<f32> = data
+            .chunks_exact(2)
+            .map(|s| u16::from_ne_bytes([s[0], s[1]]) as f32 * 255. / 65535.)
+            .collect();
+
+        for (row_idx, row) in samples.chunks_exact(row_len).enumerate() {
+            let y = y + row_idx;
+            match self.method {
+                Dither::ErrorDiffusion => {
+                    debug_assert_eq!(self.next_row.unwrap_or(y), y, "Rows must arrive in order");
+                    self.diffuse_row(row, &mut out);
+                }
+                _ => {
+                    let (size, thresholds) = &self.thresholds;
+                    for (x, pixel) in row.chunks_exact(self.channels).enumerate() {
+                        let x = x0 + x;
+                        let threshold = thresholds[(y % size) * size + x % size];
+                        out.extend(
+                            pixel
+                                .iter()
+                                .map(|v| (v + threshold).floor().min(255.) as u8),
+                        );
+                    }
+                }
+            }
+            self.next_row = Some(y + 1);
+        }
+
+        out
+    }
+
+    /// Floyd-Steinberg one row, taking the error diffused from the row above
+    fn diffuse_row(&mut self, row: &[f32], out: &mut Vec<u8>) {
+        let channels = self.channels;
+        let mut current = std::mem::replace(&mut self.errors, vec![0.; row.len()]);
+        let next = &mut self.errors;
+
+        for x in 0..self.width {
+            for c in 0..channels {
+                let i = x * channels + c;
+                let value = row[i] + current[i];
+                let quantized = value.round().clamp(0., 255.);
+                out.push(quantized as u8);
+
+                let error = value - quantized;
+                if x + 1 < self.width {
+                    current[i + channels] += error * 7. / 16.;
+                    next[i + channels] += error / 16.;
+                }
+                if x > 0 {
+                    next[i - channels] += error * 3. / 16.;
+                }
+                next[i] += error * 5. / 16.;
+            }
+        }
+    }
+}
+
+/// Ranks of a `size` x `size` Bayer matrix, `size` being a power of two
+fn bayer(size: usize) -> Vec<u32> {
+    let mut ranks = vec![0];
+    let mut side = 1;
+    while side < size {
+        let mut next = vec![0; side * side * 4];
+        for y in 0..side {
+            for x in 0..side {
+                let r = ranks[y * side + x] * 4;
+                let at = |dx, dy| (y + dy * side) * side * 2 + x + dx * side;
+                next[at(0, 0)] = r;
+                next[at(1, 1)] = r + 1;
+                next[at(1, 0)] = r + 2;
+                next[at(0, 1)] = r + 3;
+            }
+        }
+        ranks = next;
+        side *= 2;
+    }
+    ranks
+}
+
+/// Ranks of a tileable `size` x `size` blue noise map, built by void-and-cluster
+fn blue_noise(size: usize) -> Vec<u32> {
+    let n = size * size;
+
+    // Gaussian energy contributed by a set pixel at each toroidal offset
+    let sigma = 1.5f32;
+    let kernel: Vec<f32> = (0..n)
+        .map(|i| {
+            let wrap = |d: usize| d.min(size - d) as f32;
+            let (dx, dy) = (wrap(i % size), wrap(i / size));
+            (-(dx * dx + dy * dy) / (2. * sigma * sigma)).exp()
+        })
+        .collect();
+
+    let mut set = vec![false; n];
+    let mut energy = vec![0f32; n];
+    let toggle = |set: &mut [bool], energy: &mut [f32], p: usize| {
+        set[p] = !set[p];
+        let sign = if set[p] { 1. } else { -1. };
+        let (px, py) = (p % size, p / size);
+        for (q, e) in energy.iter_mut().enumerate() {
+            let dx = (q % size + size - px) % size;
+            let dy = (q / size + size - py) % size;
+            *e += sign * kernel[dy * size + dx];
+        }
+    };
+
+    // Tightest cluster among set pixels, and largest void among unset ones
+    let extreme = |set: &[bool], energy: &[f32], want: bool| -> usize {
+        let candidates = (0..n).filter(|&p| set[p] == want);
+        let key = |&p: &usize| energy[p];
+        if want {
+            candidates.max_by(|a, b| key(a).total_cmp(&key(b))).unwrap()
+        } else {
+            candidates.min_by(|a, b| key(a).total_cmp(&key(b))).unwrap()
+        }
+    };
+
+    // Deterministic initial pattern of roughly a tenth of the pixels
+    let mut state = 0x2545_F491u32;
+    let initial = n / 10;
+    let mut placed = 0;
+    while placed < initial {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        let p = state as usize % n;
+        if !set[p] {
+            toggle(&mut set, &mut energy, p);
+            placed += 1;
+        }
+    }
+
+    // Even it out by moving clusters into voids until that stops changing anything
+    loop {
+        let cluster = extreme(&set, &energy, true);
+        toggle(&mut set, &mut energy, cluster);
+        let void = extreme(&set, &energy, false);
+        if void == cluster {
+            toggle(&mut set, &mut energy, cluster);
+            break;
+        }
+        toggle(&mut set, &mut energy, void);
+    }
+    let prototype = (set.clone(), energy.clone());
+
+    // Rank the initial pixels by removing clusters, then the rest by filling voids
+    let mut ranks = vec![0; n];
+    for rank in (0..initial).rev() {
+        let cluster = extreme(&set, &energy, true);
+        toggle(&mut set, &mut energy, cluster);
+        ranks[cluster] = rank as u32;
+    }
+    let (mut set, mut energy) = prototype;
+    for rank in initial..n {
+        let void = extreme(&set, &energy, false);
+        toggle(&mut set, &mut energy, void);
+        ranks[void] = rank as u32;
+    }
+
+    ranks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_permutation(ranks: &[u32]) -> bool {
+        let mut sorted = ranks.to_vec();
+        sorted.sort_unstable();
+        sorted.iter().enumerate().all(|(i, &r)| r == i as u32)
+    }
+
+    fn sixteen_bit(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_ne_bytes()).collect()
+    }
+
+    #[test]
+    fn test_threshold_maps() {
+        assert_eq!(bayer(2), vec![0, 2, 3, 1]);
+        assert!(is_permutation(&bayer(BAYER_SIZE)));
+        assert!(is_permutation(&blue_noise(16)));
+    }
+
+    #[test]
+    fn test_mean_preserved() {
+        // A level between two 8-bit steps comes out as a mix of both, in proportion
+        let (width, height) = (64, 64);
+        let level = 100.25f32 * 65535. / 255.;
+        let data = sixteen_bit(&vec![level.round() as u16; width * height]);
+
+        for &method in &[Dither::Bayer, Dither::BlueNoise, Dither::ErrorDiffusion] {
+            let out = Ditherer::new(method, width, 1).rows(&data, (0, 0));
+            assert!(out.iter().all(|&v| v == 100 || v == 101), "{:?}", method);
+            let mean = out.iter().map(|&v| v as f32).sum::<f32>() / out.len() as f32;
+            assert!((mean - 100.25).abs() < 0.01, "{:?}: {}", method, mean);
+        }
+    }
+
+    #[test]
+    fn test_bands_are_seamless() {
+        // Dithering a frame band by band matches dithering it whole
+        let (width, height, channels) = (20, 12, 3);
+        let values: Vec<u16> = (0..width * height * channels)
+            .map(|i| (i * 2711 % 65536) as u16)
+            .collect();
+        let data = sixteen_bit(&values);
+        let row_bytes = width * channels * 2;
+
+        for &method in &[Dither::Bayer, Dither::BlueNoise, Dither::ErrorDiffusion] {
+            let mut ditherer = Ditherer::new(method, width, channels);
+            let whole = ditherer.rows(&data, (0, 0));
+
+            ditherer.start_frame();
+            let mut banded = ditherer.rows(&data[..5 * row_bytes], (0, 0));
+            banded.extend(ditherer.rows(&data[5 * row_bytes..], (0, 5)));
+            assert_eq!(whole, banded, "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn test_crops_are_seamless() {
+        // An ordered dither of a cropped region matches the same region of the whole image
+        let (width, height) = (20, 12);
+        let values: Vec<u16> = (0..width * height)
+            .map(|i| (i * 2711 % 65536) as u16)
+            .collect();
+        let (x, y, crop_width, crop_height) = (3, 5, 9, 4);
+        let crop: Vec<u16> = (y..y + crop_height)
+            .flat_map(|row| values[row * width + x..][..crop_width].to_vec())
+            .collect();
+
+        for &method in &[Dither::Bayer, Dither::BlueNoise] {
+            let whole = Ditherer::new(method, width, 1).rows(&sixteen_bit(&values), (0, 0));
+            let cropped = Ditherer::new(method, crop_width, 1).rows(&sixteen_bit(&crop), (x, y));
+            let expected: Vec<u8> = (y..y + crop_height)
+                .flat_map(|row| whole[row * width + x..][..crop_width].to_vec())
+                .collect();
+            assert_eq!(cropped, expected, "{:?}", method);
+        }
+    }
+}
@@ -0,0 +1,420 @@
+use crate::output::{commit_partial, create_parent_dir, partial_path, png_samples};
+use anyhow::{bail, ensure, Context, Error, Result};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Image format of the tiles in a pyramid
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TileFormat {
+    Jpeg,
+    Png,
+}
+
+impl TileFormat {
+    fn extension(self) -> &'static str {
+        match self {
+            TileFormat::Jpeg => "jpg",
+            TileFormat::Png => "png",
+        }
+    }
+}
+
+impl FromStr for TileFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "jpg" | "jpeg" => Ok(TileFormat::Jpeg),
+            "png" => Ok(TileFormat::Png),
+            _ => bail!("Unknown DZI tile format \"{}\", expected jpg or png", s),
+        }
+    }
+}
+
+/// Layout and encoding of the tiles of a pyramid
+#[derive(Debug, Clone, Copy)]
+pub struct DziOptions {
+    pub tile_size: usize,
+    pub overlap: usize,
+    pub format: TileFormat,
+    pub jpeg_quality: u8,
+}
+
+/// Builds a Deep Zoom Image pyramid from rows of the full resolution image, which must arrive in
+/// order. Each level only buffers the rows of its current tile row, so memory use is bounded by
+/// the image width rather than its area
+pub struct DziWriter {
+    path: PathBuf,
+    width: usize,
+    height: usize,
+    channels: usize,
+    bytes_per_channel: usize,
+    options: DziOptions,
+    /// Level 0 is a single pixel, the last level is full resolution
+    levels: Vec<Level>,
+}
+
+/// Rows received so far at one level of the pyramid
+struct Level {
+    dir: PathBuf,
+    width: usize,
+    height: usize,
+    /// Buffered rows, the first of which is image row `rows_start`
+    rows: Vec<u8>,
+    rows_start: usize,
+    rows_received: usize,
+    /// First row of a pair waiting to be downsampled into the next level down
+    pending: Option<Vec<u8>>,
+    next_tile_row: usize,
+}
+
+impl DziWriter {
+    /// Prepare to write the pyramid for a `width` x `height` image described by `path`. Tiles go
+    /// in a directory alongside it named after its stem with a "_files" suffix
+    pub fn new(
+        path: &Path,
+        (width, height): (u32, u32),
+        channels: usize,
+        bytes_per_channel: usize,
+        options: DziOptions,
+    ) -> Result<Self> {
+        ensure!(options.tile_size > 0, "DZI tile size must be positive");
+        ensure!(width > 0 && height > 0, "DZI images must not be empty");
+
+        let stem = path
+            .file_stem()
+            .context("DZI path has no file name")?
+            .to_string_lossy();
+        let files_dir = path.with_file_name(format!("{}_files", stem));
+
+        let (width, height) = (width as usize, height as usize);
+        let max_level = max_level(width, height);
+        let mut levels = vec![];
+        let (mut level_width, mut level_height) = (width, height);
+        for level in (0..=max_level).rev() {
+            let dir = files_dir.join(level.to_string());
+            std::fs::create_dir_all(&dir)
+                .with_context(|| format!("Failed to create directory {}", dir.display()))?;
+            levels.push(Level {
+                dir,
+                width: level_width,
+                height: level_height,
+                rows: vec![],
+                rows_start: 0,
+                rows_received: 0,
+                pending: None,
+                next_tile_row: 0,
+            });
+            level_width = level_width.div_ceil(2);
+            level_height = level_height.div_ceil(2);
+        }
+        levels.reverse();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            width,
+            height,
+            channels,
+            bytes_per_channel,
+            options,
+            levels,
+        })
+    }
+
+    fn pixel_size(&self) -> usize {
+        self.channels * self.bytes_per_channel
+    }
+
+    /// Append whole rows of the full resolution image. 16-bit samples are in native byte order
+    pub fn write_rows(&mut self, data: &[u8]) -> Result<()> {
+        let row_bytes = self.width * self.pixel_size();
+        let rows = data.chunks_exact(row_bytes);
+        ensure!(
+            rows.remainder().is_empty(),
+            "Partial row passed to DZI writer"
+        );
+
+        let top = self.levels.len() - 1;
+        for row in rows {
+            self.push_row(top, row.to_vec())?;
+        }
+
+        Ok(())
+    }
+
+    /// Add a row to `level`, writing any tiles it completes and passing it down the pyramid
+    fn push_row(&mut self, level_idx: usize, row: Vec<u8>) -> Result<()> {
+        let (channels, bytes_per_channel) = (self.channels, self.bytes_per_channel);
+        let level = &mut self.levels[level_idx];
+        ensure!(
+            level.rows_received < level.height,
+            "Too many rows passed to DZI writer"
+        );
+
+        level.rows.extend_from_slice(&row);
+        level.rows_received += 1;
+
+        // Rows pair up to form the next level down, with a lone last row at odd heights
+        let last = level.rows_received == level.height;
+        let width = level.width;
+        let down = match level.pending.take() {
+            Some(first) => Some(downsample(
+                &first,
+                Some(&row),
+                width,
+                channels,
+                bytes_per_channel,
+            )),
+            None if last => Some(downsample(&row, None, width, channels, bytes_per_channel)),
+            None => {
+                level.pending = Some(row);
+                None
+            }
+        };
+
+        self.write_tiles(level_idx)?;
+
+        match down {
+            Some(down) if level_idx > 0 => self.push_row(level_idx - 1, down),
+            _ => Ok(()),
+        }
+    }
+
+    /// Write every tile row of `level` whose rows have all arrived, then drop rows no longer
+    /// needed
+    fn write_tiles(&mut self, level_idx: usize) -> Result<()> {
+        let pixel_size = self.pixel_size();
+        let DziOptions {
+            tile_size, overlap, ..
+        } = self.options;
+
+        loop {
+            let level = &self.levels[level_idx];
+            let tile_rows = level.height.div_ceil(tile_size);
+            let tile_row = level.next_tile_row;
+            if tile_row == tile_rows {
+                return Ok(());
+            }
+
+            let (top, bottom) = tile_span(tile_row, tile_size, overlap, level.height);
+            if level.rows_received < bottom {
+                return Ok(());
+            }
+
+            let row_bytes = level.width * pixel_size;
+            for col in 0..level.width.div_ceil(tile_size) {
+                let (left, right) = tile_span(col, tile_size, overlap, level.width);
+                let mut tile = Vec::with_capacity((right - left) * (bottom - top) * pixel_size);
+                for y in top..bottom {
+                    let row = &level.rows[(y - level.rows_start) * row_bytes..][..row_bytes];
+                    tile.extend_from_slice(&row[left * pixel_size..right * pixel_size]);
+                }
+
+                let path = level.dir.join(format!(
+                    "{}_{}.{}",
+                    col,
+                    tile_row,
+                    self.options.format.extension()
+                ));
+                self.write_tile(&tile, (right - left, bottom - top), &path)
+                    .with_context(|| format!("Failed to write DZI tile {}", path.display()))?;
+            }
+
+            // Keep only the rows the next tile row overlaps
+            let level = &mut self.levels[level_idx];
+            level.next_tile_row += 1;
+            let keep_from = ((tile_row + 1) * tile_size).saturating_sub(overlap);
+            let keep_from = keep_from.min(level.rows_received);
+            level
+                .rows
+                .drain(..(keep_from - level.rows_start) * row_bytes);
+            level.rows_start = keep_from;
+        }
+    }
+
+    fn write_tile(&self, data: &[u8], (width, height): (usize, usize), path: &Path) -> Result<()> {
+        match self.options.format {
+            TileFormat::Jpeg => {
+                // JPEG is 8-bit only, so keep the most significant byte of wider samples
+                let data: Vec<u8> = match self.bytes_per_channel {
+                    1 => data.to_vec(),
+                    _ => data
+                        .chunks_exact(2)
+                        .map(|s| (u16::from_ne_bytes([s[0], s[1]]) >> 8) as u8)
+                        .collect(),
+                };
+                let color = match self.channels {
+                    4 => jpeg_encoder::ColorType::Rgba,
+                    _ => jpeg_encoder::ColorType::Rgb,
+                };
+                let encoder = jpeg_encoder::Encoder::new(
+                    BufWriter::new(File::create(path)?),
+                    self.options.jpeg_quality,
+                );
+                encoder.encode(&data, width as u16, height as u16, color)?;
+            }
+            TileFormat::Png => {
+                let mut encoder =
+                    png::Encoder::new(BufWriter::new(File::create(path)?), width as _, height as _);
+                encoder.set_color(match self.channels {
+                    4 => png::ColorType::Rgba,
+                    _ => png::ColorType::Rgb,
+                });
+                encoder.set_depth(match self.bytes_per_channel {
+                    1 => png::BitDepth::Eight,
+                    _ => png::BitDepth::Sixteen,
+                });
+                let mut writer = encoder.write_header()?;
+                writer.write_image_data(&png_samples(data, self.bytes_per_channel == 2))?;
+                writer.finish()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the XML descriptor once every row has arrived
+    pub fn finish(self) -> Result<()> {
+        let top = &self.levels[self.levels.len() - 1];
+        ensure!(
+            top.rows_received == self.height,
+            "DZI writer expected {} rows, got {}",
+            self.height,
+            top.rows_received
+        );
+
+        let xml = format!(
+            concat!(
+                "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n",
+                "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" ",
+                "Format=\"{}\" Overlap=\"{}\" TileSize=\"{}\">\n",
+                "  <Size Width=\"{}\" Height=\"{}\"/>\n",
+                "</Image>\n"
+            ),
+            self.options.format.extension(),
+            self.options.overlap,
+            self.options.tile_size,
+            self.width,
+            self.height,
+        );
+
+        create_parent_dir(&self.path)?;
+        std::fs::write(partial_path(&self.path), xml)
+            .with_context(|| format!("Failed to write DZI descriptor {}", self.path.display()))?;
+        commit_partial(&self.path)
+    }
+}
+
+/// Index of the full resolution level; the level at which the image is halved down to 1x1
+pub fn max_level(width: usize, height: usize) -> usize {
+    let size = width.max(height).max(1);
+    (usize::BITS - (size - 1).leading_zeros()) as usize
+}
+
+/// Start and end along one axis of tile `idx`, including overlap with its neighbours
+fn tile_span(idx: usize, tile_size: usize, overlap: usize, len: usize) -> (usize, usize) {
+    let start = (idx * tile_size).saturating_sub(overlap);
+    let end = ((idx + 1) * tile_size + overlap).min(len);
+    (start, end)
+}
+
+/// Halve a pair of rows (or a lone last row) in both directions by averaging
+fn downsample(
+    first: &[u8],
+    second: Option<&[u8]>,
+    width: usize,
+    channels: usize,
+    bytes_per_channel: usize,
+) -> Vec<u8> {
+    let pixel_size = channels * bytes_per_channel;
+    let read = |row: &[u8], x: usize, c: usize| -> u32 {
+        let idx = x * pixel_size + c * bytes_per_channel;
+        match bytes_per_channel {
+            1 => row[idx] as u32,
+            _ => u16::from_ne_bytes([row[idx], row[idx + 1]]) as u32,
+        }
+    };
+
+    let rows: Vec<&[u8]> = std::iter::once(first).chain(second).collect();
+    let mut out = Vec::with_capacity(width.div_ceil(2) * pixel_size);
+    for x in (0..width).step_by(2) {
+        let cols = x..(x + 2).min(width);
+        let count = (rows.len() * cols.len()) as u32;
+        for c in 0..channels {
+            let sum: u32 = rows
+                .iter()
+                .flat_map(|row| cols.clone().map(move |x| read(row, x, c)))
+                .sum();
+            let value = (sum + count / 2) / count;
+            match bytes_per_channel {
+                1 => out.push(value as u8),
+                _ => out.extend_from_slice(&(value as u16).to_ne_bytes()),
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_levels() {
+        assert_eq!(max_level(1, 1), 0);
+        assert_eq!(max_level(2, 1), 1);
+        assert_eq!(max_level(3, 2), 2);
+        assert_eq!(max_level(1024, 768), 10);
+        assert_eq!(max_level(1025, 768), 11);
+    }
+
+    #[test]
+    fn test_tile_span() {
+        assert_eq!(tile_span(0, 254, 1, 1000), (0, 255));
+        assert_eq!(tile_span(1, 254, 1, 1000), (253, 509));
+        assert_eq!(tile_span(3, 254, 1, 1000), (761, 1000));
+    }
+
+    #[test]
+    fn test_pyramid() {
+        let dir = std::env::temp_dir().join(format!("bosrender_dzi_{}", std::process::id()));
+        let path = dir.join("image.dzi");
+        let options = DziOptions {
+            tile_size: 4,
+            overlap: 1,
+            format: TileFormat::Png,
+            jpeg_quality: 90,
+        };
+
+        let (width, height) = (10, 7);
+        let mut dzi = DziWriter::new(&path, (width, height), 3, 1, options).unwrap();
+        let data: Vec<u8> = (0..width * height * 3).map(|i| i as u8).collect();
+        // Rows arrive in uneven bands
+        dzi.write_rows(&data[..3 * 30]).unwrap();
+        dzi.write_rows(&data[3 * 30..]).unwrap();
+        dzi.finish().unwrap();
+
+        let files = dir.join("image_files");
+        // 10x7 at level 4, then 5x4, 3x2, 2x1 and 1x1
+        for (level, tiles) in [(4, 3 * 2), (3, 2), (2, 1), (1, 1), (0, 1)] {
+            let count = std::fs::read_dir(files.join(level.to_string()))
+                .unwrap()
+                .count();
+            assert_eq!(count, tiles, "level {}", level);
+        }
+        assert!(files.join("4").join("2_1.png").exists());
+
+        let xml = std::fs::read_to_string(&path).unwrap();
+        assert!(xml.contains("TileSize=\"4\""));
+        assert!(xml.contains("<Size Width=\"10\" Height=\"7\"/>"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_downsample() {
+        let out = downsample(&[0, 10, 20], Some(&[40, 50, 60]), 3, 1, 1);
+        assert_eq!(out, vec![25, 40]);
+    }
+}
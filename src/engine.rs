@@ -1,11 +1,17 @@
+use crate::remap::Remap;
+use crate::uniforms::{self, Uniform};
+use crate::view::Affine;
 use anyhow::{Context, Result};
+use std::collections::HashMap;
 use std::ffi::CString;
 use std::path::Path;
+use std::sync::Mutex;
 use watertender::prelude::*;
 
 static VERTEX_SHADER_SPV: &[u8] = include_bytes!("shaders/builtin.vert.spv");
 
 pub struct Engine {
+    blend: Blend,
     pipeline: vk::Pipeline,
     pipeline_layout: vk::PipelineLayout,
     scene_ubo: FrameDataUbo<SceneData>,
@@ -26,15 +32,105 @@ pub struct SceneData {
     // TODO: Add mouse in interactive mode!
 }
 
+/// How the shader's output combines with the render target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Blend {
+    /// Store the shader's output as-is
+    Replace,
+    /// Composite over the clear color
+    Background,
+    /// Add the output, scaled by each subframe's weight, to what is already there
+    Accumulate,
+}
+
+/// One of the renders summed into a frame
+#[derive(Debug, Clone, Copy)]
+pub struct Subframe {
+    /// Added to the scene time
+    pub time_offset: f32,
+    pub weight: f32,
+    /// Progressive sample index, passed to the shader as `u_sample`
+    pub sample: u32,
+    /// Passed to the shader as `u_seed`
+    pub seed: u32,
+}
+
+/// Coordinate handling injected ahead of the user's shader
+#[derive(Debug, Clone)]
+pub struct Prelude {
+    /// Mapping from output pixels to shader coordinates
+    pub remap: Option<Remap>,
+    /// Clear pixels outside the remap's domain instead of shading them
+    pub mask: bool,
+    /// Transform applied to coordinates after any remap
+    pub view: Option<Affine>,
+    /// Supersampling factor; the framebuffer has this many pixels per output pixel on each axis
+    pub ssaa: u32,
+    /// Uniforms of the user's shader fixed to constants
+    pub uniforms: Vec<Uniform>,
+}
+
+/// Compiled fragment shaders and a Vulkan pipeline cache, kept for the engines built on one core
+/// so that a shader used again isn't compiled again
+pub struct ShaderCache {
+    pipeline_cache: vk::PipelineCache,
+    /// SPIR-V by shader source and prelude
+    fragment_shaders: Mutex<HashMap<(Vec<u8>, String), Vec<u8>>>,
+    core: SharedCore,
+}
+
+impl ShaderCache {
+    pub fn new(core: SharedCore) -> Result<Self> {
+        let create_info = vk::PipelineCacheCreateInfoBuilder::new();
+        let pipeline_cache =
+            unsafe { core.device.create_pipeline_cache(&create_info, None, None) }.result()?;
+        Ok(Self {
+            pipeline_cache,
+            fragment_shaders: Mutex::new(HashMap::new()),
+            core,
+        })
+    }
+
+    /// The shader at `path` with the prelude, compiled unless it has been already
+    fn fragment_shader(&self, path: &Path, prelude: &Prelude) -> Result<Vec<u8>> {
+        let source = std::fs::read(path)
+            .with_context(|| format!("Failed to find shader source at \"{}\"", path.display()))?;
+        let key = (source, format!("{:?}", prelude));
+        if let Some(spv) = self.fragment_shaders.lock().unwrap().get(&key) {
+            return Ok(spv.clone());
+        }
+
+        let spv = load_fragment_shader(path, prelude)?;
+        self.fragment_shaders
+            .lock()
+            .unwrap()
+            .insert(key, spv.clone());
+        Ok(spv)
+    }
+}
+
+impl Drop for ShaderCache {
+    fn drop(&mut self) {
+        unsafe {
+            self.core
+                .device
+                .destroy_pipeline_cache(Some(self.pipeline_cache), None);
+        }
+    }
+}
+
 impl Engine {
     pub fn new(
         core: SharedCore,
         frames_in_flight: usize,
         shader_path: &Path,
         render_pass: vk::RenderPass,
+        blend: Blend,
+        prelude: &Prelude,
+        cache: &ShaderCache,
     ) -> Result<Self> {
         // Load fragment shader
-        let fragment_spv = load_fragment_shader(shader_path)?;
+        let fragment_spv = cache.fragment_shader(shader_path, prelude)?;
 
         // Scene data
         let scene_ubo = FrameDataUbo::new(core.clone(), frames_in_flight)?;
@@ -107,9 +203,10 @@ impl Engine {
 
         let descriptor_set_layouts = [descriptor_set_layout];
 
-        // Pipeline layout
+        // Pipeline layout. The fragment shader receives the subframe's time offset, sample index
+        // and seed as push constants
         let push_constant_ranges = [vk::PushConstantRangeBuilder::new()
-            .stage_flags(vk::ShaderStageFlags::VERTEX)
+            .stage_flags(vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT)
             .offset(0)
             .size(std::mem::size_of::<[f32; 4 * 4]>() as u32)];
 
@@ -128,9 +225,12 @@ impl Engine {
             vk::PrimitiveTopology::TRIANGLE_LIST,
             render_pass,
             pipeline_layout,
+            blend,
+            cache.pipeline_cache,
         )?;
 
         Ok(Self {
+            blend,
             descriptor_set_layout,
             descriptor_sets,
             descriptor_pool,
@@ -141,11 +241,13 @@ impl Engine {
         })
     }
 
+    /// Record drawing `scene` once per subframe into the bound render pass
     pub fn write_commands(
         &mut self,
         command_buffer: vk::CommandBuffer,
         frame: usize,
         scene: &SceneData,
+        subframes: &[Subframe],
     ) -> Result<()> {
         // TODO: Factor this out?
         self.scene_ubo.upload(frame, scene)?;
@@ -166,7 +268,30 @@ impl Engine {
                 self.pipeline,
             );
 
-            self.core.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            for subframe in subframes {
+                // Laid out as the BosRenderSubframe block
+                let constants = [
+                    subframe.time_offset.to_bits(),
+                    subframe.sample,
+                    subframe.seed,
+                ];
+                self.core.device.cmd_push_constants(
+                    command_buffer,
+                    self.pipeline_layout,
+                    vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                    0,
+                    std::mem::size_of_val(&constants) as u32,
+                    constants.as_ptr() as _,
+                );
+
+                if self.blend == Blend::Accumulate {
+                    self.core
+                        .device
+                        .cmd_set_blend_constants(command_buffer, [subframe.weight; 4]);
+                }
+
+                self.core.device.cmd_draw(command_buffer, 3, 1, 0, 0);
+            }
         }
 
         Ok(())
@@ -183,6 +308,8 @@ pub fn shader(
     primitive: vk::PrimitiveTopology,
     render_pass: vk::RenderPass,
     pipeline_layout: vk::PipelineLayout,
+    blend: Blend,
+    pipeline_cache: vk::PipelineCache,
 ) -> Result<vk::Pipeline> {
     // Create shader modules
     let vert_decoded = erupt::utils::decode_spv(vertex_src)?;
@@ -216,7 +343,10 @@ pub fn shader(
         .viewport_count(1)
         .scissor_count(1);
 
-    let dynamic_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    let mut dynamic_states = vec![vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
+    if blend == Blend::Accumulate {
+        dynamic_states.push(vk::DynamicState::BLEND_CONSTANTS);
+    }
     let dynamic_state =
         vk::PipelineDynamicStateCreateInfoBuilder::new().dynamic_states(&dynamic_states);
 
@@ -233,6 +363,19 @@ pub fn shader(
         .sample_shading_enable(false)
         .rasterization_samples(vk::SampleCountFlagBits::_1);
 
+    // Accumulation weights each subframe by the blend constants
+    let (src_color, dst_color, src_alpha) = match blend {
+        Blend::Accumulate => (
+            vk::BlendFactor::CONSTANT_COLOR,
+            vk::BlendFactor::ONE,
+            vk::BlendFactor::CONSTANT_ALPHA,
+        ),
+        _ => (
+            vk::BlendFactor::ONE,
+            vk::BlendFactor::SRC_ALPHA,
+            vk::BlendFactor::ONE,
+        ),
+    };
     let color_blend_attachments = [vk::PipelineColorBlendAttachmentStateBuilder::new()
         .color_write_mask(
             vk::ColorComponentFlags::R
@@ -240,12 +383,12 @@ pub fn shader(
                 | vk::ColorComponentFlags::B
                 | vk::ColorComponentFlags::A,
         )
-        .blend_enable(true)
+        .blend_enable(blend != Blend::Replace)
         .color_blend_op(vk::BlendOp::ADD)
-        .src_color_blend_factor(vk::BlendFactor::ONE)
-        .dst_color_blend_factor(vk::BlendFactor::SRC_ALPHA)
+        .src_color_blend_factor(src_color)
+        .dst_color_blend_factor(dst_color)
         .alpha_blend_op(vk::BlendOp::ADD)
-        .src_alpha_blend_factor(vk::BlendFactor::ONE)
+        .src_alpha_blend_factor(src_alpha)
         .dst_alpha_blend_factor(vk::BlendFactor::ONE)];
     let color_blending = vk::PipelineColorBlendStateCreateInfoBuilder::new()
         .logic_op_enable(false)
@@ -288,7 +431,7 @@ pub fn shader(
     let pipeline = unsafe {
         prelude
             .device
-            .create_graphics_pipelines(None, &[create_info], None)
+            .create_graphics_pipelines(Some(pipeline_cache), &[create_info], None)
     }
     .result()?[0];
 
@@ -303,7 +446,8 @@ pub fn shader(
 impl Drop for Engine {
     fn drop(&mut self) {
         unsafe {
-            self.core.device.device_wait_idle().unwrap();
+            // A lost device reports an error here, with nothing left to wait for
+            let _ = self.core.device.device_wait_idle();
             self.core
                 .device
                 .destroy_descriptor_pool(Some(self.descriptor_pool), None);
@@ -319,11 +463,12 @@ impl Drop for Engine {
 }
 
 #[cfg(feature = "shaderc")]
-fn load_fragment_shader(path: &Path) -> Result<Vec<u8>> {
+fn load_fragment_shader(path: &Path, prelude: &Prelude) -> Result<Vec<u8>> {
     let source = std::fs::read_to_string(path)
         .with_context(|| format!("Failed to find shader source at \"{}\"", path.display()))?;
 
-    let source = doctor_source(source);
+    let source = uniforms::bind(&source, &prelude.uniforms)?;
+    let source = doctor_source(source, prelude);
 
     let mut compiler = shaderc::Compiler::new().context("Could not find shaderc compiler")?;
 
@@ -353,28 +498,92 @@ fn load_fragment_shader(path: &Path) -> Result<Vec<u8>> {
 }
 
 #[cfg(not(feature = "shaderc"))]
-fn load_fragment_shader(path: &Path) -> Result<Vec<u8>> {
+fn load_fragment_shader(path: &Path, prelude: &Prelude) -> Result<Vec<u8>> {
+    anyhow::ensure!(
+        prelude.remap.is_none(),
+        "Coordinate remapping requires the shaderc feature"
+    );
+    anyhow::ensure!(
+        prelude.uniforms.is_empty(),
+        "Setting uniforms requires the shaderc feature"
+    );
     Ok(std::fs::read(path)?)
 }
 
-fn doctor_source(source: String) -> String {
-    "#version 450
+fn doctor_source(source: String, prelude: &Prelude) -> String {
+    let mut header = "#version 450
 layout(binding = 0) uniform BosRenderSceneData {
     int offset_x;
     int offset_y;
     float resolution_x;
     float resolution_y;
-    float u_time;
+    float bos_render_frame_time;
+};
+layout(push_constant) uniform BosRenderSubframe {
+    float bos_render_time_offset;
+    int u_sample;
+    uint u_seed;
 };
 layout(location = 0) out vec4 bos_render_output_color;
+float u_time = bos_render_frame_time + bos_render_time_offset;
 vec2 u_resolution = vec2(resolution_x, resolution_y);
-vec4 bos_render_input_coord = vec4(offset_x, offset_y, 0, 0) + vec4(gl_FragCoord.x, resolution_y - gl_FragCoord.y, gl_FragCoord.zw);
+vec4 bos_render_pixel_coord = vec4(offset_x + gl_FragCoord.x / SSAA, resolution_y - (offset_y + gl_FragCoord.y / SSAA), gl_FragCoord.zw);
 "
-    .to_string()
-        + &source
-            .replace("uniform vec2 u_resolution;", "")
-            .replace("uniform vec2 u_mouse;", "")
-            .replace("uniform float u_time;", "")
-            .replace("gl_FragCoord", "bos_render_input_coord")
-            .replace("gl_FragColor", "bos_render_output_color")
+    .replace("SSAA", &format!("{}.", prelude.ssaa.max(1)));
+
+    match &prelude.remap {
+        None => header += "vec2 bos_render_canvas_coord = bos_render_pixel_coord.xy;\n",
+        Some(remap) => {
+            header += &remap.glsl();
+            header +=
+                "vec2 bos_render_canvas_coord = bos_render_remap(bos_render_pixel_coord.xy);\n";
+            if remap.has_direction() {
+                header +=
+                    "vec3 bos_render_view_dir = bos_render_direction(bos_render_pixel_coord.xy);\n";
+            }
+        }
+    }
+
+    match &prelude.view {
+        None => {
+            header += "vec4 bos_render_input_coord = \
+                       vec4(bos_render_canvas_coord, bos_render_pixel_coord.zw);\n"
+        }
+        Some([a, b, c, d, e, f]) => {
+            // GLSL matrices are column-major
+            header += &format!(
+                "vec4 bos_render_input_coord = vec4(mat2({:?}, {:?}, {:?}, {:?}) * \
+                 bos_render_canvas_coord + vec2({:?}, {:?}), bos_render_pixel_coord.zw);\n",
+                a, d, b, e, c, f
+            );
+        }
+    }
+
+    let mut source = source
+        .replace("uniform vec2 u_resolution;", "")
+        .replace("uniform vec2 u_mouse;", "")
+        .replace("uniform float u_time;", "")
+        .replace("uniform int u_sample;", "")
+        .replace("uniform uint u_seed;", "")
+        .replace("gl_FragCoord", "bos_render_input_coord")
+        .replace("gl_FragColor", "bos_render_output_color");
+
+    // Rename the shader's entry point however it is spelled, and wrap it to skip pixels outside
+    // the mapping
+    if prelude.mask && prelude.remap.is_some() {
+        source = "#define main bos_render_user_main\n".to_string()
+            + &source
+            + "
+#undef main
+void main() {
+    if (!bos_render_in_domain(bos_render_pixel_coord.xy)) {
+        bos_render_output_color = vec4(0);
+        return;
+    }
+    bos_render_user_main();
+}
+";
+    }
+
+    header + &source
 }
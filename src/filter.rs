@@ -0,0 +1,268 @@
+use anyhow::{bail, Error};
+use std::f32::consts::PI;
+use std::str::FromStr;
+
+/// Reconstruction filter used to downsample supersampled tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Box,
+    Tent,
+    Mitchell,
+    Lanczos,
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "box" => Ok(Filter::Box),
+            "tent" => Ok(Filter::Tent),
+            "mitchell" => Ok(Filter::Mitchell),
+            "lanczos" => Ok(Filter::Lanczos),
+            _ => bail!(
+                "Unknown filter \"{}\", expected box, tent, mitchell or lanczos",
+                s
+            ),
+        }
+    }
+}
+
+impl Filter {
+    /// Extent of the filter either side of a pixel center, in output pixels
+    pub fn radius(self) -> f32 {
+        match self {
+            Filter::Box => 0.5,
+            Filter::Tent => 1.,
+            Filter::Mitchell => 2.,
+            Filter::Lanczos => 3.,
+        }
+    }
+
+    /// Whole output pixels of neighbouring image a tile needs around it to be filtered without
+    /// seams
+    pub fn margin(self) -> usize {
+        (self.radius() - 0.5).ceil() as usize
+    }
+
+    /// Weight of a sample `x` output pixels from the pixel center
+    fn weight(self, x: f32) -> f32 {
+        let x = x.abs();
+        match self {
+            Filter::Box => {
+                if x < 0.5 {
+                    1.
+                } else {
+                    0.
+                }
+            }
+            Filter::Tent => (1. - x).max(0.),
+            Filter::Mitchell => {
+                // B = C = 1/3
+                let (b, c) = (1. / 3., 1. / 3.);
+                let value = if x < 1. {
+                    (12. - 9. * b - 6. * c) * x.powi(3)
+                        + (-18. + 12. * b + 6. * c) * x.powi(2)
+                        + (6. - 2. * b)
+                } else if x < 2. {
+                    (-b - 6. * c) * x.powi(3)
+                        + (6. * b + 30. * c) * x.powi(2)
+                        + (-12. * b - 48. * c) * x
+                        + (8. * b + 24. * c)
+                } else {
+                    0.
+                };
+                value / 6.
+            }
+            Filter::Lanczos => {
+                let a = self.radius();
+                if x == 0. {
+                    1.
+                } else if x < a {
+                    let px = PI * x;
+                    a * px.sin() * (px / a).sin() / (px * px)
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+}
+
+/// Normalized weights of the samples contributing to each output pixel along one axis, as the
+/// first sample index and the weights from there
+fn axis_weights(
+    filter: Filter,
+    factor: usize,
+    margin: usize,
+    samples: usize,
+    outputs: usize,
+) -> Vec<(usize, Vec<f32>)> {
+    let factor_f = factor as f32;
+    let reach = (filter.radius() * factor_f).ceil() as isize;
+
+    (0..outputs)
+        .map(|out| {
+            // Output pixel center, in samples
+            let center = (margin + out) as f32 * factor_f + factor_f / 2.;
+            let first = (center.floor() as isize - reach).max(0) as usize;
+            let last = ((center.ceil() as isize + reach) as usize).min(samples);
+
+            let mut weights: Vec<f32> = (first..last)
+                .map(|s| filter.weight((s as f32 + 0.5 - center) / factor_f))
+                .collect();
+            let total: f32 = weights.iter().sum();
+            if total != 0. {
+                weights.iter_mut().for_each(|w| *w /= total);
+            }
+            (first, weights)
+        })
+        .collect()
+}
+
+/// Downsample an image rendered at `factor` times resolution, with `margin` output pixels of
+/// extra image on every side, to `(width, height)` pixels using `filter`
+pub fn downsample(
+    data: &[u8],
+    (width, height): (usize, usize),
+    factor: usize,
+    margin: usize,
+    channels: usize,
+    bytes_per_channel: usize,
+    filter: Filter,
+) -> Vec<u8> {
+    let src_width = (width + 2 * margin) * factor;
+    let src_height = (height + 2 * margin) * factor;
+    let pixel_size = channels * bytes_per_channel;
+    debug_assert_eq!(data.len(), src_width * src_height * pixel_size);
+
+    let read = |idx: usize| -> f32 {
+        match bytes_per_channel {
+            1 => data[idx] as f32,
+            _ => u16::from_ne_bytes([data[idx], data[idx + 1]]) as f32,
+        }
+    };
+
+    // Filter rows, then columns
+    let columns = axis_weights(filter, factor, margin, src_width, width);
+    let rows = axis_weights(filter, factor, margin, src_height, height);
+
+    let mut horizontal = vec![0f32; width * src_height * channels];
+    for y in 0..src_height {
+        for (x, (first, weights)) in columns.iter().enumerate() {
+            for c in 0..channels {
+                let mut sum = 0.;
+                for (i, w) in weights.iter().enumerate() {
+                    sum += w * read(
+                        ((y * src_width) + first + i) * pixel_size + c * bytes_per_channel,
+                    );
+                }
+                horizontal[(y * width + x) * channels + c] = sum;
+            }
+        }
+    }
+
+    let max = match bytes_per_channel {
+        1 => u8::MAX as f32,
+        _ => u16::MAX as f32,
+    };
+    let mut out = Vec::with_capacity(width * height * pixel_size);
+    for (first, weights) in &rows {
+        for x in 0..width {
+            for c in 0..channels {
+                let mut sum = 0.;
+                for (i, w) in weights.iter().enumerate() {
+                    sum += w * horizontal[((first + i) * width + x) * channels + c];
+                }
+                let value = sum.round().clamp(0., max);
+                match bytes_per_channel {
+                    1 => out.push(value as u8),
+                    _ => out.extend_from_slice(&(value as u16).to_ne_bytes()),
+                }
+            }
+        }
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTERS: [Filter; 4] = [Filter::Box, Filter::Tent, Filter::Mitchell, Filter::Lanczos];
+
+    #[test]
+    fn test_flat_image() {
+        for &filter in &FILTERS {
+            let margin = filter.margin();
+            let (width, height, factor) = (3, 2, 4);
+            let samples = (width + 2 * margin) * (height + 2 * margin) * factor * factor;
+            let data = vec![77u8; samples * 3];
+            let out = downsample(&data, (width, height), factor, margin, 3, 1, filter);
+            assert_eq!(out, vec![77; width * height * 3], "{:?}", filter);
+        }
+    }
+
+    #[test]
+    fn test_box_averages() {
+        // Each output pixel covers a 2x2 block
+        let data = [0u8, 100, 50, 50, 10, 30, 20, 40];
+        let out = downsample(&data, (2, 1), 2, 0, 1, 1, Filter::Box);
+        // Row-major 4x2 samples: [0 100 50 50] over [10 30 20 40]
+        assert_eq!(out, vec![35, 40]);
+    }
+
+    #[test]
+    fn test_tiles_are_seamless() {
+        // A tile rendered with its margin matches the same region of the whole image
+        let factor = 3;
+        let (width, height) = (12, 10);
+        let pattern = |x: usize, y: usize| ((x * 7 + y * 13) % 256) as u8;
+
+        for &filter in &FILTERS {
+            let margin = filter.margin();
+            let render = |x0: isize, y0: isize, w: usize, h: usize| -> Vec<u8> {
+                let mut data = vec![];
+                for y in 0..(h + 2 * margin) * factor {
+                    for x in 0..(w + 2 * margin) * factor {
+                        let sx = (x0 * factor as isize + x as isize).rem_euclid(1000) as usize;
+                        let sy = (y0 * factor as isize + y as isize).rem_euclid(1000) as usize;
+                        data.push(pattern(sx, sy));
+                    }
+                }
+                data
+            };
+
+            let m = margin as isize;
+            let whole = downsample(
+                &render(-m, -m, width, height),
+                (width, height),
+                factor,
+                margin,
+                1,
+                1,
+                filter,
+            );
+            let tile = downsample(
+                &render(4 - m, 6 - m, 4, 2),
+                (4, 2),
+                factor,
+                margin,
+                1,
+                1,
+                filter,
+            );
+
+            for y in 0..2 {
+                for x in 0..4 {
+                    assert_eq!(
+                        tile[y * 4 + x],
+                        whole[(6 + y) * width + 4 + x],
+                        "{:?}",
+                        filter
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,113 @@
+use crate::output::tile_bytes_per_pixel;
+use crate::settings::Settings;
+use crate::tiles::blit;
+
+/// A finished tile of a frame, at the tile depth
+#[derive(Debug, Clone)]
+pub struct Tile {
+    pub frame_idx: usize,
+    pub time: f32,
+    /// Number of samples averaged into the tile
+    pub samples: u32,
+    /// Position of the top left corner within the rendered region
+    pub pos: (usize, usize),
+    pub dims: (usize, usize),
+    /// Position among the tiles of the frame, which arrive in this order, and their number
+    pub tile_idx: usize,
+    pub tiles: usize,
+    pub pixels: Vec<u8>,
+}
+
+/// A finished image of the rendered region, at the tile depth
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub index: usize,
+    pub time: f32,
+    /// Number of samples averaged into the frame, fewer than configured for progressive
+    /// snapshots
+    pub samples: u32,
+    pub pixels: Vec<u8>,
+}
+
+/// Assembles tiles into frames
+pub struct FrameAssembler {
+    dims: (usize, usize),
+    bytes_per_pixel: usize,
+    image: Vec<u8>,
+}
+
+impl FrameAssembler {
+    pub fn new(cfg: &Settings) -> Self {
+        let region = cfg.region();
+        Self::with_dims(
+            (region.width as usize, region.height as usize),
+            tile_bytes_per_pixel(cfg),
+        )
+    }
+
+    fn with_dims(dims: (usize, usize), bytes_per_pixel: usize) -> Self {
+        Self {
+            dims,
+            bytes_per_pixel,
+            image: vec![0; dims.0 * dims.1 * bytes_per_pixel],
+        }
+    }
+
+    /// Place a tile, returning its frame if it was the last one
+    pub fn add(&mut self, tile: &Tile) -> Option<Frame> {
+        blit(
+            &tile.pixels,
+            &mut self.image,
+            tile.pos,
+            self.dims,
+            tile.dims,
+            self.bytes_per_pixel,
+        );
+
+        if tile.tile_idx + 1 < tile.tiles {
+            return None;
+        }
+
+        let blank = vec![0; self.image.len()];
+        Some(Frame {
+            index: tile.frame_idx,
+            time: tile.time,
+            samples: tile.samples,
+            pixels: std::mem::replace(&mut self.image, blank),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(frame_idx: usize, tile_idx: usize, pos: (usize, usize), value: u8) -> Tile {
+        Tile {
+            frame_idx,
+            time: frame_idx as f32,
+            samples: 1,
+            pos,
+            dims: (2, 1),
+            tile_idx,
+            tiles: 2,
+            pixels: vec![value; 2],
+        }
+    }
+
+    #[test]
+    fn test_frames_complete_on_their_last_tile() {
+        let mut assembler = FrameAssembler::with_dims((2, 2), 1);
+
+        assert!(assembler.add(&tile(0, 0, (0, 0), 1)).is_none());
+        let frame = assembler.add(&tile(0, 1, (0, 1), 2)).unwrap();
+        assert_eq!(frame.index, 0);
+        assert_eq!(frame.pixels, vec![1, 1, 2, 2]);
+
+        // Tiles of the next frame are assembled separately
+        assert!(assembler.add(&tile(1, 0, (0, 1), 3)).is_none());
+        let frame = assembler.add(&tile(1, 1, (0, 0), 4)).unwrap();
+        assert_eq!(frame.index, 1);
+        assert_eq!(frame.pixels, vec![4, 4, 3, 3]);
+    }
+}
@@ -1,6 +1,30 @@
 //pub mod visualizer;
+pub mod animation;
+pub mod atlas;
+pub mod balance;
+pub mod batch;
+mod devices;
+pub mod distributed;
+pub mod dither;
+pub mod dzi;
 mod engine;
+pub mod filter;
+pub mod frame;
 pub mod offscreen;
+pub mod output;
+pub mod progressive;
+pub mod quantize;
+pub mod ranges;
+pub mod remap;
+pub mod renderer;
+pub mod report;
+pub mod resume;
+pub mod schedule;
 //pub use visualizer::visualize;
 pub mod settings;
+pub mod template;
+pub mod tiff;
 pub mod tiles;
+pub mod uniforms;
+pub mod video;
+pub mod view;
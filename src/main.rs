@@ -1,18 +1,21 @@
-use anyhow::{Context, Result};
-use bosrender::offscreen::OffScreen;
+use anyhow::{ensure, Context, Error, Result};
+use bosrender::batch::{self, Outcome, Status};
+use bosrender::distributed::{Coordinator, Poll, Setup};
+use bosrender::frame::FrameAssembler;
+use bosrender::offscreen::Gpu;
+use bosrender::output::{self, BandStream, Sink};
+use bosrender::renderer::{Event, Renderer, Retry};
+use bosrender::report::{self, JsonEvent, ProgressMode, Throughput};
+use bosrender::resume;
 use bosrender::settings::Settings;
-use std::collections::VecDeque;
-use std::fs::File;
-use std::io::BufWriter;
+use std::collections::hash_map::{Entry, HashMap};
+use std::io::IsTerminal;
+use std::net::TcpListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
-use std::time::{Duration, Instant};
-
-struct Job {
-    pos: (usize, usize),
-    time: f32,
-    frame_idx: usize,
-    tile_idx: usize,
-}
 
 fn main() -> Result<()> {
     // Load configuration
@@ -20,137 +23,416 @@ fn main() -> Result<()> {
 
     // Line displays
     let mut line_display = RealtimeDisplay::from_fps(60.);
+    if cfg.video.as_deref() == Some(Path::new("-")) {
+        line_display = line_display.on_stderr();
+    }
+    if cfg.progress == ProgressMode::Json {
+        line_display = line_display.json();
+    }
     line_display.status_line("Initializing...");
 
+    if cfg.shader.extension() == Some("toml".as_ref()) {
+        let result = batch(&cfg.shader, &mut line_display);
+        return line_display.check(result);
+    }
+    if cfg.coordinator.is_some() {
+        let result = coordinate(&cfg, &mut line_display);
+        return line_display.check(result);
+    }
+
     // Initialize engine
-    let mut engine = OffScreen::new(cfg.clone())?;
+    let result = Renderer::new(cfg.clone()).and_then(|mut renderer| {
+        let interrupted = interrupt_flag()?;
+        render(&cfg, &mut renderer, &mut line_display, &interrupted)
+    });
+    let stopped = line_display.check(result)?;
+    line_display.finish(if stopped { "Stopped" } else { "Finished!" });
 
-    // Calculate tile dimensions
-    let (tile_width, tile_height) = bosrender::offscreen::calc_tile_dims(&cfg);
+    Ok(())
+}
 
-    // Tiles which make up the destination image
-    let tiles = bosrender::tiles::tiles(
-        (cfg.width as _, cfg.height as _),
-        (tile_width as _, tile_height as _),
-    );
+/// Render every frame the renderer gives to the configured output. On Ctrl-C, the frames in
+/// progress are finished and no more are started. Returns whether it was stopped early
+fn render(
+    cfg: &Settings,
+    renderer: &mut Renderer,
+    line_display: &mut RealtimeDisplay,
+    interrupted: &AtomicBool,
+) -> Result<bool> {
+    // Where finished frames go. When streaming, tiles are written out a band at a time instead
+    // of being assembled into whole frames
+    let (_, tile_height) = bosrender::offscreen::calc_tile_dims(cfg);
+    let mut bands = if output::streams_bands(cfg) {
+        Some(output::BandStream::new(&cfg.cropped(), tile_height as _)?)
+    } else {
+        None
+    };
+    let mut sink = match bands {
+        Some(_) => None,
+        None => Some(output::create_sink(cfg)?),
+    };
+    let mut assembler = match bands {
+        Some(_) => None,
+        None => Some(FrameAssembler::new(cfg)),
+    };
+    let mut snapshots = match cfg.snapshot_every {
+        Some(_) => Some(BandStream::snapshots(&cfg.cropped(), tile_height as _)?),
+        None => None,
+    };
+    let region = cfg.region();
 
-    // Determine the total work to be done (all tiles of all frames)
-    let work_order: Vec<Job> = (cfg.first_frame..)
-        .take(cfg.frames)
-        .map(|frame_idx| {
-            let time = cfg.rate * (frame_idx + cfg.first_frame) as f32;
-            tiles.iter().enumerate().map(move |(tile_idx, &pos)| Job {
-                pos,
-                time,
-                frame_idx,
-                tile_idx,
-            })
-        })
-        .flatten()
-        .collect();
-
-    let mut work_order = work_order.into_iter();
-
-    // Submit `frames_in_flight` frames to prime the engine
-    let mut tile_tracker = VecDeque::new();
-    for job in work_order.by_ref().take(cfg.frames_in_flight) {
-        let Job { pos, time, .. } = job;
-        engine.submit_tile(time, pos.0 as _, pos.1 as _)?;
-        tile_tracker.push_back(job);
-    }
-
-    // Download each frame
-    let mut last_frame_idx = cfg.first_frame;
-    let mut current_image = vec![0; (cfg.width * cfg.height * 3) as usize];
+    let started = Instant::now();
+    let mut throughput = Throughput::new();
+    let progress = renderer.progress();
+    line_display.lazy_event(|| {
+        JsonEvent::new("started")
+            .field("frames", progress.frames)
+            .field("width", region.width)
+            .field("height", region.height)
+            .field("pixels", progress.total_pixels)
+    });
 
-    loop {
-        // Download the most recently rendered tile
-        let tile_info = tile_tracker.pop_front();
+    let mut stopping = false;
+
+    while let Some(event) = renderer.next_event()? {
+        if interrupted.load(Ordering::SeqCst) && !stopping {
+            renderer.stop();
+            stopping = true;
+            line_display.finish("Interrupted, finishing the frames in progress...");
+        }
+
+        // Rendering speed, and time left at that speed
+        let progress = renderer.progress();
+        throughput.record(started.elapsed(), progress.pixels);
+        let rate = throughput.rate();
+        let eta = progress
+            .total_pixels
+            .and_then(|total| throughput.eta(total.saturating_sub(progress.pixels)));
 
         // Display the status line
-        if let Some(job) = &tile_info {
-            line_display.status_line(format_args!(
-                "Frame {:>4}/{:<4} (#{:<4}), Tile {:>4}/{:<4}",
-                job.frame_idx + 1 - cfg.first_frame,
-                cfg.frames,
-                job.frame_idx,
-                job.tile_idx + 1,
-                tiles.len()
-            ));
-        }
-
-        // Determine if a frame was finished, and if so at what frame index
-        let finish_frame = match &tile_info {
-            Some(job) => {
-                if job.frame_idx != last_frame_idx {
-                    let finish_frame = last_frame_idx;
-                    last_frame_idx = job.frame_idx;
-                    Some(finish_frame)
-                } else {
-                    None
-                }
-            }
-            None => Some(last_frame_idx),
+        let frames = match progress.frames {
+            Some(frames) => frames.to_string(),
+            None => "-".into(),
         };
+        line_display.lazy_status_line(|| {
+            let speed = match (rate, eta) {
+                (Some(rate), Some(eta)) => format!(
+                    ", {}, ETA {}",
+                    report::format_rate(rate),
+                    report::format_duration(eta)
+                ),
+                (Some(rate), None) => format!(", {}", report::format_rate(rate)),
+                _ => String::new(),
+            };
+            match progress.sample {
+                Some(sample) => format!(
+                    "Frame {:>4}/{:<4} (#{:<4}), Sample {:>4}/{:<4}, Tile {:>4}/{:<4}{}",
+                    progress.frame + 1,
+                    frames,
+                    progress.frame_idx,
+                    sample + 1,
+                    cfg.samples,
+                    progress.tile_idx + 1,
+                    progress.tiles,
+                    speed
+                ),
+                None => format!(
+                    "Frame {:>4}/{:<4} (#{:<4}), Tile {:>4}/{:<4}{}",
+                    progress.frame + 1,
+                    frames,
+                    progress.frame_idx,
+                    progress.tile_idx + 1,
+                    progress.tiles,
+                    speed
+                ),
+            }
+        });
 
-        // If we have tile data, blit it
-        if let Some(job) = &tile_info {
-            let tile_data = engine.download_frame().context("Downloading frame")?;
-            bosrender::tiles::blit_rgb(
-                &tile_data,
-                &mut current_image,
-                job.pos,
-                (cfg.width as _, cfg.height as _),
-                (tile_width as _, tile_height as _),
-            )
+        if let Event::Sample | Event::Tile(_) = event {
+            line_display.lazy_event(|| {
+                JsonEvent::new("tile_done")
+                    .field("frame", progress.frame_idx)
+                    .field("frames_done", progress.frame)
+                    .field("sample", progress.sample)
+                    .field("tile", progress.tile_idx)
+                    .field("tiles", progress.tiles)
+                    .field("pixels", progress.pixels)
+                    .field("pixels_per_second", rate)
+                    .field("eta", eta.map(|eta| eta.as_secs_f64()))
+            });
         }
 
-        // If we've finished a frame, save it
-        if let Some(frame_idx) = finish_frame {
-            let path = format!("{}_{:04}.png", cfg.name, frame_idx);
-            write_rgb_png(cfg.width, cfg.height, &current_image, &path).context("Writing image")?;
-
-            // We're completely finished
-            if frame_idx + 1 == cfg.frames + cfg.first_frame {
-                break;
+        match event {
+            Event::Sample => (),
+            Event::Retried(retry) => line_display.retried(&retry),
+            Event::Tile(tile) => match &mut bands {
+                Some(bands) => {
+                    bands.write_tile(&tile).context("Writing band")?;
+                    if tile.tile_idx + 1 == tile.tiles {
+                        line_display.frame_written(tile.frame_idx);
+                    }
+                }
+                None => {
+                    let frame = assembler
+                        .as_mut()
+                        .and_then(|assembler| assembler.add(&tile));
+                    if let Some(frame) = frame {
+                        if let Some(sink) = &mut sink {
+                            sink.write_frame(frame.index, &frame.pixels)
+                                .context("Writing frame")?;
+                            line_display.frame_written(frame.index);
+                        }
+                    }
+                }
+            },
+            // Write intermediate snapshots a band at a time
+            Event::Snapshot(tile) => {
+                if let Some(snapshots) = &mut snapshots {
+                    snapshots.write_tile(&tile).context("Writing snapshot")?;
+                    if tile.tile_idx + 1 == tile.tiles {
+                        snapshots.finish().context("Writing snapshot")?;
+                    }
+                }
             }
         }
+    }
 
-        // Submit new work, if any
-        if let Some(job) = work_order.next() {
-            engine.submit_tile(job.time, job.pos.0 as _, job.pos.1 as _)?;
-            tile_tracker.push_back(job);
+    finish_output(&mut bands, &mut sink)?;
+
+    let progress = renderer.progress();
+    let secs = started.elapsed().as_secs_f64();
+    line_display.lazy_event(|| {
+        JsonEvent::new("finished")
+            .field("frames", progress.frame)
+            .field("stopped", stopping)
+            .field("pixels", progress.pixels)
+            .field("pixels_per_second", progress.pixels as f64 / secs)
+    });
+    Ok(stopping)
+}
+
+/// Cores kept between the jobs of a batch, by validation and devices
+type Gpus = HashMap<(bool, Vec<usize>), Vec<Gpu>>;
+
+/// Render each job of a TOML manifest in turn, then print how each went
+fn batch(manifest: &Path, line_display: &mut RealtimeDisplay) -> Result<()> {
+    // Only --progress applies to the batch as a whole
+    let mut args = std::env::args().skip(1);
+    let mut others = 0;
+    while let Some(arg) = args.next() {
+        if arg == "--progress" {
+            args.next();
+        } else if !arg.starts_with("--progress=") {
+            others += 1;
         }
     }
+    ensure!(
+        others == 1,
+        "Settings for a batch go in the manifest's [defaults] table, not on the command line"
+    );
+    let jobs = batch::load(manifest)?;
+    let interrupted = interrupt_flag()?;
 
-    println!();
-    println!("Finished!");
+    let mut gpus = Gpus::new();
+    let mut outcomes = vec![];
+    for (idx, job) in jobs.iter().enumerate() {
+        // Jobs after an interrupted one aren't started
+        if interrupted.load(Ordering::SeqCst) {
+            outcomes.push(Outcome {
+                label: job.label.clone(),
+                status: Status::Skipped,
+                frames: 0,
+                time: Duration::ZERO,
+            });
+            continue;
+        }
 
+        line_display.finish(format!("Job {}/{}: {}", idx + 1, jobs.len(), job.label));
+        let start = Instant::now();
+        let mut frames = 0;
+        let result = render_job(&job.cfg, &mut gpus, line_display, &interrupted, &mut frames);
+        outcomes.push(Outcome {
+            label: job.label.clone(),
+            status: match result {
+                Ok(false) => Status::Finished,
+                Ok(true) => Status::Stopped,
+                Err(e) => {
+                    line_display.fail(&e);
+                    Status::Failed(format!("{:#}", e))
+                }
+            },
+            frames,
+            time: start.elapsed(),
+        });
+    }
+
+    line_display.finish(batch::summary(&outcomes));
+    let failed = outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome.status, Status::Failed(_)))
+        .count();
+    ensure!(failed == 0, "{} of {} jobs failed", failed, outcomes.len());
     Ok(())
 }
 
-fn write_rgb_png(width: u32, height: u32, data: &[u8], path: &str) -> Result<()> {
-    debug_assert_eq!(data.len() % 3, 0);
-    debug_assert_eq!(data.len() % width as usize, 0);
-    debug_assert_eq!(data.len() % height as usize, 0);
-    debug_assert_eq!(data.len() / width as usize, height as usize);
+/// Render a job of a batch on the cores for its devices, building them if no earlier job did.
+/// Counts finished frames into `frames`, so that they are known even if the job fails
+fn render_job(
+    cfg: &Settings,
+    gpus: &mut Gpus,
+    line_display: &mut RealtimeDisplay,
+    interrupted: &AtomicBool,
+    frames: &mut usize,
+) -> Result<bool> {
+    let gpus = match gpus.entry((cfg.validation, cfg.devices.clone())) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(Gpu::for_settings(cfg)?),
+    };
+    let mut renderer = Renderer::on_gpus(cfg.clone(), resume::work_order(cfg)?, gpus)?;
+    let result = render(cfg, &mut renderer, line_display, interrupted);
+    *frames = renderer.progress().frame;
+    // Later jobs use any devices rebuilt after a fault
+    gpus.clone_from_slice(renderer.gpus());
+    result
+}
+
+/// Hand frames out to workers instead of rendering them here, and write them as they come back
+fn coordinate(cfg: &Settings, line_display: &mut RealtimeDisplay) -> Result<()> {
+    ensure!(
+        !output::streams_bands(cfg),
+        "Distributed renders can't be streamed in bands"
+    );
+    ensure!(
+        cfg.snapshot_every.is_none(),
+        "Distributed renders can't write snapshots"
+    );
+
+    let address = cfg.coordinator.as_deref().unwrap_or_default();
+    let setup = Setup::new(cfg, std::env::args())?;
+    let work_order = resume::work_order(cfg)?;
+    let listener =
+        TcpListener::bind(address).with_context(|| format!("Listening on {}", address))?;
+    let mut sink = output::create_sink(cfg)?;
+    let coordinator = Coordinator::start(cfg, setup, listener, work_order)?;
 
-    let file = File::create(&path).with_context(|| format!("Failed to create image {}", path))?;
-    let ref mut w = BufWriter::new(file);
+    // Workers send whole frames, so each counts as a frame of one tile
+    let region = cfg.region();
+    let frame_pixels = region.width as u64 * region.height as u64;
+    let frames = coordinator.status().frames;
+    let total_pixels = frames.map(|frames| frames as u64 * frame_pixels);
+    line_display.lazy_event(|| {
+        JsonEvent::new("started")
+            .field("frames", frames)
+            .field("width", region.width)
+            .field("height", region.height)
+            .field("pixels", total_pixels)
+    });
 
-    let mut encoder = png::Encoder::new(w, width, height);
-    encoder.set_color(png::ColorType::Rgb);
-    encoder.set_depth(png::BitDepth::Eight);
+    let started = Instant::now();
+    let mut throughput = Throughput::new();
+    let interrupted = interrupt_flag()?;
+    let mut stopping = false;
+    loop {
+        if interrupted.load(Ordering::SeqCst) && !stopping {
+            coordinator.stop();
+            stopping = true;
+            line_display.finish("Interrupted, waiting for the frames in progress...");
+        }
 
-    let mut writer = encoder.write_header()?;
-    writer.write_image_data(data)?;
+        match coordinator.poll(Duration::from_millis(100))? {
+            Poll::Frame(frame) => {
+                sink.write_frame(frame.index, &frame.pixels)
+                    .context("Writing frame")?;
 
+                let frames_done = coordinator.status().frames_done;
+                let pixels = frames_done as u64 * frame_pixels;
+                throughput.record(started.elapsed(), pixels);
+                let rate = throughput.rate();
+                let eta =
+                    total_pixels.and_then(|total| throughput.eta(total.saturating_sub(pixels)));
+                line_display.lazy_event(|| {
+                    JsonEvent::new("tile_done")
+                        .field("frame", frame.index)
+                        .field("frames_done", frames_done)
+                        .field("sample", None::<u32>)
+                        .field("tile", 0usize)
+                        .field("tiles", 1usize)
+                        .field("pixels", pixels)
+                        .field("pixels_per_second", rate)
+                        .field("eta", eta.map(|eta| eta.as_secs_f64()))
+                });
+                line_display.frame_written(frame.index);
+            }
+            Poll::Pending => (),
+            Poll::Finished => break,
+        }
+
+        let status = coordinator.status();
+        let frames = match status.frames {
+            Some(frames) => frames.to_string(),
+            None => "-".into(),
+        };
+        line_display.lazy_status_line(|| {
+            format!(
+                "Frame {:>4}/{:<4}, {} workers on {}",
+                status.frames_done, frames, status.workers, address
+            )
+        });
+    }
+
+    sink.finish().context("Finishing output")?;
+    line_display.finish(if stopping { "Stopped" } else { "Finished!" });
+
+    let frames_done = coordinator.status().frames_done;
+    let pixels = frames_done as u64 * frame_pixels;
+    let secs = started.elapsed().as_secs_f64();
+    line_display.lazy_event(|| {
+        JsonEvent::new("finished")
+            .field("frames", frames_done)
+            .field("stopped", stopping)
+            .field("pixels", pixels)
+            .field("pixels_per_second", pixels as f64 / secs)
+    });
     Ok(())
 }
 
+/// Flag set on Ctrl-C, so that the frames in progress can be finished. A second Ctrl-C exits
+/// right away
+fn interrupt_flag() -> Result<Arc<AtomicBool>> {
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let handler_interrupted = interrupted.clone();
+    ctrlc::set_handler(move || {
+        if handler_interrupted.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    })
+    .context("Installing Ctrl-C handler")?;
+    Ok(interrupted)
+}
+
+/// Complete whichever output is in use
+fn finish_output(bands: &mut Option<BandStream>, sink: &mut Option<Box<dyn Sink>>) -> Result<()> {
+    if let Some(bands) = bands {
+        bands.finish().context("Finishing output")?;
+    }
+    if let Some(sink) = sink {
+        sink.finish().context("Finishing output")?;
+    }
+    Ok(())
+}
+
+/// Status lines on a stream that isn't a terminal are printed as plain lines this often
+const PLAIN_INTERVAL: Duration = Duration::from_secs(5);
+
 struct RealtimeDisplay {
     last_update: Instant,
     refresh_interval: Duration,
+    stderr: bool,
+    /// Whether the stream is a terminal, which status lines are redrawn in place on
+    tty: bool,
+    /// Print JSON events instead of status lines
+    json: bool,
+    start: Instant,
 }
 
 impl RealtimeDisplay {
@@ -160,13 +442,38 @@ impl RealtimeDisplay {
 
     pub fn from_interval(refresh_interval: Duration) -> Self {
         Self {
-            last_update: Instant::now() - refresh_interval,
+            // Show the first status line right away
+            last_update: Instant::now()
+                .checked_sub(refresh_interval.max(PLAIN_INTERVAL))
+                .unwrap_or_else(Instant::now),
             refresh_interval,
+            stderr: false,
+            tty: std::io::stdout().is_terminal(),
+            json: false,
+            start: Instant::now(),
         }
     }
 
+    /// Print to stderr instead, leaving stdout free for frame data
+    pub fn on_stderr(mut self) -> Self {
+        self.stderr = true;
+        self.tty = std::io::stderr().is_terminal();
+        self
+    }
+
+    /// Print a JSON object per event instead of status lines, for --progress json
+    pub fn json(mut self) -> Self {
+        self.json = true;
+        self
+    }
+
     pub fn needs_update(&mut self) -> bool {
-        if self.last_update.elapsed() >= self.refresh_interval {
+        let interval = if self.tty {
+            self.refresh_interval
+        } else {
+            PLAIN_INTERVAL
+        };
+        if self.last_update.elapsed() >= interval {
             self.last_update = Instant::now();
             true
         } else {
@@ -179,11 +486,129 @@ impl RealtimeDisplay {
     }
 
     pub fn lazy_status_line<F: FnOnce() -> T, T: std::fmt::Display>(&mut self, f: F) {
-        if self.needs_update() {
+        if self.json || !self.needs_update() {
+            return;
+        }
+        if self.tty {
             // TODO: Make sure this works on Windows?
-            print!("\r\x1b[1K{}", f());
-            use std::io::Write;
-            std::io::stdout().flush().unwrap();
+            self.print(format_args!("\r\x1b[1K{}", f()));
+        } else {
+            self.print(format_args!("{}\n", f()));
+        }
+    }
+
+    /// End the status line and print a final message
+    pub fn finish<T: std::fmt::Display>(&mut self, v: T) {
+        if self.json {
+            let message = v.to_string();
+            self.lazy_event(|| JsonEvent::new("message").field("message", message));
+        } else if self.tty {
+            self.print(format_args!("\n{}\n", v));
+        } else {
+            self.print(format_args!("{}\n", v));
+        }
+    }
+
+    /// Report a tile that failed on the GPU and is being rendered again
+    pub fn retried(&mut self, retry: &Retry) {
+        let sample = match retry.sample {
+            Some(sample) => format!(", sample {}", sample + 1),
+            None => String::new(),
+        };
+        let how = match retry.pieces {
+            Some(pieces) => format!(" in up to {}x{} pieces", pieces, pieces),
+            None => String::new(),
+        };
+        let message = format!(
+            "Frame {}{}, tile {}/{} ({}x{} at {},{}) failed on device {}: {}. \
+             Rendering it again{} ({}/{})",
+            retry.frame_idx,
+            sample,
+            retry.tile_idx + 1,
+            retry.tiles,
+            retry.rect.width,
+            retry.rect.height,
+            retry.rect.x,
+            retry.rect.y,
+            retry.device,
+            retry.fault,
+            how,
+            retry.attempt,
+            retry.max_attempts
+        );
+        if !self.json {
+            self.finish(message);
+            return;
+        }
+        self.lazy_event(|| {
+            JsonEvent::new("error")
+                .field("message", message)
+                .field("recovered", true)
+                .field("frame", retry.frame_idx)
+                .field("tile", retry.tile_idx)
+                .field("tiles", retry.tiles)
+                .field("sample", retry.sample)
+                .field("x", retry.rect.x)
+                .field("y", retry.rect.y)
+                .field("width", retry.rect.width)
+                .field("height", retry.rect.height)
+                .field("device", retry.device)
+                .field("fault", retry.fault.kind())
+                .field("attempt", retry.attempt)
+                .field("max_attempts", retry.max_attempts)
+        });
+    }
+
+    /// Report an error that stopped a render. Only JSON events include it, as the error is also
+    /// returned
+    pub fn fail(&mut self, error: &Error) {
+        self.error_event(&format!("{:#}", error), false);
+    }
+
+    /// Pass a result on, reporting its error if it failed
+    pub fn check<T>(&mut self, result: Result<T>) -> Result<T> {
+        if let Err(e) = &result {
+            self.fail(e);
+        }
+        result
+    }
+
+    pub fn frame_written(&mut self, frame_idx: usize) {
+        self.lazy_event(|| JsonEvent::new("frame_written").field("frame", frame_idx));
+    }
+
+    fn error_event(&mut self, message: &str, recovered: bool) {
+        self.lazy_event(|| {
+            JsonEvent::new("error")
+                .field("message", message)
+                .field("recovered", recovered)
+        });
+    }
+
+    /// Print a JSON event, timestamped in seconds since the Unix epoch and since the start
+    pub fn lazy_event<F: FnOnce() -> JsonEvent>(&mut self, f: F) {
+        if !self.json {
+            return;
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let event = f()
+            .field("timestamp", timestamp.as_secs_f64())
+            .field("elapsed", self.start.elapsed().as_secs_f64());
+        self.print(format_args!("{}\n", event));
+    }
+
+    fn print(&self, args: std::fmt::Arguments) {
+        use std::io::Write;
+        if self.stderr {
+            let mut stderr = std::io::stderr();
+            stderr.write_fmt(args).unwrap();
+            stderr.flush().unwrap();
+        } else {
+            let mut stdout = std::io::stdout();
+            stdout.write_fmt(args).unwrap();
+            stdout.flush().unwrap();
         }
     }
 }
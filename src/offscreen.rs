@@ -1,9 +1,19 @@
 use crate::{
-    engine::{Engine, SceneData},
-    settings::Settings,
+    devices,
+    engine::{Blend, Engine, Prelude, SceneData, ShaderCache, Subframe},
+    filter, output,
+    renderer::{Device, TileEngine},
+    settings::{AlphaMode, BitDepth, Settings},
+    tiles::Rect,
+};
+use anyhow::{bail, ensure, Result};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    mem::{self, ManuallyDrop},
+    sync::Arc,
+    time::Duration,
 };
-use anyhow::Result;
-use std::{collections::VecDeque, sync::Arc, time::Duration};
 use watertender::app_info::AppInfo;
 use watertender::defaults::DEPTH_FORMAT;
 use watertender::headless_backend::build_core;
@@ -18,41 +28,246 @@ struct Frame {
     fb_depth_image_view: vk::ImageView,
     fb_download_buf: ManagedBuffer,
     fence: vk::Fence,
+    /// Output size of the tile last submitted with this frame
+    dims: (u32, u32),
+    /// Samples summed in what the frame last copied out, which its download is divided by
+    samples: u32,
+    /// Tile whose running sum is freed once the frame has copied it out for the last time
+    release: Option<usize>,
+}
+
+/// Running sum of the progressive samples of a tile, kept on the device between passes
+struct Sum {
+    framebuffer: vk::Framebuffer,
+    image: ManagedImage,
+    image_view: vk::ImageView,
 }
 
 pub struct OffScreen {
     command_pool: vk::CommandPool,
     render_pass: vk::RenderPass,
+    /// Adds samples to a running sum instead of clearing it
+    sum_pass: vk::RenderPass,
 
     frames: Vec<Frame>,
-    fb_extent: vk::Extent2D,
-    fb_size_bytes: u64,
+    color_format: vk::Format,
+    /// Bytes per pixel of the render target
+    texel_size: u64,
+    tile_dims: (u32, u32),
+    /// Output pixels rendered around each tile for the supersampling filter
+    margin: u32,
     frame_indices_in_flight: VecDeque<usize>,
     available_indices: Vec<usize>,
     command_buffers: Vec<vk::CommandBuffer>,
+    subframes: Vec<Subframe>,
+    /// Running sums of progressive tiles by tile index, and the depth buffer they share
+    sums: HashMap<usize, Sum>,
+    sum_depth: Option<(ManagedImage, vk::ImageView)>,
+    /// Timestamps before and after each frame's commands
+    query_pool: vk::QueryPool,
+    /// Nanoseconds per timestamp tick, if the queue supports timestamps
+    timestamp_period: Option<f32>,
+    last_gpu_time: Option<Duration>,
 
     cfg: Settings,
 
-    engine: Engine,
+    /// Only dropped once nothing in flight uses it
+    engine: ManuallyDrop<Engine>,
     core: SharedCore,
 }
 
-const COLOR_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+/// Render target format for the given bit depth. Subframes are accumulated in float, in 32 bits
+/// if the device can blend into them. Blending into 16-bit floats is always supported
+fn color_format(core: &Core, depth: BitDepth, accumulate: bool) -> Result<vk::Format> {
+    if !accumulate {
+        return Ok(match depth {
+            BitDepth::Eight => vk::Format::R8G8B8A8_UNORM,
+            BitDepth::Sixteen => vk::Format::R16G16B16A16_UNORM,
+        });
+    }
+    for format in [
+        vk::Format::R32G32B32A32_SFLOAT,
+        vk::Format::R16G16B16A16_SFLOAT,
+    ] {
+        let properties = unsafe {
+            core.instance
+                .get_physical_device_format_properties(core.physical_device, format)
+        };
+        if properties
+            .optimal_tiling_features
+            .contains(vk::FormatFeatureFlags::COLOR_ATTACHMENT_BLEND)
+        {
+            return Ok(format);
+        }
+    }
+    bail!(
+        "The Vulkan device can't blend into a float render target, which motion blur and \
+         progressive rendering accumulate in"
+    )
+}
+
+/// Bytes per pixel of a render target format
+fn texel_size(format: vk::Format) -> u64 {
+    match format {
+        vk::Format::R32G32B32A32_SFLOAT => 16,
+        vk::Format::R16G16B16A16_SFLOAT | vk::Format::R16G16B16A16_UNORM => 8,
+        _ => 4,
+    }
+}
+
+/// Whether renders are summed in a float target, for motion blur or progressive rendering
+fn accumulates(cfg: &Settings) -> bool {
+    cfg.motion_blur > 1 || cfg.progressive()
+}
+
+/// Time offsets and weights of the renders averaged into each frame, spread evenly across the
+/// shutter interval centered on the frame's time
+fn subframes(cfg: &Settings) -> Vec<Subframe> {
+    let samples = cfg.motion_blur.max(1);
+    let shutter = cfg.rate * cfg.shutter_angle / 360.;
+    (0..samples)
+        .map(|i| Subframe {
+            time_offset: ((i as f32 + 0.5) / samples as f32 - 0.5) * shutter,
+            weight: 1. / samples as f32,
+            sample: 0,
+            seed: cfg.seed,
+        })
+        .collect()
+}
+
+/// Default tile size when streaming bands, small enough to keep a band of tiles in memory
+const STREAM_TILE_DIMS: (u32, u32) = (4096, 256);
 
 pub fn calc_tile_dims(cfg: &Settings) -> (u32, u32) {
+    let region = cfg.region();
+    let (default_width, default_height) = if crate::output::streams_bands(cfg) {
+        (
+            region.width.min(STREAM_TILE_DIMS.0),
+            region.height.min(STREAM_TILE_DIMS.1),
+        )
+    } else {
+        (region.width, region.height)
+    };
+
     (
-        cfg.tile_width.unwrap_or(cfg.width),
-        cfg.tile_height.unwrap_or(cfg.height),
+        cfg.tile_width.unwrap_or(default_width),
+        cfg.tile_height.unwrap_or(default_height),
     )
 }
 
+/// A failure of the GPU on a tile, after which its device has to be rebuilt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpuFault {
+    /// The tile wasn't finished within the timeout
+    Timeout(Duration),
+    /// The driver gave up on the device, usually after a hang or crash
+    DeviceLost,
+}
+
+impl fmt::Display for GpuFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GpuFault::Timeout(timeout) => {
+                write!(f, "Timed out after {:.1}s", timeout.as_secs_f32())
+            }
+            GpuFault::DeviceLost => write!(f, "Vulkan device lost"),
+        }
+    }
+}
+
+impl GpuFault {
+    /// Short name of the kind of fault, for machine-readable progress
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GpuFault::Timeout(_) => "timeout",
+            GpuFault::DeviceLost => "device_lost",
+        }
+    }
+}
+
+impl std::error::Error for GpuFault {}
+
+/// A Vulkan core and the shaders compiled on it. Renders sharing one, as the jobs of a batch do,
+/// build the core once and compile each shader once
+#[derive(Clone)]
+pub struct Gpu {
+    core: SharedCore,
+    shaders: Arc<ShaderCache>,
+    validation: bool,
+    device: Option<usize>,
+}
+
+impl Gpu {
+    /// Build a core on the given Vulkan device, by its index in the order the loader lists them,
+    /// or on the one watertender picks
+    pub fn new(validation: bool, device: Option<usize>) -> Result<Self> {
+        let core = match device {
+            Some(index) => devices::build_core(validation, index)?,
+            None => {
+                let info = AppInfo::default()
+                    .validation(validation)
+                    .vk_version(1, 1, 0);
+                build_core(info)?
+            }
+        };
+        let core = Arc::new(core);
+        let shaders = Arc::new(ShaderCache::new(core.clone())?);
+        Ok(Self {
+            core,
+            shaders,
+            validation,
+            device,
+        })
+    }
+
+    /// One for each of the configured devices, or one on the device watertender picks
+    pub fn for_settings(cfg: &Settings) -> Result<Vec<Self>> {
+        if cfg.devices.is_empty() {
+            return Ok(vec![Self::new(cfg.validation, None)?]);
+        }
+        cfg.devices
+            .iter()
+            .map(|&device| Self::new(cfg.validation, Some(device)))
+            .collect()
+    }
+}
+
+impl Device for Gpu {
+    type Engine = OffScreen;
+
+    fn engine(&self, cfg: &Settings) -> Result<OffScreen> {
+        OffScreen::on_gpu(cfg.clone(), self)
+    }
+
+    /// A new core on the same device, to replace one that was lost. Shaders are compiled again
+    fn rebuild(&self) -> Result<Self> {
+        Self::new(self.validation, self.device)
+    }
+}
+
 impl OffScreen {
     pub fn new(cfg: Settings) -> Result<Self> {
-        let info = AppInfo::default()
-            .validation(cfg.validation)
-            .vk_version(1, 1, 0);
-        let core = build_core(info)?;
-        let core = Arc::new(core);
+        let gpu = Gpu::new(cfg.validation, None)?;
+        Self::on_gpu(cfg, &gpu)
+    }
+
+    /// Render on a core which may already have been used for other renders
+    pub fn on_gpu(cfg: Settings, gpu: &Gpu) -> Result<Self> {
+        ensure!(cfg.ssaa >= 1, "Supersampling factor must be at least 1");
+        ensure!(
+            cfg.region().fits((cfg.width, cfg.height)),
+            "Crop region {:?} does not fit in the {}x{} image",
+            cfg.region(),
+            cfg.width,
+            cfg.height
+        );
+        ensure!(
+            (0. ..=360.).contains(&cfg.shutter_angle),
+            "Shutter angle must be between 0 and 360 degrees"
+        );
+        ensure!(cfg.gpu_timeout > 0., "GPU timeout must be positive");
+
+        let core = gpu.core.clone();
 
         // Command pool
         let create_info = vk::CommandPoolCreateInfoBuilder::new()
@@ -71,20 +286,52 @@ impl OffScreen {
             unsafe { core.device.allocate_command_buffers(&allocate_info) }.result()?;
 
         // Create render pass
-        let render_pass = create_render_pass(&core)?;
-
-        // Create engine
-        let engine = Engine::new(core.clone(), cfg.frames_in_flight, &cfg.shader, render_pass)?;
-
-        // Output extent
-        let (width, height) = calc_tile_dims(&cfg);
+        let accumulate = accumulates(&cfg);
+        let color_format = color_format(&core, output::tile_depth(&cfg), accumulate)?;
+        let render_pass = create_render_pass(&core, color_format, false)?;
+        let sum_pass = create_render_pass(&core, color_format, true)?;
+
+        // Create engine. Blending against the clear color is disabled when keeping alpha, so that
+        // the shader's output is stored as-is. Accumulation sums weighted subframes instead
+        let blend = if accumulate {
+            Blend::Accumulate
+        } else if cfg.alpha.is_none() {
+            Blend::Background
+        } else {
+            Blend::Replace
+        };
+        let engine = Engine::new(
+            core.clone(),
+            cfg.frames_in_flight,
+            &cfg.shader,
+            render_pass,
+            blend,
+            &Prelude {
+                remap: cfg.remap,
+                mask: cfg.remap_mask,
+                view: cfg.view.map(|view| view.matrix((cfg.width, cfg.height))),
+                ssaa: cfg.ssaa,
+                uniforms: cfg.uniforms.clone(),
+            },
+            &gpu.shaders,
+        )?;
+
+        // Output extent. Supersampled tiles are rendered larger, with a margin so that the filter
+        // sees the same neighbourhood on either side of a seam
+        let tile_dims = calc_tile_dims(&cfg);
+        let margin = if cfg.ssaa > 1 {
+            cfg.ssaa_filter.margin() as u32
+        } else {
+            0
+        };
         let fb_extent = vk::Extent2DBuilder::new()
-            .width(width)
-            .height(height)
+            .width((tile_dims.0 + 2 * margin) * cfg.ssaa)
+            .height((tile_dims.1 + 2 * margin) * cfg.ssaa)
             .build();
 
         // Framebuffer size
-        let fb_size_bytes = (cfg.height * cfg.width * 4) as u64 * std::mem::size_of::<u8>() as u64;
+        let texel_size = texel_size(color_format);
+        let fb_size_bytes = fb_extent.width as u64 * fb_extent.height as u64 * texel_size;
 
         // Frames in flight
         let mut frames = vec![];
@@ -97,99 +344,14 @@ impl OffScreen {
 
             let fb_download_buf = ManagedBuffer::new(core.clone(), bi, UsageFlags::DOWNLOAD)?;
 
-            // Create depth image
-            let create_info = vk::ImageCreateInfoBuilder::new()
-                .image_type(vk::ImageType::_2D)
-                .extent(
-                    vk::Extent3DBuilder::new()
-                        .width(fb_extent.width)
-                        .height(fb_extent.height)
-                        .depth(1)
-                        .build(),
-                )
-                .mip_levels(1)
-                .array_layers(1)
-                .format(DEPTH_FORMAT)
-                .tiling(vk::ImageTiling::OPTIMAL)
-                .initial_layout(vk::ImageLayout::UNDEFINED)
-                .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
-                .samples(vk::SampleCountFlagBits::_1)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-            let fb_depth_image =
-                ManagedImage::new(core.clone(), create_info, UsageFlags::FAST_DEVICE_ACCESS)?;
-
-            let create_info = vk::ImageViewCreateInfoBuilder::new()
-                .image(fb_depth_image.instance())
-                .view_type(vk::ImageViewType::_2D)
-                .format(DEPTH_FORMAT)
-                .subresource_range(
-                    vk::ImageSubresourceRangeBuilder::new()
-                        .aspect_mask(vk::ImageAspectFlags::DEPTH)
-                        .base_mip_level(0)
-                        .level_count(1)
-                        .base_array_layer(0)
-                        .layer_count(1)
-                        .build(),
-                );
-            let fb_depth_image_view =
-                unsafe { core.device.create_image_view(&create_info, None, None) }.result()?;
-
-            // Build image views and buffers
-            let create_info = vk::ImageCreateInfoBuilder::new()
-                .image_type(vk::ImageType::_2D)
-                .extent(
-                    vk::Extent3DBuilder::new()
-                        .width(fb_extent.width)
-                        .height(fb_extent.height)
-                        .depth(1)
-                        .build(),
-                )
-                .mip_levels(1)
-                .array_layers(1)
-                .format(COLOR_FORMAT)
-                .tiling(vk::ImageTiling::OPTIMAL)
-                .initial_layout(vk::ImageLayout::UNDEFINED)
-                .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
-                .samples(vk::SampleCountFlagBits::_1)
-                .sharing_mode(vk::SharingMode::EXCLUSIVE);
-
-            let fb_image =
-                ManagedImage::new(core.clone(), create_info, UsageFlags::FAST_DEVICE_ACCESS)?;
-
-            let create_info = vk::ImageViewCreateInfoBuilder::new()
-                .image(fb_image.instance())
-                .view_type(vk::ImageViewType::_2D)
-                .format(COLOR_FORMAT)
-                .components(vk::ComponentMapping {
-                    r: vk::ComponentSwizzle::IDENTITY,
-                    g: vk::ComponentSwizzle::IDENTITY,
-                    b: vk::ComponentSwizzle::IDENTITY,
-                    a: vk::ComponentSwizzle::IDENTITY,
-                })
-                .subresource_range(
-                    vk::ImageSubresourceRangeBuilder::new()
-                        .aspect_mask(vk::ImageAspectFlags::COLOR)
-                        .base_mip_level(0)
-                        .level_count(1)
-                        .base_array_layer(0)
-                        .layer_count(1)
-                        .build(),
-                );
-
-            let fb_image_view =
-                unsafe { core.device.create_image_view(&create_info, None, None) }.result()?;
-
-            let attachments = [fb_image_view, fb_depth_image_view];
-            let create_info = vk::FramebufferCreateInfoBuilder::new()
-                .render_pass(render_pass)
-                .attachments(&attachments)
-                .width(fb_extent.width)
-                .height(fb_extent.height)
-                .layers(1);
-
-            let framebuffer =
-                unsafe { core.device.create_framebuffer(&create_info, None, None) }.result()?;
+            let (fb_depth_image, fb_depth_image_view) = depth_target(&core, fb_extent)?;
+            let (fb_image, fb_image_view) = color_target(&core, color_format, fb_extent)?;
+            let framebuffer = create_framebuffer(
+                &core,
+                render_pass,
+                &[fb_image_view, fb_depth_image_view],
+                fb_extent,
+            )?;
 
             let ci = vk::FenceCreateInfoBuilder::new().build();
             let fence = unsafe { core.device.create_fence(&ci, None, None).result()? };
@@ -202,89 +364,143 @@ impl OffScreen {
                 fb_depth_image_view,
                 fb_download_buf,
                 fence,
+                dims: tile_dims,
+                samples: 1,
+                release: None,
             };
 
             frames.push(frame);
         }
 
+        // Progressive samples are summed in a target per tile. Depth isn't tested, so one depth
+        // buffer does for all of them
+        let sum_depth = if cfg.progressive() {
+            Some(depth_target(&core, fb_extent)?)
+        } else {
+            None
+        };
+
+        // Timestamp queries, used to measure tiles for adaptive tile sizing
+        let create_info = vk::QueryPoolCreateInfoBuilder::new()
+            .query_type(vk::QueryType::TIMESTAMP)
+            .query_count(2 * cfg.frames_in_flight as u32);
+        let query_pool =
+            unsafe { core.device.create_query_pool(&create_info, None, None) }.result()?;
+        let limits = unsafe {
+            core.instance
+                .get_physical_device_properties(core.physical_device)
+        }
+        .limits;
+        let timestamp_period = if limits.timestamp_compute_and_graphics != 0 {
+            Some(limits.timestamp_period)
+        } else {
+            None
+        };
+
         Ok(Self {
             render_pass,
+            sum_pass,
             frames,
-            fb_extent,
-            fb_size_bytes,
+            color_format,
+            texel_size,
+            tile_dims,
+            margin,
             frame_indices_in_flight: VecDeque::new(),
             available_indices: (0..cfg.frames_in_flight).collect(),
+            subframes: subframes(&cfg),
+            sums: HashMap::new(),
+            sum_depth,
+            query_pool,
+            timestamp_period,
+            last_gpu_time: None,
             cfg,
             core,
             command_buffers,
             command_pool,
-            engine,
+            engine: ManuallyDrop::new(engine),
         })
     }
 
     pub fn submit_tile(&mut self, time: f32, offset_x: i32, offset_y: i32) -> Result<()> {
+        let dims = self.tile_dims;
+        self.submit(time, (offset_x, offset_y), dims, 0, self.cfg.seed, None)
+    }
+
+    /// Render a tile, copied out to be downloaded, or one progressive sample of it added to the
+    /// running sum kept on the device for tile `sum`. The first sample starts the sum afresh
+    fn submit(
+        &mut self,
+        time: f32,
+        (offset_x, offset_y): (i32, i32),
+        dims: (u32, u32),
+        sample: u32,
+        seed: u32,
+        sum: Option<usize>,
+    ) -> Result<()> {
+        let extent = self.extent(dims);
         let scene = SceneData {
-            offset_x,
-            offset_y,
+            offset_x: offset_x - self.margin as i32,
+            offset_y: offset_y - self.margin as i32,
             resolution_x: self.cfg.width as f32,
             resolution_y: self.cfg.height as f32,
             time,
         };
 
-        let frame_idx = self
-            .available_indices
-            .pop()
-            .expect("No more frames in flight left. Perhaps you didn't download a frame?");
-
-        let frame = &self.frames[frame_idx];
-        let command_buffer = self.command_buffers[frame_idx];
+        if let Some(tile_idx) = sum {
+            self.create_sum(tile_idx)?;
+        }
+        let (frame_idx, command_buffer) = self.begin(dims, 1)?;
+        let (image, framebuffer, render_pass) = match sum {
+            Some(tile_idx) => {
+                let sum = &self.sums[&tile_idx];
+                (sum.image.instance(), sum.framebuffer, self.sum_pass)
+            }
+            None => {
+                let frame = &self.frames[frame_idx];
+                (
+                    frame.fb_image.instance(),
+                    frame.framebuffer,
+                    self.render_pass,
+                )
+            }
+        };
+        let fresh = sum.is_none() || sample == 0;
 
-        // Record command buffer to upload to gpu_buffer
         unsafe {
-            self.core
-                .device
-                .reset_command_buffer(command_buffer, None)
-                .result()?;
-            let begin_info = vk::CommandBufferBeginInfoBuilder::new();
-            self.core
-                .device
-                .begin_command_buffer(command_buffer, &begin_info)
-                .result()?;
-
-            // Barrier (UNDEFINED -> TRANSFER_DST_OPTIMAL)
-            let image_subresource = vk::ImageSubresourceRangeBuilder::new()
-                .aspect_mask(vk::ImageAspectFlags::COLOR)
-                .base_mip_level(0)
-                .level_count(1)
-                .base_array_layer(0)
-                .layer_count(1)
-                .build();
-
-            let barrier = vk::ImageMemoryBarrierBuilder::new()
-                .image(frame.fb_image.instance())
-                .old_layout(vk::ImageLayout::UNDEFINED)
-                .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
-                .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-                .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
-                .src_access_mask(vk::AccessFlags::empty())
-                .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
-                .subresource_range(image_subresource);
-
-            self.core.device.cmd_pipeline_barrier(
-                command_buffer,
-                vk::PipelineStageFlags::TOP_OF_PIPE,
-                vk::PipelineStageFlags::ALL_GRAPHICS,
-                None,
-                &[],
-                &[],
-                &[barrier],
-            );
+            // Barrier (UNDEFINED -> COLOR_ATTACHMENT_OPTIMAL), unless adding to a sum
+            if fresh {
+                let barrier = vk::ImageMemoryBarrierBuilder::new()
+                    .image(image)
+                    .old_layout(vk::ImageLayout::UNDEFINED)
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .src_access_mask(vk::AccessFlags::empty())
+                    .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
+                    .subresource_range(color_range());
+
+                self.core.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TOP_OF_PIPE,
+                    vk::PipelineStageFlags::ALL_GRAPHICS,
+                    None,
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+            }
 
             // Set render pass
+            // Accumulation starts from nothing
+            let background_alpha = if self.cfg.alpha.is_some() || accumulates(&self.cfg) {
+                0.0
+            } else {
+                1.0
+            };
             let clear_values = [
                 vk::ClearValue {
                     color: vk::ClearColorValue {
-                        float32: [0.0, 0.0, 0.0, 1.0],
+                        float32: [0.0, 0.0, 0.0, background_alpha],
                     },
                 },
                 vk::ClearValue {
@@ -296,11 +512,11 @@ impl OffScreen {
             ];
 
             let begin_info = vk::RenderPassBeginInfoBuilder::new()
-                .framebuffer(frame.framebuffer)
-                .render_pass(self.render_pass)
+                .framebuffer(framebuffer)
+                .render_pass(render_pass)
                 .render_area(vk::Rect2D {
                     offset: vk::Offset2D { x: 0, y: 0 },
-                    extent: self.fb_extent,
+                    extent,
                 })
                 .clear_values(&clear_values);
 
@@ -310,17 +526,35 @@ impl OffScreen {
                 vk::SubpassContents::INLINE,
             );
 
+            // The sum pass keeps what's there, so a new sum is cleared by hand
+            if sum.is_some() && fresh {
+                let attachments = [vk::ClearAttachmentBuilder::new()
+                    .aspect_mask(vk::ImageAspectFlags::COLOR)
+                    .color_attachment(0)
+                    .clear_value(clear_values[0])];
+                let rects = [vk::ClearRectBuilder::new()
+                    .rect(vk::Rect2D {
+                        offset: vk::Offset2D { x: 0, y: 0 },
+                        extent,
+                    })
+                    .base_array_layer(0)
+                    .layer_count(1)];
+                self.core
+                    .device
+                    .cmd_clear_attachments(command_buffer, &attachments, &rects);
+            }
+
             let viewports = [vk::ViewportBuilder::new()
                 .x(0.0)
                 .y(0.0)
-                .width(self.fb_extent.width as f32)
-                .height(self.fb_extent.height as f32)
+                .width(extent.width as f32)
+                .height(extent.height as f32)
                 .min_depth(0.0)
                 .max_depth(1.0)];
 
             let scissors = [vk::Rect2DBuilder::new()
                 .offset(vk::Offset2D { x: 0, y: 0 })
-                .extent(self.fb_extent)];
+                .extent(extent)];
 
             self.core
                 .device
@@ -330,26 +564,129 @@ impl OffScreen {
                 .device
                 .cmd_set_scissor(command_buffer, 0, &scissors);
 
+            let subframes: Vec<Subframe> = self
+                .subframes
+                .iter()
+                .map(|&subframe| Subframe {
+                    sample,
+                    seed,
+                    ..subframe
+                })
+                .collect();
             self.engine
-                .write_commands(command_buffer, frame_idx, &scene)?;
+                .write_commands(command_buffer, frame_idx, &scene, &subframes)?;
 
             self.core.device.cmd_end_render_pass(command_buffer);
+        }
+
+        if sum.is_none() {
+            self.copy_out(command_buffer, frame_idx, image, extent, false);
+        }
+        self.end(frame_idx, command_buffer)
+    }
+
+    /// Create the running sum for a tile the first time it is rendered. Every tile of a frame
+    /// keeps one until its last resolve, so they must all fit within `--sum-memory`
+    fn create_sum(&mut self, tile_idx: usize) -> Result<()> {
+        if self.sums.contains_key(&tile_idx) {
+            return Ok(());
+        }
+        let depth_view = match &self.sum_depth {
+            Some((_, view)) => *view,
+            None => bail!("Running sums are only kept for progressive rendering"),
+        };
+        let extent = self.extent(self.tile_dims);
+        let sum_bytes = extent.width as u64 * extent.height as u64 * self.texel_size;
+        let budget = self.cfg.sum_memory * 1024 * 1024;
+        ensure!(
+            (self.sums.len() as u64 + 1) * sum_bytes <= budget,
+            "Progressive running sums of {} MiB per tile don't fit in --sum-memory {} MiB beyond \
+             {} tiles. Render a smaller region, with less supersampling or on more devices, or \
+             raise --sum-memory",
+            sum_bytes.div_ceil(1024 * 1024),
+            self.cfg.sum_memory,
+            self.sums.len()
+        );
+        let (image, image_view) = color_target(&self.core, self.color_format, extent)?;
+        let framebuffer =
+            create_framebuffer(&self.core, self.sum_pass, &[image_view, depth_view], extent)?;
+        self.sums.insert(
+            tile_idx,
+            Sum {
+                framebuffer,
+                image,
+                image_view,
+            },
+        );
+        Ok(())
+    }
 
-            // Barrier (UNDEFINED -> TRANSFER_DST_OPTIMAL)
+    /// Take a free frame for a tile of the given size, with `samples` samples summed in what it
+    /// copies out, and start recording its command buffer
+    fn begin(&mut self, dims: (u32, u32), samples: u32) -> Result<(usize, vk::CommandBuffer)> {
+        let frame_idx = self
+            .available_indices
+            .pop()
+            .expect("No more frames in flight left. Perhaps you didn't download a frame?");
+
+        let frame = &mut self.frames[frame_idx];
+        frame.dims = dims;
+        frame.samples = samples;
+        frame.release = None;
+        let command_buffer = self.command_buffers[frame_idx];
+        let first_query = 2 * frame_idx as u32;
+
+        unsafe {
+            self.core
+                .device
+                .reset_command_buffer(command_buffer, None)
+                .result()?;
+            let begin_info = vk::CommandBufferBeginInfoBuilder::new();
+            self.core
+                .device
+                .begin_command_buffer(command_buffer, &begin_info)
+                .result()?;
+
+            self.core
+                .device
+                .cmd_reset_query_pool(command_buffer, self.query_pool, first_query, 2);
+            self.core.device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlagBits::TOP_OF_PIPE,
+                self.query_pool,
+                first_query,
+            );
+        }
+
+        Ok((frame_idx, command_buffer))
+    }
+
+    /// Record copying a render target into a frame's download buffer. A running sum is put back
+    /// afterwards, to be added to again
+    fn copy_out(
+        &self,
+        command_buffer: vk::CommandBuffer,
+        frame_idx: usize,
+        image: vk::Image,
+        extent: vk::Extent2D,
+        sum: bool,
+    ) {
+        unsafe {
+            // Barrier (COLOR_ATTACHMENT_OPTIMAL -> TRANSFER_SRC_OPTIMAL)
             let barrier = vk::ImageMemoryBarrierBuilder::new()
-                .image(frame.fb_image.instance())
+                .image(image)
                 .old_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
                 .new_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
                 .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
                 .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
                 .src_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)
-                .dst_access_mask(vk::AccessFlags::empty())
-                .subresource_range(image_subresource);
+                .dst_access_mask(vk::AccessFlags::TRANSFER_READ)
+                .subresource_range(color_range());
 
             self.core.device.cmd_pipeline_barrier(
                 command_buffer,
                 vk::PipelineStageFlags::ALL_GRAPHICS,
-                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                vk::PipelineStageFlags::TRANSFER,
                 None,
                 &[],
                 &[],
@@ -369,8 +706,8 @@ impl OffScreen {
                 .buffer_image_height(0)
                 .image_extent(
                     vk::Extent3DBuilder::new()
-                        .width(self.fb_extent.width)
-                        .height(self.fb_extent.height)
+                        .width(extent.width)
+                        .height(extent.height)
                         .depth(1)
                         .build(),
                 )
@@ -379,23 +716,62 @@ impl OffScreen {
 
             self.core.device.cmd_copy_image_to_buffer(
                 command_buffer,
-                frame.fb_image.instance(),
+                image,
                 vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
-                frame.fb_download_buf.instance(),
+                self.frames[frame_idx].fb_download_buf.instance(),
                 &[buffer_image_copy],
             );
 
+            // Barrier (TRANSFER_SRC_OPTIMAL -> COLOR_ATTACHMENT_OPTIMAL)
+            if sum {
+                let barrier = vk::ImageMemoryBarrierBuilder::new()
+                    .image(image)
+                    .old_layout(vk::ImageLayout::TRANSFER_SRC_OPTIMAL)
+                    .new_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .src_access_mask(vk::AccessFlags::TRANSFER_READ)
+                    .dst_access_mask(
+                        vk::AccessFlags::COLOR_ATTACHMENT_READ
+                            | vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+                    )
+                    .subresource_range(color_range());
+
+                self.core.device.cmd_pipeline_barrier(
+                    command_buffer,
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                    None,
+                    &[],
+                    &[],
+                    &[barrier],
+                );
+            }
+        }
+    }
+
+    /// Finish recording a frame's command buffer and submit it
+    fn end(&mut self, frame_idx: usize, command_buffer: vk::CommandBuffer) -> Result<()> {
+        let fence = self.frames[frame_idx].fence;
+        unsafe {
+            self.core.device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlagBits::BOTTOM_OF_PIPE,
+                self.query_pool,
+                2 * frame_idx as u32 + 1,
+            );
+
             // Submit & wait
             self.core
                 .device
                 .end_command_buffer(command_buffer)
                 .result()?;
-            self.core.device.reset_fences(&[frame.fence]).result()?;
+            self.core.device.reset_fences(&[fence]).result()?;
             let command_buffers = [command_buffer];
             let submit_info = vk::SubmitInfoBuilder::new().command_buffers(&command_buffers);
             self.core
                 .device
-                .queue_submit(self.core.queue, &[submit_info], Some(frame.fence))
+                .queue_submit(self.core.queue, &[submit_info], Some(fence))
                 .result()?;
         }
 
@@ -404,56 +780,447 @@ impl OffScreen {
         Ok(())
     }
 
-    pub fn download_frame(&mut self) -> Result<Vec<u8>> {
-        let frame_idx = self
+    /// Downloaded float render target data as f32
+    fn floats(&self, data: &[u8]) -> Vec<f32> {
+        floats(data, self.color_format)
+    }
+
+    /// Render target extent for a tile of the given output size
+    fn extent(&self, (width, height): (u32, u32)) -> vk::Extent2D {
+        vk::Extent2D {
+            width: (width + 2 * self.margin) * self.cfg.ssaa,
+            height: (height + 2 * self.margin) * self.cfg.ssaa,
+        }
+    }
+
+    /// Read back what the oldest tile in flight copied out, along with the tile's size and the
+    /// number of samples summed into it
+    fn download_raw(&mut self) -> Result<(Vec<u8>, (u32, u32), u32)> {
+        let frame_idx = self.retire()?;
+        let frame = &self.frames[frame_idx];
+        let extent = self.extent(frame.dims);
+        let size = extent.width as u64 * extent.height as u64 * self.texel_size;
+        let mut image_data = vec![0xFFu8; size as usize];
+        frame.fb_download_buf.read_bytes(0, &mut image_data)?;
+
+        Ok((image_data, frame.dims, frame.samples))
+    }
+
+    /// Wait for the oldest tile in flight and free its frame, returning which frame it was. What
+    /// it copied out stays in the frame's download buffer until the frame is submitted again
+    fn retire(&mut self) -> Result<usize> {
+        let frame_idx = *self
             .frame_indices_in_flight
-            .pop_front()
+            .front()
             .expect("Attempted to download a frame we have not finished...");
 
-        let frame = &mut self.frames[frame_idx];
+        // A frame that faulted stays in flight, for dropping to wait on
+        let timeout = Duration::from_secs_f32(self.cfg.gpu_timeout);
+        if !self.wait_for(frame_idx, timeout)? {
+            return Err(GpuFault::Timeout(timeout).into());
+        }
+        self.frame_indices_in_flight.pop_front();
+
+        if let Some(period) = self.timestamp_period {
+            let mut stamps = [0u64; 2];
+            unsafe {
+                self.core.device.get_query_pool_results(
+                    self.query_pool,
+                    2 * frame_idx as u32,
+                    2,
+                    std::mem::size_of_val(&stamps),
+                    stamps.as_mut_ptr() as _,
+                    std::mem::size_of::<u64>() as _,
+                    Some(vk::QueryResultFlags::_64),
+                )
+            }
+            .result()?;
+            let ticks = stamps[1].saturating_sub(stamps[0]);
+            self.last_gpu_time = Some(Duration::from_nanos((ticks as f64 * period as f64) as u64));
+        }
 
+        if let Some(tile_idx) = self.frames[frame_idx].release.take() {
+            if let Some(sum) = self.sums.remove(&tile_idx) {
+                self.destroy_sum(sum);
+            }
+        }
+        self.available_indices.push(frame_idx);
+
+        Ok(frame_idx)
+    }
+
+    /// Free a running sum that nothing in flight uses any more
+    fn destroy_sum(&self, sum: Sum) {
         unsafe {
             self.core
                 .device
-                .wait_for_fences(&[frame.fence], true, Duration::from_secs(5).as_nanos() as _)
-                .result()?
+                .destroy_framebuffer(Some(sum.framebuffer), None);
+            self.core
+                .device
+                .destroy_image_view(Some(sum.image_view), None);
         }
+    }
 
-        let mut image_data = vec![0xFFu8; self.fb_size_bytes as usize];
-        frame.fb_download_buf.read_bytes(0, &mut image_data)?;
+    /// Wait up to `timeout` for a frame's commands to finish, returning whether they have
+    fn wait_for(&self, frame_idx: usize, timeout: Duration) -> Result<bool> {
+        let fence = self.frames[frame_idx].fence;
+        let wait = unsafe {
+            self.core
+                .device
+                .wait_for_fences(&[fence], true, timeout.as_nanos() as _)
+        };
+        match wait.raw {
+            vk::Result::SUCCESS => Ok(true),
+            vk::Result::TIMEOUT => Ok(false),
+            vk::Result::ERROR_DEVICE_LOST => Err(GpuFault::DeviceLost.into()),
+            error => Err(error.into()),
+        }
+    }
 
-        self.available_indices.push(frame_idx);
+    fn check_fits(&self, rect: Rect) -> Result<()> {
+        ensure!(
+            rect.width <= self.tile_dims.0 && rect.height <= self.tile_dims.1,
+            "Tile {:?} is larger than the render target",
+            rect
+        );
+        Ok(())
+    }
 
-        // Convert RGBA to RBG
-        let image_data = rgba_to_rgb(image_data);
+    /// Wait up to `timeout` for everything in flight to finish, returning whether it has. A lost
+    /// device has nothing left to wait for
+    fn settle(&self, timeout: Duration) -> bool {
+        let fences: Vec<vk::Fence> = self
+            .frame_indices_in_flight
+            .iter()
+            .map(|&frame_idx| self.frames[frame_idx].fence)
+            .collect();
+        if fences.is_empty() {
+            return true;
+        }
+        let wait = unsafe {
+            self.core
+                .device
+                .wait_for_fences(&fences, true, timeout.as_nanos() as _)
+        };
+        matches!(
+            wait.raw,
+            vk::Result::SUCCESS | vk::Result::ERROR_DEVICE_LOST
+        )
+    }
 
-        Ok(image_data)
+    /// Convert RGBA data at the tile depth to the output channel layout and resolution
+    fn finish_tile(&self, image_data: Vec<u8>, dims: (u32, u32)) -> Vec<u8> {
+        let bytes_per_channel = output::tile_depth(&self.cfg).bytes_per_channel();
+        let image_data = match self.cfg.alpha {
+            // Convert RGBA to RBG
+            None => rgba_to_rgb(image_data, bytes_per_channel),
+            Some(AlphaMode::Straight) => image_data,
+            Some(AlphaMode::Premultiplied) => premultiply(image_data, bytes_per_channel),
+        };
+
+        if self.cfg.ssaa == 1 {
+            return image_data;
+        }
+
+        filter::downsample(
+            &image_data,
+            (dims.0 as usize, dims.1 as usize),
+            self.cfg.ssaa as usize,
+            self.margin as usize,
+            output::channels(&self.cfg),
+            bytes_per_channel,
+            self.cfg.ssaa_filter,
+        )
     }
 }
 
-fn rgba_to_rgb(input: Vec<u8>) -> Vec<u8> {
-    assert!(input.len() % 4 == 0);
+impl TileEngine for OffScreen {
+    fn submit_rect(&mut self, time: f32, rect: Rect) -> Result<()> {
+        self.check_fits(rect)?;
+        self.submit(
+            time,
+            (rect.x as i32, rect.y as i32),
+            (rect.width, rect.height),
+            0,
+            self.cfg.seed,
+            None,
+        )
+    }
+
+    fn submit_sample(
+        &mut self,
+        time: f32,
+        rect: Rect,
+        sample: u32,
+        seed: u32,
+        tile_idx: usize,
+    ) -> Result<()> {
+        self.check_fits(rect)?;
+        self.submit(
+            time,
+            (rect.x as i32, rect.y as i32),
+            (rect.width, rect.height),
+            sample,
+            seed,
+            Some(tile_idx),
+        )
+    }
+
+    fn submit_resolve(
+        &mut self,
+        rect: Rect,
+        tile_idx: usize,
+        samples: u32,
+        last: bool,
+    ) -> Result<()> {
+        self.check_fits(rect)?;
+        let image = match self.sums.get(&tile_idx) {
+            Some(sum) => sum.image.instance(),
+            None => bail!("Tile {} has no samples to resolve", tile_idx),
+        };
+        let dims = (rect.width, rect.height);
+        let (frame_idx, command_buffer) = self.begin(dims, samples)?;
+        if last {
+            self.frames[frame_idx].release = Some(tile_idx);
+        }
+        self.copy_out(command_buffer, frame_idx, image, self.extent(dims), true);
+        self.end(frame_idx, command_buffer)
+    }
+
+    fn download_frame(&mut self) -> Result<Vec<u8>> {
+        let (image_data, dims, samples) = self.download_raw()?;
+        let image_data = if accumulates(&self.cfg) {
+            let bytes_per_channel = output::tile_depth(&self.cfg).bytes_per_channel();
+            let scale = 1. / samples.max(1) as f32;
+            float_to_unorm(&self.floats(&image_data), scale, bytes_per_channel)
+        } else {
+            image_data
+        };
+        Ok(self.finish_tile(image_data, dims))
+    }
+
+    fn finish_sample(&mut self) -> Result<()> {
+        self.retire().map(drop)
+    }
+
+    fn last_gpu_time(&self) -> Option<Duration> {
+        self.last_gpu_time
+    }
+
+    fn wait(&mut self, timeout: Duration) -> Result<bool> {
+        let frame_idx = *self
+            .frame_indices_in_flight
+            .front()
+            .expect("Waited for a frame with none in flight");
+        self.wait_for(frame_idx, timeout)
+    }
+}
+
+/// Reinterpret a downloaded float target of the given format
+fn floats(data: &[u8], format: vk::Format) -> Vec<f32> {
+    if format == vk::Format::R16G16B16A16_SFLOAT {
+        return data
+            .chunks_exact(2)
+            .map(|s| half_to_f32(u16::from_ne_bytes([s[0], s[1]])))
+            .collect();
+    }
+    data.chunks_exact(4)
+        .map(|s| f32::from_ne_bytes([s[0], s[1], s[2], s[3]]))
+        .collect()
+}
+
+/// Widen an IEEE 754 half precision float
+fn half_to_f32(half: u16) -> f32 {
+    let sign = if half & 0x8000 != 0 { -1. } else { 1. };
+    let exponent = (half >> 10) & 0x1f;
+    let mantissa = (half & 0x3ff) as f32;
+    sign * match exponent {
+        0 => mantissa * 2f32.powi(-24),
+        0x1f if mantissa == 0. => f32::INFINITY,
+        0x1f => f32::NAN,
+        _ => (1. + mantissa / 1024.) * 2f32.powi(exponent as i32 - 15),
+    }
+}
+
+/// Scale float samples and quantize them to the tile depth
+fn float_to_unorm(data: &[f32], scale: f32, bytes_per_channel: usize) -> Vec<u8> {
+    let samples = data.iter().map(|v| (v * scale).clamp(0., 1.));
+    match bytes_per_channel {
+        1 => samples.map(|v| (v * 255.).round() as u8).collect(),
+        _ => samples
+            .flat_map(|v| ((v * 65535.).round() as u16).to_ne_bytes())
+            .collect(),
+    }
+}
+
+fn rgba_to_rgb(input: Vec<u8>, bytes_per_channel: usize) -> Vec<u8> {
+    let pixel_size = 4 * bytes_per_channel;
+    assert!(input.len() % pixel_size == 0);
     let mut output = Vec::with_capacity((input.len() * 3) / 4);
-    for pixel in input.chunks_exact(4) {
-        output.push(pixel[0]);
-        output.push(pixel[1]);
-        output.push(pixel[2]);
+    for pixel in input.chunks_exact(pixel_size) {
+        output.extend_from_slice(&pixel[..3 * bytes_per_channel]);
     }
     output
 }
 
-pub fn create_render_pass(core: &Core) -> Result<vk::RenderPass> {
+/// Multiply the color channels of each RGBA pixel by its alpha
+fn premultiply(mut data: Vec<u8>, bytes_per_channel: usize) -> Vec<u8> {
+    match bytes_per_channel {
+        1 => {
+            for pixel in data.chunks_exact_mut(4) {
+                let alpha = pixel[3] as u32;
+                for c in &mut pixel[..3] {
+                    *c = ((*c as u32 * alpha + 127) / 255) as u8;
+                }
+            }
+        }
+        _ => {
+            for pixel in data.chunks_exact_mut(8) {
+                let alpha = u16::from_ne_bytes([pixel[6], pixel[7]]) as u64;
+                for c in pixel[..6].chunks_exact_mut(2) {
+                    let value = u16::from_ne_bytes([c[0], c[1]]) as u64;
+                    let value = ((value * alpha + 32767) / 65535) as u16;
+                    c.copy_from_slice(&value.to_ne_bytes());
+                }
+            }
+        }
+    }
+    data
+}
+
+/// Whole color aspect of a single-level image
+fn color_range() -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRangeBuilder::new()
+        .aspect_mask(vk::ImageAspectFlags::COLOR)
+        .base_mip_level(0)
+        .level_count(1)
+        .base_array_layer(0)
+        .layer_count(1)
+        .build()
+}
+
+/// Color render target which can be copied out for download
+fn color_target(
+    core: &SharedCore,
+    format: vk::Format,
+    extent: vk::Extent2D,
+) -> Result<(ManagedImage, vk::ImageView)> {
+    let create_info = vk::ImageCreateInfoBuilder::new()
+        .image_type(vk::ImageType::_2D)
+        .extent(
+            vk::Extent3DBuilder::new()
+                .width(extent.width)
+                .height(extent.height)
+                .depth(1)
+                .build(),
+        )
+        .mip_levels(1)
+        .array_layers(1)
+        .format(format)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::COLOR_ATTACHMENT | vk::ImageUsageFlags::TRANSFER_SRC)
+        .samples(vk::SampleCountFlagBits::_1)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+    let image = ManagedImage::new(core.clone(), create_info, UsageFlags::FAST_DEVICE_ACCESS)?;
+
+    let create_info = vk::ImageViewCreateInfoBuilder::new()
+        .image(image.instance())
+        .view_type(vk::ImageViewType::_2D)
+        .format(format)
+        .components(vk::ComponentMapping {
+            r: vk::ComponentSwizzle::IDENTITY,
+            g: vk::ComponentSwizzle::IDENTITY,
+            b: vk::ComponentSwizzle::IDENTITY,
+            a: vk::ComponentSwizzle::IDENTITY,
+        })
+        .subresource_range(color_range());
+
+    let view = unsafe { core.device.create_image_view(&create_info, None, None) }.result()?;
+    Ok((image, view))
+}
+
+fn depth_target(core: &SharedCore, extent: vk::Extent2D) -> Result<(ManagedImage, vk::ImageView)> {
+    let create_info = vk::ImageCreateInfoBuilder::new()
+        .image_type(vk::ImageType::_2D)
+        .extent(
+            vk::Extent3DBuilder::new()
+                .width(extent.width)
+                .height(extent.height)
+                .depth(1)
+                .build(),
+        )
+        .mip_levels(1)
+        .array_layers(1)
+        .format(DEPTH_FORMAT)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .usage(vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+        .samples(vk::SampleCountFlagBits::_1)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+
+    let image = ManagedImage::new(core.clone(), create_info, UsageFlags::FAST_DEVICE_ACCESS)?;
+
+    let create_info = vk::ImageViewCreateInfoBuilder::new()
+        .image(image.instance())
+        .view_type(vk::ImageViewType::_2D)
+        .format(DEPTH_FORMAT)
+        .subresource_range(
+            vk::ImageSubresourceRangeBuilder::new()
+                .aspect_mask(vk::ImageAspectFlags::DEPTH)
+                .base_mip_level(0)
+                .level_count(1)
+                .base_array_layer(0)
+                .layer_count(1)
+                .build(),
+        );
+    let view = unsafe { core.device.create_image_view(&create_info, None, None) }.result()?;
+    Ok((image, view))
+}
+
+fn create_framebuffer(
+    core: &SharedCore,
+    render_pass: vk::RenderPass,
+    attachments: &[vk::ImageView],
+    extent: vk::Extent2D,
+) -> Result<vk::Framebuffer> {
+    let create_info = vk::FramebufferCreateInfoBuilder::new()
+        .render_pass(render_pass)
+        .attachments(attachments)
+        .width(extent.width)
+        .height(extent.height)
+        .layers(1);
+
+    Ok(unsafe { core.device.create_framebuffer(&create_info, None, None) }.result()?)
+}
+
+/// Render pass clearing the color target, or with `keep` adding to what it already holds, as
+/// running sums are. Both are compatible with the same pipelines and framebuffers
+pub fn create_render_pass(
+    core: &Core,
+    color_format: vk::Format,
+    keep: bool,
+) -> Result<vk::RenderPass> {
     let device = &core.device;
 
     // Render pass
+    let (load_op, initial_layout) = if keep {
+        (
+            vk::AttachmentLoadOp::LOAD,
+            vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+        )
+    } else {
+        (vk::AttachmentLoadOp::CLEAR, vk::ImageLayout::UNDEFINED)
+    };
     let color_attachment = vk::AttachmentDescriptionBuilder::new()
-        .format(COLOR_FORMAT)
+        .format(color_format)
         .samples(vk::SampleCountFlagBits::_1)
-        .load_op(vk::AttachmentLoadOp::CLEAR)
+        .load_op(load_op)
         .store_op(vk::AttachmentStoreOp::STORE)
         .stencil_load_op(vk::AttachmentLoadOp::DONT_CARE)
         .stencil_store_op(vk::AttachmentStoreOp::DONT_CARE)
-        .initial_layout(vk::ImageLayout::UNDEFINED)
+        .initial_layout(initial_layout)
         .final_layout(vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL);
 
     let depth_attachment = vk::AttachmentDescriptionBuilder::new()
@@ -482,13 +1249,39 @@ pub fn create_render_pass(core: &Core) -> Result<vk::RenderPass> {
         .color_attachments(&color_attachment_refs)
         .depth_stencil_attachment(&depth_attachment_ref)];
 
-    let dependencies = [vk::SubpassDependencyBuilder::new()
-        .src_subpass(vk::SUBPASS_EXTERNAL)
-        .dst_subpass(0)
-        .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-        .src_access_mask(vk::AccessFlags::empty())
-        .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
-        .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)];
+    // Kept targets are added to after earlier submissions wrote to them, and share a depth
+    // buffer
+    let dependencies = if keep {
+        [vk::SubpassDependencyBuilder::new()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::TRANSFER,
+            )
+            .src_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )
+            .dst_stage_mask(
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT
+                    | vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS,
+            )
+            .dst_access_mask(
+                vk::AccessFlags::COLOR_ATTACHMENT_READ
+                    | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            )]
+    } else {
+        [vk::SubpassDependencyBuilder::new()
+            .src_subpass(vk::SUBPASS_EXTERNAL)
+            .dst_subpass(0)
+            .src_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .src_access_mask(vk::AccessFlags::empty())
+            .dst_stage_mask(vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT)
+            .dst_access_mask(vk::AccessFlags::COLOR_ATTACHMENT_WRITE)]
+    };
 
     let create_info = vk::RenderPassCreateInfoBuilder::new()
         .attachments(&attachments)
@@ -500,13 +1293,37 @@ pub fn create_render_pass(core: &Core) -> Result<vk::RenderPass> {
 
 impl Drop for OffScreen {
     fn drop(&mut self) {
+        // Tiles may still be in flight if this is dropped after a fault. If they don't finish
+        // within the timeout the device is hung, and waiting for it to go idle could take
+        // forever, so everything is leaked instead, the core included
+        if !self.settle(Duration::from_secs_f32(self.cfg.gpu_timeout)) {
+            mem::forget(self.core.clone());
+            mem::forget(mem::take(&mut self.frames));
+            mem::forget(mem::take(&mut self.sums));
+            mem::forget(self.sum_depth.take());
+            return;
+        }
+
         unsafe {
+            ManuallyDrop::drop(&mut self.engine);
             self.core
                 .device
                 .destroy_command_pool(Some(self.command_pool), None);
+            self.core
+                .device
+                .destroy_query_pool(Some(self.query_pool), None);
             self.core
                 .device
                 .destroy_render_pass(Some(self.render_pass), None);
+            self.core
+                .device
+                .destroy_render_pass(Some(self.sum_pass), None);
+            for (_, sum) in mem::take(&mut self.sums) {
+                self.destroy_sum(sum);
+            }
+            if let Some((_, view)) = &self.sum_depth {
+                self.core.device.destroy_image_view(Some(*view), None);
+            }
             for frame in self.frames.drain(..) {
                 self.core
                     .device
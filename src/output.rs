@@ -0,0 +1,589 @@
+use crate::atlas::AtlasSink;
+use crate::dither::Ditherer;
+use crate::dzi::{DziOptions, DziWriter};
+use crate::frame::Tile;
+use crate::settings::{AlphaMode, BitDepth, ImageFormat, Settings};
+use crate::template::{output_path, snapshot_path, Placeholders};
+use crate::tiff::TiffWriter;
+use crate::tiles::{blit, Rect};
+use anyhow::{bail, ensure, Context, Result};
+use std::borrow::Cow;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Destination for finished frames
+pub trait Sink {
+    /// Consume the assembled image for `frame_idx`
+    fn write_frame(&mut self, frame_idx: usize, data: &[u8]) -> Result<()>;
+
+    /// Called once after the last frame has been written
+    fn finish(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Create the sink selected by the settings, taking frames of the rendered region at the tile
+/// depth
+pub fn create_sink(cfg: &Settings) -> Result<Box<dyn Sink>> {
+    let sink: Box<dyn Sink> = match (cfg.crop, &cfg.crop_base) {
+        (Some(crop), Some(base)) => Box::new(PasteSink {
+            sink: create_output_sink(cfg)?,
+            base: read_base(cfg, base)?,
+            crop,
+            cfg: cfg.clone(),
+        }),
+        (None, Some(_)) => bail!("A crop base image requires --crop"),
+        _ => create_output_sink(&cfg.cropped())?,
+    };
+    Ok(match ditherer(&cfg.cropped()) {
+        Some(ditherer) => Box::new(DitherSink {
+            sink,
+            ditherer,
+            origin: origin(cfg),
+        }),
+        None => sink,
+    })
+}
+
+/// Create the sink selected by the settings, taking frames at the output depth
+fn create_output_sink(cfg: &Settings) -> Result<Box<dyn Sink>> {
+    ensure!(
+        cfg.keep_last.is_none() || (cfg.atlas.is_none() && cfg.video.is_none()),
+        "Only image sequences can keep just the latest frames"
+    );
+
+    if let Some(path) = &cfg.atlas {
+        let path = cfg.output.join(path);
+        create_parent_dir(&path)?;
+        return Ok(Box::new(AtlasSink::new(cfg, &path)?));
+    }
+
+    match &cfg.video {
+        Some(path) if path == Path::new("-") => crate::video::open(cfg, path),
+        Some(path) => {
+            let path = cfg.output.join(path);
+            create_parent_dir(&path)?;
+            crate::video::open(cfg, &path)
+        }
+        None => {
+            // Fail on a bad pattern before rendering anything
+            ImageFormat::from_path(&output_path(cfg, &Placeholders::default())?)?;
+            Ok(Box::new(ImageSequence {
+                cfg: cfg.clone(),
+                recent: RecentFiles::new(cfg)?,
+            }))
+        }
+    }
+}
+
+/// Whether tiles are written out a band at a time rather than assembled into whole frames, either
+/// on request or because the output is a Deep Zoom pyramid
+pub fn streams_bands(cfg: &Settings) -> bool {
+    let dzi = cfg.video.is_none()
+        && cfg.atlas.is_none()
+        && ImageFormat::from_path(Path::new(&cfg.pattern)).ok() == Some(ImageFormat::Dzi);
+    cfg.stream || dzi
+}
+
+/// Create the directory `path` will be written to, if missing
+pub fn create_parent_dir(path: &Path) -> Result<()> {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Writes each frame to its own image file
+pub struct ImageSequence {
+    cfg: Settings,
+    recent: RecentFiles,
+}
+
+impl Sink for ImageSequence {
+    fn write_frame(&mut self, frame_idx: usize, data: &[u8]) -> Result<()> {
+        let path = output_path(&self.cfg, &Placeholders::frame(&self.cfg, frame_idx))?;
+        create_parent_dir(&path)?;
+        write_image(&self.cfg, (self.cfg.width, self.cfg.height), data, &path)?;
+        self.recent.push(path)
+    }
+}
+
+/// Deletes all but the latest `--keep-last` frame images written, if set
+struct RecentFiles {
+    keep: Option<usize>,
+    paths: VecDeque<PathBuf>,
+}
+
+impl RecentFiles {
+    fn new(cfg: &Settings) -> Result<Self> {
+        ensure!(cfg.keep_last != Some(0), "Can't keep fewer than 1 frame");
+        Ok(Self {
+            keep: cfg.keep_last,
+            paths: VecDeque::new(),
+        })
+    }
+
+    /// Note a finished image, deleting the oldest ones beyond the limit
+    fn push(&mut self, path: PathBuf) -> Result<()> {
+        let keep = match self.keep {
+            Some(keep) => keep,
+            None => return Ok(()),
+        };
+
+        // Patterns without %f write the same file over and over
+        self.paths.retain(|p| p != &path);
+        self.paths.push_back(path);
+        while self.paths.len() > keep {
+            let old = self.paths.pop_front().unwrap();
+            std::fs::remove_file(&old)
+                .with_context(|| format!("Failed to delete old frame {}", old.display()))?;
+
+            // Deep Zoom tiles live in a directory next to the descriptor
+            if ImageFormat::from_path(&old).ok() == Some(ImageFormat::Dzi) {
+                let stem = old.file_stem().unwrap_or_default().to_string_lossy();
+                std::fs::remove_dir_all(old.with_file_name(format!("{}_files", stem)))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Pastes the cropped region of each frame into an existing full size image. Each frame covers
+/// the whole region, so the image is patched in place
+struct PasteSink {
+    sink: Box<dyn Sink>,
+    base: Vec<u8>,
+    crop: Rect,
+    cfg: Settings,
+}
+
+impl Sink for PasteSink {
+    fn write_frame(&mut self, frame_idx: usize, data: &[u8]) -> Result<()> {
+        blit(
+            data,
+            &mut self.base,
+            (self.crop.x as usize, self.crop.y as usize),
+            (self.cfg.width as usize, self.cfg.height as usize),
+            (self.crop.width as usize, self.crop.height as usize),
+            bytes_per_pixel(&self.cfg),
+        );
+        self.sink.write_frame(frame_idx, &self.base)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.sink.finish()
+    }
+}
+
+/// Read the PNG that cropped regions are pasted into. It must match the output's size, channels
+/// and bit depth
+fn read_base(cfg: &Settings, path: &Path) -> Result<Vec<u8>> {
+    let file = File::open(path)
+        .with_context(|| format!("Failed to open crop base image {}", path.display()))?;
+    let mut reader = png::Decoder::new(BufReader::new(file))
+        .read_info()
+        .with_context(|| format!("Failed to read crop base image {}", path.display()))?;
+    let mut data = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut data)?;
+    data.truncate(info.buffer_size());
+
+    ensure!(
+        (info.width, info.height) == (cfg.width, cfg.height),
+        "Crop base image {} is {}x{}, expected {}x{}",
+        path.display(),
+        info.width,
+        info.height,
+        cfg.width,
+        cfg.height
+    );
+    let (color_type, bit_depth) = png_layout(cfg);
+    ensure!(
+        info.color_type == color_type && info.bit_depth == bit_depth,
+        "Crop base image {} is {:?} at {:?} bits per channel, expected {:?} at {:?}",
+        path.display(),
+        info.color_type,
+        info.bit_depth,
+        color_type,
+        bit_depth
+    );
+
+    // PNG stores samples big-endian
+    if bit_depth == png::BitDepth::Sixteen {
+        data = data
+            .chunks_exact(2)
+            .flat_map(|s| u16::from_be_bytes([s[0], s[1]]).to_ne_bytes())
+            .collect();
+    }
+
+    Ok(data)
+}
+
+/// Reduces frames from the tile depth to the output depth before passing them on
+struct DitherSink {
+    sink: Box<dyn Sink>,
+    ditherer: Ditherer,
+    /// Top left of the rendered region in the full image
+    origin: (usize, usize),
+}
+
+impl Sink for DitherSink {
+    fn write_frame(&mut self, frame_idx: usize, data: &[u8]) -> Result<()> {
+        self.ditherer.start_frame();
+        let data = self.ditherer.rows(data, self.origin);
+        self.sink.write_frame(frame_idx, &data)
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.sink.finish()
+    }
+}
+
+/// Bit depth tiles are rendered and assembled at. Dithered 8-bit output is reduced from 16 bits
+/// once whole rows are available
+pub fn tile_depth(cfg: &Settings) -> BitDepth {
+    match cfg.dither {
+        Some(_) => BitDepth::Sixteen,
+        None => cfg.depth,
+    }
+}
+
+/// Bytes per pixel of tiles and assembled images, at the tile depth
+pub fn tile_bytes_per_pixel(cfg: &Settings) -> usize {
+    channels(cfg) * tile_depth(cfg).bytes_per_channel()
+}
+
+/// Ditherer reducing assembled rows to the output depth, if that differs from the tile depth
+pub fn ditherer(cfg: &Settings) -> Option<Ditherer> {
+    match cfg.dither {
+        Some(method) if cfg.depth == BitDepth::Eight => {
+            Some(Ditherer::new(method, cfg.width as usize, channels(cfg)))
+        }
+        _ => None,
+    }
+}
+
+/// Top left of the rendered region in the full image, which dithering is aligned to
+pub fn origin(cfg: &Settings) -> (usize, usize) {
+    let region = cfg.region();
+    (region.x as usize, region.y as usize)
+}
+
+/// Number of color channels in assembled images; RGBA if alpha is kept, RGB otherwise
+pub fn channels(cfg: &Settings) -> usize {
+    if cfg.alpha.is_some() {
+        4
+    } else {
+        3
+    }
+}
+
+/// Bytes per pixel of assembled images at the configured bit depth
+pub fn bytes_per_pixel(cfg: &Settings) -> usize {
+    channels(cfg) * cfg.depth.bytes_per_channel()
+}
+
+/// Write an image in the configured bit depth and channel layout, in the format selected by the
+/// extension of `path`
+pub fn write_image(
+    cfg: &Settings,
+    (width, height): (u32, u32),
+    data: &[u8],
+    path: &Path,
+) -> Result<()> {
+    let mut writer = RowWriter::create(cfg, (width, height), path)?;
+    writer.write_rows(data)?;
+    writer.finish()
+}
+
+/// Image encoder accepting rows in order, a few at a time. PNG and TIFF images are written to a
+/// partial file which only replaces the destination once complete
+pub struct RowWriter {
+    encoder: Encoder,
+    path: PathBuf,
+}
+
+enum Encoder {
+    Png {
+        writer: png::StreamWriter<'static, BufWriter<File>>,
+        sixteen_bit: bool,
+    },
+    Tiff(TiffWriter<BufWriter<File>>),
+    Dzi(DziWriter),
+}
+
+impl RowWriter {
+    /// Create `path` as an image in the configured bit depth and channel layout, in the format
+    /// selected by its extension
+    pub fn create(cfg: &Settings, (width, height): (u32, u32), path: &Path) -> Result<Self> {
+        let channels = channels(cfg);
+        let bytes_per_channel = cfg.depth.bytes_per_channel();
+        let create_file = || -> Result<BufWriter<File>> {
+            let file = File::create(partial_path(path))
+                .with_context(|| format!("Failed to create image {}", path.display()))?;
+            Ok(BufWriter::new(file))
+        };
+
+        let encoder = match ImageFormat::from_path(path)? {
+            ImageFormat::Png => {
+                // PNG has no notion of premultiplied alpha, so premultiplied data is written
+                // as-is for tools that expect it
+                let mut encoder = png::Encoder::new(create_file()?, width, height);
+                let (color_type, bit_depth) = png_layout(cfg);
+                encoder.set_color(color_type);
+                encoder.set_depth(bit_depth);
+                let writer = encoder.write_header()?.into_stream_writer()?;
+                Encoder::Png {
+                    writer,
+                    sixteen_bit: bit_depth == png::BitDepth::Sixteen,
+                }
+            }
+            ImageFormat::Tiff => {
+                let mut tiff = TiffWriter::new(
+                    create_file()?,
+                    width,
+                    height,
+                    channels as u16,
+                    bytes_per_channel,
+                    cfg.tiff_deflate,
+                )?;
+                tiff.set_associated_alpha(cfg.alpha == Some(AlphaMode::Premultiplied));
+                Encoder::Tiff(tiff)
+            }
+            ImageFormat::Dzi => {
+                let options = DziOptions {
+                    tile_size: cfg.dzi_tile_size,
+                    overlap: cfg.dzi_overlap,
+                    format: cfg.dzi_format,
+                    jpeg_quality: cfg.jpeg_quality,
+                };
+                let dzi =
+                    DziWriter::new(path, (width, height), channels, bytes_per_channel, options)?;
+                Encoder::Dzi(dzi)
+            }
+        };
+
+        Ok(Self {
+            encoder,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// Append whole rows. 16-bit samples are expected in native byte order
+    pub fn write_rows(&mut self, data: &[u8]) -> Result<()> {
+        match &mut self.encoder {
+            Encoder::Png {
+                writer,
+                sixteen_bit,
+            } => writer.write_all(&png_samples(data, *sixteen_bit))?,
+            Encoder::Tiff(tiff) => tiff.write_rows(data)?,
+            Encoder::Dzi(dzi) => dzi.write_rows(data)?,
+        }
+        Ok(())
+    }
+
+    /// Complete the file once all rows have been written
+    pub fn finish(self) -> Result<()> {
+        match self.encoder {
+            Encoder::Png { writer, .. } => writer.finish()?,
+            Encoder::Tiff(tiff) => {
+                tiff.finish()?;
+            }
+            // The descriptor is written last, and already in place
+            Encoder::Dzi(dzi) => return dzi.finish(),
+        }
+        commit_partial(&self.path)
+    }
+}
+
+/// Where a file is written before being renamed to `path`, so that an interrupted render never
+/// leaves a truncated file at `path`
+pub fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.partial", name))
+}
+
+/// Move the completed partial file for `path` into place
+pub fn commit_partial(path: &Path) -> Result<()> {
+    std::fs::rename(partial_path(path), path).with_context(|| {
+        format!(
+            "Failed to move finished file into place at {}",
+            path.display()
+        )
+    })
+}
+
+/// PNG color type and bit depth of images in the configured layout
+fn png_layout(cfg: &Settings) -> (png::ColorType, png::BitDepth) {
+    let color_type = match channels(cfg) {
+        4 => png::ColorType::Rgba,
+        _ => png::ColorType::Rgb,
+    };
+    let bit_depth = match cfg.depth {
+        BitDepth::Eight => png::BitDepth::Eight,
+        BitDepth::Sixteen => png::BitDepth::Sixteen,
+    };
+    (color_type, bit_depth)
+}
+
+/// Samples in native byte order as PNG stores them, which for 16-bit samples is big-endian
+pub fn png_samples(data: &[u8], sixteen_bit: bool) -> Cow<'_, [u8]> {
+    if !sixteen_bit {
+        return Cow::Borrowed(data);
+    }
+    data.chunks_exact(2)
+        .flat_map(|s| u16::from_ne_bytes([s[0], s[1]]).to_be_bytes())
+        .collect()
+}
+
+/// Check that `path` holds a complete image of the given size in the configured layout, decoding
+/// it a row or strip at a time
+pub fn verify_image(cfg: &Settings, (width, height): (u32, u32), path: &Path) -> Result<()> {
+    match ImageFormat::from_path(path)? {
+        ImageFormat::Png => {
+            let file = File::open(path)?;
+            let mut reader = png::Decoder::new(BufReader::new(file)).read_info()?;
+            let info = reader.info();
+            ensure!(
+                (info.width, info.height) == (width, height)
+                    && (info.color_type, info.bit_depth) == png_layout(cfg),
+                "{} doesn't match the output settings",
+                path.display()
+            );
+            let mut rows = 0;
+            while reader.next_row()?.is_some() {
+                rows += 1;
+            }
+            ensure!(rows == height, "{} is incomplete", path.display());
+        }
+        ImageFormat::Tiff => {
+            let file = BufReader::new(File::open(path)?);
+            crate::tiff::verify(file, (width, height), channels(cfg) as u16)?;
+        }
+        // The descriptor is only written once the whole pyramid is
+        ImageFormat::Dzi => ensure!(path.is_file(), "{} is missing", path.display()),
+    }
+    Ok(())
+}
+
+/// Writes each frame to its own image file one band of tiles at a time, so that only a single row
+/// of tiles is ever held in memory. Tiles must arrive in the row-major order of `tiles::tiles`
+pub struct BandStream {
+    cfg: Settings,
+    tile_height: usize,
+    band: Vec<u8>,
+    band_y: usize,
+    /// Frame index and sample count of the image being written
+    frame: Option<((usize, u32), RowWriter)>,
+    ditherer: Option<Ditherer>,
+    /// Deletes old frames. Snapshots are all kept
+    recent: Option<RecentFiles>,
+}
+
+impl BandStream {
+    /// Stream frames of size `cfg.width` by `cfg.height`, which is the cropped size when cropping
+    pub fn new(cfg: &Settings, tile_height: usize) -> Result<Self> {
+        ensure!(
+            cfg.video.is_none() && cfg.atlas.is_none() && cfg.crop_base.is_none(),
+            "Streaming output only supports image sequences"
+        );
+        // Fail on a bad pattern before rendering anything
+        ImageFormat::from_path(&output_path(cfg, &Placeholders::default())?)?;
+        Ok(Self::with_recent(
+            cfg,
+            tile_height,
+            Some(RecentFiles::new(cfg)?),
+        ))
+    }
+
+    /// Stream progressive snapshots instead, to an image per frame and sample count as named by
+    /// the snapshot pattern
+    pub fn snapshots(cfg: &Settings, tile_height: usize) -> Result<Self> {
+        ImageFormat::from_path(&snapshot_path(cfg, &Placeholders::default())?)?;
+        Ok(Self::with_recent(cfg, tile_height, None))
+    }
+
+    fn with_recent(cfg: &Settings, tile_height: usize, recent: Option<RecentFiles>) -> Self {
+        Self {
+            cfg: cfg.clone(),
+            tile_height,
+            band: vec![0; cfg.width as usize * tile_height * tile_bytes_per_pixel(cfg)],
+            band_y: 0,
+            frame: None,
+            ditherer: ditherer(cfg),
+            recent,
+        }
+    }
+
+    /// Place a tile, writing out the previous band or image as needed
+    pub fn write_tile(&mut self, tile: &Tile) -> Result<()> {
+        let (x, y) = tile.pos;
+        let key = (tile.frame_idx, tile.samples);
+        if self.frame.as_ref().map(|(key, _)| *key) != Some(key) {
+            self.finish()?;
+            let values = Placeholders::frame(&self.cfg, tile.frame_idx);
+            let path = match self.recent {
+                Some(_) => output_path(&self.cfg, &values)?,
+                None => snapshot_path(
+                    &self.cfg,
+                    &Placeholders {
+                        samples: tile.samples,
+                        ..values
+                    },
+                )?,
+            };
+            create_parent_dir(&path)?;
+            let writer = RowWriter::create(&self.cfg, (self.cfg.width, self.cfg.height), &path)?;
+            self.frame = Some((key, writer));
+            self.band_y = y;
+            if let Some(ditherer) = &mut self.ditherer {
+                ditherer.start_frame();
+            }
+        } else if y != self.band_y {
+            self.flush_band()?;
+            self.band_y = y;
+        }
+
+        blit(
+            &tile.pixels,
+            &mut self.band,
+            (x, 0),
+            (self.cfg.width as usize, self.tile_height),
+            tile.dims,
+            tile_bytes_per_pixel(&self.cfg),
+        );
+
+        Ok(())
+    }
+
+    /// Write the rows of the current band, clipped to the image
+    fn flush_band(&mut self) -> Result<()> {
+        if let Some((_, writer)) = &mut self.frame {
+            let rows = self.tile_height.min(self.cfg.height as usize - self.band_y);
+            let len = rows * self.cfg.width as usize * tile_bytes_per_pixel(&self.cfg);
+            match &mut self.ditherer {
+                Some(ditherer) => {
+                    let (x, y) = origin(&self.cfg);
+                    writer.write_rows(&ditherer.rows(&self.band[..len], (x, y + self.band_y)))?
+                }
+                None => writer.write_rows(&self.band[..len])?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Write out the last band and complete the current image, if any
+    pub fn finish(&mut self) -> Result<()> {
+        self.flush_band()?;
+        if let Some((_, writer)) = self.frame.take() {
+            let path = writer.path.clone();
+            writer.finish()?;
+            if let Some(recent) = &mut self.recent {
+                recent.push(path)?;
+            }
+        }
+        Ok(())
+    }
+}
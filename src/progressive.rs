@@ -0,0 +1,66 @@
+use std::time::{Duration, Instant};
+
+/// Seed passed to the shader as `u_seed` for one sample of a frame, decorrelated from its
+/// neighbours
+pub fn sample_seed(seed: u32, frame_idx: usize, sample: u32) -> u32 {
+    // SplitMix64 finalizer
+    let mix = |mut x: u64| {
+        x = x.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        x = (x ^ (x >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        x ^ (x >> 31)
+    };
+    let x = mix(mix(mix(seed as u64) ^ frame_idx as u64) ^ sample as u64);
+    (x >> 32) as u32
+}
+
+/// When to stop refining a frame: after a number of samples, or once a time budget has run out,
+/// whichever comes first. At least one sample is always taken
+pub struct Budget {
+    samples: u32,
+    time: Option<Duration>,
+    start: Instant,
+}
+
+impl Budget {
+    pub fn new(samples: u32, time: Option<Duration>) -> Self {
+        Self {
+            samples,
+            time,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn exhausted(&self, samples: u32) -> bool {
+        let out_of_time = matches!(self.time, Some(t) if self.start.elapsed() >= t);
+        samples >= self.samples.max(1) || (samples > 0 && out_of_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_seeds_differ() {
+        let mut seeds: Vec<u32> = (0..4)
+            .flat_map(|frame| (0..64).map(move |sample| sample_seed(7, frame, sample)))
+            .collect();
+        seeds.sort_unstable();
+        seeds.dedup();
+        assert_eq!(seeds.len(), 4 * 64);
+        assert_ne!(sample_seed(0, 0, 0), sample_seed(1, 0, 0));
+    }
+
+    #[test]
+    fn test_budget() {
+        let budget = Budget::new(3, None);
+        assert!(!budget.exhausted(2));
+        assert!(budget.exhausted(3));
+
+        // An expired budget still allows the first sample
+        let budget = Budget::new(100, Some(Duration::from_secs(0)));
+        assert!(!budget.exhausted(0));
+        assert!(budget.exhausted(1));
+    }
+}
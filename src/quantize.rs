@@ -0,0 +1,318 @@
+use anyhow::{bail, Error};
+use std::str::FromStr;
+
+/// Upper bound on the number of pixels considered when building a palette
+const MAX_SAMPLES: usize = 1 << 20;
+
+/// Bits per channel of the nearest-color lookup table
+const LUT_BITS: u32 = 6;
+
+/// Palette generation algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantizer {
+    MedianCut,
+    Octree,
+}
+
+impl FromStr for Quantizer {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "median-cut" => Ok(Quantizer::MedianCut),
+            "octree" => Ok(Quantizer::Octree),
+            _ => bail!("Unknown quantizer \"{}\", expected median-cut or octree", s),
+        }
+    }
+}
+
+/// Build a palette of at most `max_colors` colors representing `pixels`
+pub fn build_palette(pixels: &[[u8; 3]], max_colors: usize, method: Quantizer) -> Vec<[u8; 3]> {
+    let step = (pixels.len() / MAX_SAMPLES).max(1);
+    let samples: Vec<[u8; 3]> = pixels.iter().step_by(step).copied().collect();
+    if samples.is_empty() {
+        return vec![[0; 3]];
+    }
+
+    match method {
+        Quantizer::MedianCut => median_cut(samples, max_colors),
+        Quantizer::Octree => octree(&samples, max_colors),
+    }
+}
+
+fn median_cut(samples: Vec<[u8; 3]>, max_colors: usize) -> Vec<[u8; 3]> {
+    // The channel with the widest range, and that range
+    fn widest_channel(colors: &[[u8; 3]]) -> (usize, u8) {
+        (0..3)
+            .map(|c| {
+                let min = colors.iter().map(|p| p[c]).min().unwrap_or(0);
+                let max = colors.iter().map(|p| p[c]).max().unwrap_or(0);
+                (c, max - min)
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap()
+    }
+
+    let mut boxes = vec![samples];
+    while boxes.len() < max_colors {
+        // Split the box with the widest channel range at its median
+        let (idx, channel, range) = boxes
+            .iter()
+            .enumerate()
+            .map(|(idx, b)| {
+                let (channel, range) = widest_channel(b);
+                (idx, channel, range)
+            })
+            .max_by_key(|&(_, _, range)| range)
+            .unwrap();
+
+        if range == 0 {
+            break;
+        }
+
+        let mut colors = boxes.swap_remove(idx);
+        colors.sort_unstable_by_key(|p| p[channel]);
+        let upper = colors.split_off(colors.len() / 2);
+        boxes.push(colors);
+        boxes.push(upper);
+    }
+
+    boxes.iter().map(|b| average(b.iter().copied())).collect()
+}
+
+fn average(colors: impl Iterator<Item = [u8; 3]>) -> [u8; 3] {
+    let mut sum = [0u64; 3];
+    let mut count = 0u64;
+    for color in colors {
+        for (total, value) in sum.iter_mut().zip(color) {
+            *total += value as u64;
+        }
+        count += 1;
+    }
+    let count = count.max(1);
+    [
+        ((sum[0] + count / 2) / count) as u8,
+        ((sum[1] + count / 2) / count) as u8,
+        ((sum[2] + count / 2) / count) as u8,
+    ]
+}
+
+#[derive(Default)]
+struct OctreeNode {
+    children: [Option<usize>; 8],
+    sum: [u64; 3],
+    count: u64,
+    is_leaf: bool,
+}
+
+fn octree(samples: &[[u8; 3]], max_colors: usize) -> Vec<[u8; 3]> {
+    const DEPTH: usize = 8;
+
+    let mut nodes = vec![OctreeNode::default()];
+    // Interior nodes at each level, candidates for reduction
+    let mut levels: Vec<Vec<usize>> = vec![vec![]; DEPTH];
+    let mut leaves = 0;
+
+    for color in samples {
+        let mut node = 0;
+        for level in 0..DEPTH {
+            let shift = 7 - level;
+            let child = (((color[0] >> shift) & 1) << 2
+                | ((color[1] >> shift) & 1) << 1
+                | ((color[2] >> shift) & 1)) as usize;
+
+            node = match nodes[node].children[child] {
+                Some(next) => next,
+                None => {
+                    let next = nodes.len();
+                    nodes.push(OctreeNode::default());
+                    nodes[node].children[child] = Some(next);
+                    if level + 1 == DEPTH {
+                        nodes[next].is_leaf = true;
+                        leaves += 1;
+                    } else {
+                        levels[level + 1].push(next);
+                    }
+                    next
+                }
+            };
+        }
+
+        let leaf = &mut nodes[node];
+        for (sum, &value) in leaf.sum.iter_mut().zip(color) {
+            *sum += value as u64;
+        }
+        leaf.count += 1;
+    }
+
+    // Fold the deepest interior nodes into leaves until few enough colors remain
+    let mut level = DEPTH - 1;
+    while leaves > max_colors && level > 0 {
+        let node = match levels[level].pop() {
+            Some(node) => node,
+            None => {
+                level -= 1;
+                continue;
+            }
+        };
+
+        let mut merged = 0;
+        for child in nodes[node]
+            .children
+            .iter_mut()
+            .filter_map(Option::take)
+            .collect::<Vec<_>>()
+        {
+            let OctreeNode { sum, count, .. } = std::mem::take(&mut nodes[child]);
+            for (total, value) in nodes[node].sum.iter_mut().zip(sum) {
+                *total += value;
+            }
+            nodes[node].count += count;
+            merged += 1;
+        }
+        nodes[node].is_leaf = true;
+        leaves = leaves + 1 - merged;
+    }
+
+    nodes
+        .iter()
+        .filter(|n| n.is_leaf && n.count > 0)
+        .map(|n| {
+            let count = n.count;
+            [
+                ((n.sum[0] + count / 2) / count) as u8,
+                ((n.sum[1] + count / 2) / count) as u8,
+                ((n.sum[2] + count / 2) / count) as u8,
+            ]
+        })
+        .collect()
+}
+
+/// Map each pixel of a `width` pixel wide image to its palette index, optionally with
+/// Floyd-Steinberg error diffusion
+pub fn remap(pixels: &[[u8; 3]], width: usize, palette: &[[u8; 3]], dither: bool) -> Vec<u8> {
+    let mut lut = NearestLut::new(palette);
+
+    if !dither {
+        return pixels.iter().map(|&p| lut.nearest(p)).collect();
+    }
+
+    let mut indices = Vec::with_capacity(pixels.len());
+    // Error carried into the current and next row
+    let mut errors = vec![[0f32; 3]; (width + 2) * 2];
+    let (mut current, mut next) = errors.split_at_mut(width + 2);
+
+    for row in pixels.chunks_exact(width) {
+        for (x, pixel) in row.iter().enumerate() {
+            let mut wanted = [0u8; 3];
+            for c in 0..3 {
+                wanted[c] = (pixel[c] as f32 + current[x + 1][c])
+                    .round()
+                    .clamp(0., 255.) as u8;
+            }
+
+            let idx = lut.nearest(wanted);
+            indices.push(idx);
+
+            let got = palette[idx as usize];
+            for c in 0..3 {
+                let err = wanted[c] as f32 - got[c] as f32;
+                current[x + 2][c] += err * 7. / 16.;
+                next[x][c] += err * 3. / 16.;
+                next[x + 1][c] += err * 5. / 16.;
+                next[x + 2][c] += err * 1. / 16.;
+            }
+        }
+
+        std::mem::swap(&mut current, &mut next);
+        next.iter_mut().for_each(|e| *e = [0.; 3]);
+    }
+
+    indices
+}
+
+/// Lazily filled table of nearest palette entries over a reduced color cube
+struct NearestLut<'a> {
+    palette: &'a [[u8; 3]],
+    table: Vec<Option<u8>>,
+}
+
+impl<'a> NearestLut<'a> {
+    fn new(palette: &'a [[u8; 3]]) -> Self {
+        debug_assert!(palette.len() <= 256);
+        Self {
+            palette,
+            table: vec![None; 1 << (LUT_BITS * 3)],
+        }
+    }
+
+    fn nearest(&mut self, color: [u8; 3]) -> u8 {
+        let shift = 8 - LUT_BITS;
+        let key = ((color[0] as usize >> shift) << (2 * LUT_BITS))
+            | ((color[1] as usize >> shift) << LUT_BITS)
+            | (color[2] as usize >> shift);
+
+        let palette = self.palette;
+        *self.table[key].get_or_insert_with(|| {
+            palette
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, p)| {
+                    (0..3)
+                        .map(|c| (p[c] as i32 - color[c] as i32).pow(2))
+                        .sum::<i32>()
+                })
+                .map(|(idx, _)| idx as u8)
+                .unwrap_or(0)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_pixels() -> Vec<[u8; 3]> {
+        let mut pixels = vec![];
+        for &color in &[[255, 0, 0], [0, 255, 0], [0, 0, 255], [255, 255, 255]] {
+            pixels.extend(vec![color; 100]);
+        }
+        pixels
+    }
+
+    #[test]
+    fn test_exact_palettes() {
+        for &method in &[Quantizer::MedianCut, Quantizer::Octree] {
+            let pixels = test_pixels();
+            let mut palette = build_palette(&pixels, 16, method);
+            palette.sort_unstable();
+            assert_eq!(
+                palette,
+                vec![[0, 0, 255], [0, 255, 0], [255, 0, 0], [255, 255, 255]]
+            );
+
+            let indices = remap(&pixels, 20, &palette, false);
+            for (idx, pixel) in indices.iter().zip(&pixels) {
+                assert_eq!(&palette[*idx as usize], pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn test_palette_size_limit() {
+        let pixels: Vec<[u8; 3]> = (0..=255u8).map(|v| [v, v / 2, 255 - v]).collect();
+        for &method in &[Quantizer::MedianCut, Quantizer::Octree] {
+            let palette = build_palette(&pixels, 8, method);
+            assert!(!palette.is_empty() && palette.len() <= 8, "{:?}", method);
+        }
+    }
+
+    #[test]
+    fn test_dither_preserves_mean() {
+        // A flat mid gray against a black and white palette should dither to ~50% coverage
+        let pixels = vec![[128, 128, 128]; 64 * 64];
+        let palette = [[0, 0, 0], [255, 255, 255]];
+        let indices = remap(&pixels, 64, &palette, true);
+        let white = indices.iter().filter(|&&i| i == 1).count();
+        assert!((white as f32 / pixels.len() as f32 - 0.5).abs() < 0.02);
+    }
+}
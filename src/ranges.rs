@@ -0,0 +1,78 @@
+use anyhow::{ensure, Context, Error};
+use std::str::FromStr;
+
+/// Comma separated frame indices and inclusive ranges, each range with an optional step, e.g.
+/// "0-99,150,200-400:5"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameRanges(Vec<FrameRange>);
+
+/// Every `step`th frame from `start` up to and including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameRange {
+    pub start: usize,
+    pub end: usize,
+    pub step: usize,
+}
+
+impl FrameRanges {
+    /// Frame indices in the order given, each only the first time it appears
+    pub fn indices(&self) -> Vec<usize> {
+        let mut seen = std::collections::HashSet::new();
+        self.0
+            .iter()
+            .flat_map(|range| (range.start..=range.end).step_by(range.step))
+            .filter(|&frame_idx| seen.insert(frame_idx))
+            .collect()
+    }
+}
+
+impl FromStr for FrameRanges {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(|part| {
+                part.trim()
+                    .parse()
+                    .with_context(|| format!("Invalid frame range \"{}\"", part))
+            })
+            .collect::<Result<_, _>>()
+            .map(FrameRanges)
+    }
+}
+
+impl FromStr for FrameRange {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (range, step) = match s.split_once(':') {
+            Some((range, step)) => (range, step.parse()?),
+            None => (s, 1),
+        };
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse()?, end.parse()?),
+            None => {
+                let frame = range.parse()?;
+                (frame, frame)
+            }
+        };
+        ensure!(start <= end, "Range ends before it starts");
+        ensure!(step > 0, "Step must be at least 1");
+        Ok(Self { start, end, step })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_ranges() {
+        let ranges: FrameRanges = "0-3, 7,10-20:5,2".parse().unwrap();
+        assert_eq!(ranges.indices(), vec![0, 1, 2, 3, 7, 10, 15, 20]);
+
+        assert!("100-95".parse::<FrameRanges>().is_err());
+        assert!("1-2:0".parse::<FrameRanges>().is_err());
+        assert!("1-".parse::<FrameRanges>().is_err());
+        assert!("a".parse::<FrameRanges>().is_err());
+        assert!("".parse::<FrameRanges>().is_err());
+    }
+}
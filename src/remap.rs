@@ -0,0 +1,259 @@
+use anyhow::{bail, ensure, Context, Error, Result};
+use std::str::FromStr;
+
+/// Non-linear mapping from output pixels to the coordinates the shader sees. Spherical mappings
+/// treat the shader's canvas as a 360x180 degree panorama, and also expose the view direction of
+/// each pixel (y up, z forward) as `bos_render_view_dir` for shaders that trace rays
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Remap {
+    /// Circular dome master with the zenith at the center, covering `fov` degrees edge to edge
+    Fisheye { fov: f32 },
+    /// Longitude and latitude across the image, covering `hfov` by `vfov` degrees
+    Equirect { hfov: f32, vfov: f32 },
+    /// Flat canvas seen from inside a cylinder, covering `hfov` degrees horizontally
+    Cylindrical { hfov: f32 },
+    /// Angle across the image and radius down it, out to `radius` times the half diagonal
+    Polar { radius: f32 },
+    /// Canvas folded into `segments` mirrored wedges around the center
+    Kaleidoscope { segments: u32 },
+    /// Left half of the canvas reflected onto the right
+    Mirror,
+}
+
+impl FromStr for Remap {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, params) = match s.split_once(':') {
+            Some((name, params)) => (name, params.split(',').collect()),
+            None => (s, vec![]),
+        };
+
+        // Parameter `idx`, or `default` if it was not given
+        let param = |idx: usize, default: f32| -> Result<f32> {
+            match params.get(idx) {
+                Some(p) => p
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid parameter \"{}\" for {} remap", p, name)),
+                None => Ok(default),
+            }
+        };
+
+        let max_params = match name {
+            "fisheye" | "cylindrical" | "polar" | "kaleidoscope" => 1,
+            "equirect" => 2,
+            _ => 0,
+        };
+        ensure!(
+            params.len() <= max_params,
+            "Too many parameters for {} remap",
+            name
+        );
+
+        let remap = match name {
+            "fisheye" => Remap::Fisheye {
+                fov: param(0, 180.)?,
+            },
+            "equirect" => Remap::Equirect {
+                hfov: param(0, 360.)?,
+                vfov: param(1, 180.)?,
+            },
+            "cylindrical" => {
+                let hfov = param(0, 90.)?;
+                ensure!(
+                    hfov > 0. && hfov < 180.,
+                    "Cylindrical field of view must be between 0 and 180 degrees"
+                );
+                Remap::Cylindrical { hfov }
+            }
+            "polar" => Remap::Polar {
+                radius: param(0, 1.)?,
+            },
+            "kaleidoscope" => {
+                let segments = param(0, 6.)?;
+                ensure!(
+                    segments >= 1. && segments.fract() == 0.,
+                    "Kaleidoscope segments must be a positive whole number"
+                );
+                Remap::Kaleidoscope {
+                    segments: segments as u32,
+                }
+            }
+            "mirror" => Remap::Mirror,
+            _ => bail!(
+                "Unknown remap \"{}\", expected fisheye, equirect, cylindrical, polar, \
+                 kaleidoscope or mirror",
+                name
+            ),
+        };
+
+        Ok(remap)
+    }
+}
+
+impl Remap {
+    /// GLSL defining `bos_render_remap(vec2)`, which maps an output pixel to a canvas pixel, and
+    /// `bos_render_in_domain(vec2)`, which is false where the mapping is undefined. Spherical
+    /// mappings also define `bos_render_direction(vec2)`
+    pub fn glsl(&self) -> String {
+        let body = match *self {
+            Remap::Fisheye { fov } => format!(
+                "
+vec2 bos_render_dome(vec2 p) {{
+    vec2 d = (p - bos_render_center) / (min(resolution_x, resolution_y) / 2.);
+    return vec2(length(d) * radians({fov:?}) / 2., atan(d.y, d.x));
+}}
+bool bos_render_in_domain(vec2 p) {{
+    return length(p - bos_render_center) <= min(resolution_x, resolution_y) / 2.;
+}}
+vec3 bos_render_direction(vec2 p) {{
+    vec2 tp = bos_render_dome(p);
+    return vec3(sin(tp.x) * cos(tp.y), cos(tp.x), sin(tp.x) * sin(tp.y));
+}}
+vec2 bos_render_remap(vec2 p) {{
+    vec2 tp = bos_render_dome(p);
+    return vec2(tp.y / (2. * BOS_RENDER_PI) + 0.5, 1. - tp.x / BOS_RENDER_PI) * bos_render_res;
+}}
+",
+                fov = fov
+            ),
+            Remap::Equirect { hfov, vfov } => format!(
+                "
+vec2 bos_render_lon_lat(vec2 p) {{
+    return (p / bos_render_res - 0.5) * radians(vec2({hfov:?}, {vfov:?}));
+}}
+bool bos_render_in_domain(vec2 p) {{
+    return true;
+}}
+vec3 bos_render_direction(vec2 p) {{
+    vec2 ll = bos_render_lon_lat(p);
+    return vec3(cos(ll.y) * sin(ll.x), sin(ll.y), cos(ll.y) * cos(ll.x));
+}}
+vec2 bos_render_remap(vec2 p) {{
+    vec2 ll = bos_render_lon_lat(p);
+    return (ll / vec2(2. * BOS_RENDER_PI, BOS_RENDER_PI) + 0.5) * bos_render_res;
+}}
+",
+                hfov = hfov,
+                vfov = vfov
+            ),
+            Remap::Cylindrical { hfov } => format!(
+                "
+float bos_render_focal = resolution_x / 2. / tan(radians({hfov:?}) / 2.);
+float bos_render_azimuth(vec2 p) {{
+    return (p.x / resolution_x - 0.5) * radians({hfov:?});
+}}
+vec2 bos_render_remap(vec2 p) {{
+    float az = bos_render_azimuth(p);
+    return bos_render_center
+        + vec2(bos_render_focal * tan(az), (p.y - bos_render_center.y) / cos(az));
+}}
+bool bos_render_in_domain(vec2 p) {{
+    vec2 q = bos_render_remap(p);
+    return q.y >= 0. && q.y <= resolution_y;
+}}
+vec3 bos_render_direction(vec2 p) {{
+    float az = bos_render_azimuth(p);
+    return normalize(vec3(sin(az), (p.y - bos_render_center.y) / bos_render_focal, cos(az)));
+}}
+",
+                hfov = hfov
+            ),
+            Remap::Polar { radius } => format!(
+                "
+bool bos_render_in_domain(vec2 p) {{
+    return true;
+}}
+vec2 bos_render_remap(vec2 p) {{
+    vec2 uv = p / bos_render_res;
+    float r = uv.y * length(bos_render_center) * {radius:?};
+    float a = uv.x * 2. * BOS_RENDER_PI;
+    return bos_render_center + r * vec2(cos(a), sin(a));
+}}
+",
+                radius = radius
+            ),
+            Remap::Kaleidoscope { segments } => format!(
+                "
+bool bos_render_in_domain(vec2 p) {{
+    return true;
+}}
+vec2 bos_render_remap(vec2 p) {{
+    vec2 d = p - bos_render_center;
+    float wedge = 2. * BOS_RENDER_PI / {segments}.;
+    float a = mod(atan(d.y, d.x), wedge);
+    a = min(a, wedge - a);
+    return bos_render_center + length(d) * vec2(cos(a), sin(a));
+}}
+",
+                segments = segments
+            ),
+            Remap::Mirror => "
+bool bos_render_in_domain(vec2 p) {
+    return true;
+}
+vec2 bos_render_remap(vec2 p) {
+    return vec2(bos_render_center.x - abs(p.x - bos_render_center.x), p.y);
+}
+"
+            .to_string(),
+        };
+
+        "const float BOS_RENDER_PI = 3.14159265358979;
+vec2 bos_render_res = vec2(resolution_x, resolution_y);
+vec2 bos_render_center = bos_render_res / 2.;"
+            .to_string()
+            + &body
+    }
+
+    /// Whether the mapping defines `bos_render_direction`
+    pub fn has_direction(&self) -> bool {
+        matches!(
+            self,
+            Remap::Fisheye { .. } | Remap::Equirect { .. } | Remap::Cylindrical { .. }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "fisheye".parse::<Remap>().unwrap(),
+            Remap::Fisheye { fov: 180. }
+        );
+        assert_eq!(
+            "equirect:360,90".parse::<Remap>().unwrap(),
+            Remap::Equirect {
+                hfov: 360.,
+                vfov: 90.
+            }
+        );
+        assert_eq!(
+            "kaleidoscope:8".parse::<Remap>().unwrap(),
+            Remap::Kaleidoscope { segments: 8 }
+        );
+        assert_eq!("mirror".parse::<Remap>().unwrap(), Remap::Mirror);
+
+        assert!("mirror:2".parse::<Remap>().is_err());
+        assert!("cylindrical:180".parse::<Remap>().is_err());
+        assert!("kaleidoscope:2.5".parse::<Remap>().is_err());
+        assert!("fisheye:wide".parse::<Remap>().is_err());
+        assert!("swirl".parse::<Remap>().is_err());
+    }
+
+    #[test]
+    fn test_glsl_floats() {
+        // Parameters must be emitted as GLSL float literals
+        let glsl = Remap::Fisheye { fov: 200. }.glsl();
+        assert!(glsl.contains("radians(200.0)"));
+        assert!(glsl.contains("bos_render_direction"));
+
+        let glsl = Remap::Kaleidoscope { segments: 6 }.glsl();
+        assert!(glsl.contains("/ 6.;"));
+        assert!(!glsl.contains("bos_render_direction"));
+    }
+}
@@ -0,0 +1,1171 @@
+use crate::balance::Balancer;
+use crate::frame::{Frame, FrameAssembler, Tile};
+use crate::offscreen::{calc_tile_dims, Gpu, GpuFault};
+use crate::output;
+use crate::progressive::{self, Budget};
+use crate::resume::{self, WorkOrder};
+use crate::schedule::TileScheduler;
+use crate::settings::Settings;
+use crate::tiles::{blit, split, Rect};
+use anyhow::{ensure, Context, Error, Result};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// How long to wait on each busy device in turn for a tile to finish, when there are several
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Something produced by the renderer
+#[derive(Debug)]
+pub enum Event {
+    /// A progressive sample of a tile was accumulated
+    Sample,
+    /// A tile is finished
+    Tile(Tile),
+    /// A tile of a progressive frame as it stands, due for a snapshot. Tiles of a snapshot come
+    /// out in order, each with the samples taken so far
+    Snapshot(Tile),
+    /// A tile failed on the GPU and was rendered again
+    Retried(Retry),
+}
+
+/// A tile that failed on the GPU, and how it is being rendered again
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    pub frame_idx: usize,
+    pub tile_idx: usize,
+    pub tiles: usize,
+    /// Index of the progressive sample that failed, if it was one
+    pub sample: Option<u32>,
+    pub rect: Rect,
+    pub device: usize,
+    pub fault: GpuFault,
+    pub attempt: u32,
+    pub max_attempts: u32,
+    /// Pieces along each side the tile is split into this time, if it is split
+    pub pieces: Option<u32>,
+}
+
+/// Where the renderer is up to, as of the last event
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Progress {
+    pub frame_idx: usize,
+    /// Position of the frame among those being rendered, and their number unless endless
+    pub frame: usize,
+    pub frames: Option<usize>,
+    /// Index of the progressive sample being rendered, if progressive
+    pub sample: Option<u32>,
+    pub tile_idx: usize,
+    pub tiles: usize,
+    /// Pixels rendered so far, counting each progressive sample, and in all unless endless. A
+    /// progressive frame may stop short of its samples on a budget or Ctrl-C
+    pub pixels: u64,
+    pub total_pixels: Option<u64>,
+}
+
+/// Renders tiles on one device, keeping up to `frames_in_flight` of them in flight and
+/// downloading them in the order they were submitted
+pub trait TileEngine {
+    /// Submit a tile covering `rect` of the image, which may be smaller than the tile size
+    fn submit_rect(&mut self, time: f32, rect: Rect) -> Result<()>;
+
+    /// Submit one progressive sample of a tile covering `rect`, rendered with the given
+    /// `u_sample` and `u_seed` and added to the running sum kept on the device for `tile_idx`.
+    /// Sample 0 starts the sum afresh
+    fn submit_sample(
+        &mut self,
+        time: f32,
+        rect: Rect,
+        sample: u32,
+        seed: u32,
+        tile_idx: usize,
+    ) -> Result<()>;
+
+    /// Submit copying out the running sum for `tile_idx`, to be downloaded as the mean of its
+    /// `samples` samples. The sum can still be added to afterwards, unless this is its `last`
+    /// resolve, after which it is freed
+    fn submit_resolve(
+        &mut self,
+        rect: Rect,
+        tile_idx: usize,
+        samples: u32,
+        last: bool,
+    ) -> Result<()>;
+
+    /// Wait for the oldest tile or resolved sum in flight and return it in the output layout
+    fn download_frame(&mut self) -> Result<Vec<u8>>;
+
+    /// Wait for the oldest sample in flight, which leaves nothing to download
+    fn finish_sample(&mut self) -> Result<()>;
+
+    /// Wait up to `timeout` for the oldest tile in flight to finish, returning whether it has.
+    /// Faults are returned as errors
+    fn wait(&mut self, timeout: Duration) -> Result<bool>;
+
+    /// Time taken by the most recently downloaded tile, if the device can measure it
+    fn last_gpu_time(&self) -> Option<Duration>;
+}
+
+/// A device to build tile engines on, which can be rebuilt after a fault
+pub trait Device: Clone {
+    type Engine: TileEngine;
+
+    fn engine(&self, cfg: &Settings) -> Result<Self::Engine>;
+
+    /// A replacement for a device that faulted
+    fn rebuild(&self) -> Result<Self>;
+}
+
+/// What a job renders
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Work {
+    /// A whole tile
+    Tile,
+    /// A progressive sample of a tile, added to its sum on the device
+    Sample,
+    /// The mean of a progressive tile's samples so far, for a snapshot, as the finished tile, or
+    /// both
+    Resolve { snapshot: bool, last: bool },
+}
+
+/// A tile submitted or waiting to be
+struct Job {
+    /// Position of the tile's pass among those queued; frames are a single pass unless
+    /// progressive
+    pass: usize,
+    work: Work,
+    rect: Rect,
+    time: f32,
+    frame_idx: usize,
+    tile_idx: usize,
+    tiles: usize,
+    /// Index of a progressive sample, or the number of samples a resolve divides by
+    sample: u32,
+    /// Device the tile is submitted to, once it is
+    engine: usize,
+}
+
+impl Job {
+    /// Order the tile comes out in
+    fn key(&self) -> (usize, usize) {
+        (self.pass, self.tile_idx)
+    }
+}
+
+/// A job downloaded ahead of its turn, with its pixels unless it was a progressive sample
+type Downloaded = (Job, Option<Vec<u8>>);
+
+/// A progressive frame being refined. Each tile's samples are summed on the device that took
+/// its first one, and only read back to be resolved
+struct Accumulation {
+    frame_idx: usize,
+    time: f32,
+    rects: Vec<Rect>,
+    /// Complete passes over the frame
+    samples: u32,
+    /// Device holding each tile's sum, and the samples added to it so far
+    devices: Vec<usize>,
+    summed: Vec<u32>,
+    budget: Budget,
+}
+
+/// Renders the configured frames tile by tile, keeping several tiles in flight, and yields
+/// finished tiles in order, or whole frames. Frames already written are left out as
+/// `resume::work_order` decides
+pub struct Renderer<D: Device = Gpu> {
+    cfg: Settings,
+    /// One per device, each with up to `frames_in_flight` tiles in flight
+    engines: Vec<D::Engine>,
+    gpus: Vec<D>,
+    balancer: Balancer,
+    region: Rect,
+    scheduler: TileScheduler,
+    /// Frames left to queue
+    work_order: WorkOrder,
+    frames: Option<usize>,
+    frames_done: usize,
+    /// Whether to stop once the frames already started are finished
+    stopping: bool,
+    pending: VecDeque<Job>,
+    passes_queued: usize,
+    /// Tiles submitted and not yet downloaded, in the order they were submitted
+    in_flight: VecDeque<Job>,
+    /// Tiles downloaded ahead of earlier ones still in flight on other devices, by `Job::key`
+    downloaded: BTreeMap<(usize, usize), Downloaded>,
+    /// When each device last finished a tile, or was given one while idle, to time it out by
+    active_since: Vec<Instant>,
+    accumulation: Option<Accumulation>,
+    /// Finished progressive tiles which were also due for a snapshot
+    ready: VecDeque<Tile>,
+    /// Faults recovered from, to report
+    retries: VecDeque<Retry>,
+    assembler: Option<FrameAssembler>,
+    progress: Progress,
+}
+
+impl Renderer {
+    /// Render the frames `resume::work_order` gives for the settings
+    pub fn new(cfg: Settings) -> Result<Self> {
+        let work_order = resume::work_order(&cfg)?;
+        Self::with_work_order(cfg, work_order)
+    }
+
+    /// Render the given frames. Running out of frames only ends rendering until the work order
+    /// yields more, so frames can be fed in as they become known
+    pub fn with_work_order(cfg: Settings, work_order: WorkOrder) -> Result<Self> {
+        let gpus = Gpu::for_settings(&cfg)?;
+        Self::on_gpus(cfg, work_order, &gpus)
+    }
+}
+
+impl<D: Device> Renderer<D> {
+    /// Render the given frames on cores which may already have been used for other renders, one
+    /// per device
+    pub fn on_gpus(cfg: Settings, work_order: WorkOrder, gpus: &[D]) -> Result<Self> {
+        let target = cfg
+            .tile_target_ms
+            .map(|ms| Duration::from_secs_f32(ms / 1000.));
+        ensure!(
+            target.is_none() || !cfg.progressive(),
+            "Adaptive tiles are not supported with progressive rendering"
+        );
+        ensure!(
+            target.is_none() || !output::streams_bands(&cfg),
+            "Adaptive tiles can't be streamed in bands"
+        );
+        ensure!(
+            target.is_none() || gpus.len() <= 1,
+            "Adaptive tiles are not supported across several devices"
+        );
+
+        let engines = gpus
+            .iter()
+            .map(|gpu| gpu.engine(&cfg))
+            .collect::<Result<Vec<_>>>()?;
+        let balancer = Balancer::new(engines.len(), cfg.frames_in_flight);
+        let region = cfg.region();
+        let scheduler = TileScheduler::new(region, calc_tile_dims(&cfg), target);
+
+        // Finite work orders are checked up front, so their length is known
+        let frames = work_order.size_hint().1;
+        let passes = if cfg.progressive() {
+            cfg.samples as u64
+        } else {
+            1
+        };
+        let progress = Progress {
+            frames,
+            total_pixels: frames.map(|frames| frames as u64 * area(region) * passes),
+            ..Progress::default()
+        };
+
+        Ok(Self {
+            frames,
+            frames_done: 0,
+            work_order,
+            stopping: false,
+            engines,
+            gpus: gpus.to_vec(),
+            balancer,
+            region,
+            scheduler,
+            pending: VecDeque::new(),
+            passes_queued: 0,
+            in_flight: VecDeque::new(),
+            downloaded: BTreeMap::new(),
+            active_since: vec![Instant::now(); gpus.len()],
+            accumulation: None,
+            ready: VecDeque::new(),
+            retries: VecDeque::new(),
+            assembler: None,
+            progress,
+            cfg,
+        })
+    }
+
+    /// The cores rendered on, including any rebuilt after a fault
+    pub fn gpus(&self) -> &[D] {
+        &self.gpus
+    }
+
+    /// Where rendering is up to
+    pub fn progress(&self) -> Progress {
+        self.progress
+    }
+
+    /// Finish the frames already started and render no more. A progressive frame keeps the
+    /// samples it has at the end of the current pass
+    pub fn stop(&mut self) {
+        self.work_order = Box::new(std::iter::empty());
+        self.stopping = true;
+    }
+
+    /// Wait for the next event, or `None` once every frame is finished
+    pub fn next_event(&mut self) -> Result<Option<Event>> {
+        let event = self.render()?;
+        self.progress.frame = self.frames_done;
+        self.progress.frames = self.frames;
+        if let Some(Event::Tile(tile)) = &event {
+            if tile.tile_idx + 1 == tile.tiles {
+                self.frames_done += 1;
+            }
+        }
+        Ok(event)
+    }
+
+    fn render(&mut self) -> Result<Option<Event>> {
+        if let Some(retry) = self.retries.pop_front() {
+            return Ok(Some(Event::Retried(retry)));
+        }
+        if let Some(tile) = self.ready.pop_front() {
+            self.progress.tile_idx = tile.tile_idx;
+            return Ok(Some(Event::Tile(tile)));
+        }
+
+        // Tiles are downloaded as they finish on any device, but come out in order
+        let (job, pixels) = loop {
+            self.submit_work()?;
+            let oldest = self.in_flight.front().map(Job::key);
+            match self.downloaded.keys().next() {
+                Some(&key) if !matches!(oldest, Some(oldest) if oldest < key) => {
+                    break self.downloaded.remove(&key).unwrap();
+                }
+                None if oldest.is_none() => return Ok(None),
+                _ => self.collect()?,
+            }
+        };
+        if let Some(retry) = self.retries.pop_front() {
+            // Report the fault before the tile
+            self.downloaded.insert(job.key(), (job, pixels));
+            return Ok(Some(Event::Retried(retry)));
+        }
+
+        self.progress = Progress {
+            frame_idx: job.frame_idx,
+            sample: match job.work {
+                Work::Tile => None,
+                Work::Sample => Some(job.sample),
+                Work::Resolve { .. } => self.progress.sample,
+            },
+            tile_idx: job.tile_idx,
+            tiles: job.tiles,
+            ..self.progress
+        };
+
+        match job.work {
+            Work::Tile => {
+                self.progress.pixels += area(job.rect);
+                let tile = self.tile(&job, 1, pixels.expect("Tile downloaded without pixels"));
+                Ok(Some(Event::Tile(tile)))
+            }
+            Work::Sample => {
+                self.progress.pixels += area(job.rect);
+                if job.tile_idx + 1 == job.tiles {
+                    self.end_pass();
+                }
+                Ok(Some(Event::Sample))
+            }
+            Work::Resolve { snapshot, last } => {
+                let pixels = pixels.expect("Sum resolved without pixels");
+                let tile = self.tile(&job, job.sample, pixels);
+                if last && job.tile_idx + 1 == job.tiles {
+                    self.accumulation = None;
+                }
+                if !snapshot {
+                    return Ok(Some(Event::Tile(tile)));
+                }
+                if last {
+                    self.ready.push_back(tile.clone());
+                }
+                Ok(Some(Event::Snapshot(tile)))
+            }
+        }
+    }
+
+    /// Note that every tile of the progressive frame has another sample, and resolve the tiles
+    /// if a snapshot is due or the frame is finished
+    fn end_pass(&mut self) {
+        let accumulation = self
+            .accumulation
+            .as_mut()
+            .expect("Sample downloaded outside of a progressive frame");
+        accumulation.samples += 1;
+        let samples = accumulation.samples;
+        let snapshot = matches!(
+            self.cfg.snapshot_every,
+            Some(every) if samples.checked_rem(every) == Some(0)
+        );
+        let last = self.stopping || accumulation.budget.exhausted(samples);
+        if snapshot || last {
+            let (frame_idx, time) = (accumulation.frame_idx, accumulation.time);
+            let rects = accumulation.rects.clone();
+            let work = Work::Resolve { snapshot, last };
+            self.queue_pass(frame_idx, time, &rects, samples, work);
+        }
+    }
+
+    /// A finished tile of a job, with `samples` samples averaged into it
+    fn tile(&self, job: &Job, samples: u32, pixels: Vec<u8>) -> Tile {
+        Tile {
+            frame_idx: job.frame_idx,
+            time: job.time,
+            samples,
+            pos: self.pos(job.rect),
+            dims: (job.rect.width as usize, job.rect.height as usize),
+            tile_idx: job.tile_idx,
+            tiles: job.tiles,
+            pixels,
+        }
+    }
+
+    /// Wait for the next finished tile, skipping other events
+    pub fn next_tile(&mut self) -> Result<Option<Tile>> {
+        while let Some(event) = self.next_event()? {
+            if let Event::Tile(tile) = event {
+                return Ok(Some(tile));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Wait for the next finished frame, skipping other events
+    pub fn next_frame(&mut self) -> Result<Option<Frame>> {
+        while let Some(tile) = self.next_tile()? {
+            let cfg = &self.cfg;
+            let assembler = self
+                .assembler
+                .get_or_insert_with(|| FrameAssembler::new(cfg));
+            if let Some(frame) = assembler.add(&tile) {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Position of a rect within the rendered region
+    fn pos(&self, rect: Rect) -> (usize, usize) {
+        (
+            (rect.x - self.region.x) as usize,
+            (rect.y - self.region.y) as usize,
+        )
+    }
+
+    /// Keep `frames_in_flight` tiles submitted to each device, with at most as many again
+    /// downloaded ahead of the tile due out next
+    fn submit_work(&mut self) -> Result<()> {
+        let capacity = self.balancer.capacity();
+        while self.in_flight.len() < capacity && self.downloaded.len() < capacity {
+            if self.pending.is_empty() && !self.queue_work()? {
+                break;
+            }
+            let mut job = match self.pending.pop_front() {
+                Some(job) => job,
+                None => break,
+            };
+            // A progressive tile's samples add up on the device that took its first
+            let pinned = match &self.accumulation {
+                Some(accumulation) if job.work != Work::Sample || job.sample > 0 => {
+                    Some(accumulation.devices[job.tile_idx])
+                }
+                _ => None,
+            };
+            let engine = match pinned {
+                Some(engine) => Some(engine).filter(|&engine| self.balancer.has_room(engine)),
+                None => self.balancer.pick(area(job.rect)),
+            };
+            job.engine = match engine {
+                Some(engine) => engine,
+                None => {
+                    // A busy device will get to it sooner than any with room, or holds its sum
+                    self.pending.push_front(job);
+                    break;
+                }
+            };
+            if let Some(accumulation) = &mut self.accumulation {
+                accumulation.devices[job.tile_idx] = job.engine;
+            }
+
+            let engine = job.engine;
+            if self.in_flight.iter().all(|other| other.engine != engine) {
+                self.active_since[engine] = Instant::now();
+            }
+            submit(&self.cfg, &mut self.engines[job.engine], &job)?;
+            self.balancer.submitted(job.engine, area(job.rect));
+            self.in_flight.push_back(job);
+        }
+        Ok(())
+    }
+
+    /// Wait for the oldest tile in flight on any device to finish, and download it into the
+    /// reorder buffer. Busy devices are polled in turn, so that a slow one doesn't hold up tiles
+    /// finished on the others
+    fn collect(&mut self) -> Result<()> {
+        let timeout = Duration::from_secs_f32(self.cfg.gpu_timeout);
+        loop {
+            let mut busy: Vec<usize> = self.in_flight.iter().map(|job| job.engine).collect();
+            busy.sort_unstable();
+            busy.dedup();
+            // A lone busy device may as well be waited on until it times out
+            let slice = if busy.len() == 1 {
+                timeout
+            } else {
+                POLL_INTERVAL
+            };
+            for engine in busy {
+                let finished = match self.engines[engine].wait(slice) {
+                    Ok(true) => Ok(()),
+                    Ok(false) if self.active_since[engine].elapsed() < timeout => continue,
+                    Ok(false) => Err(GpuFault::Timeout(timeout).into()),
+                    Err(error) => Err(error),
+                };
+                return self.download(engine, finished);
+            }
+        }
+    }
+
+    /// Download the oldest tile in flight on a device into the reorder buffer, or render it again
+    /// if the device faulted
+    fn download(&mut self, engine: usize, finished: Result<()>) -> Result<()> {
+        let position = self
+            .in_flight
+            .iter()
+            .position(|job| job.engine == engine)
+            .expect("Downloaded from a device with nothing in flight");
+        let job = self.in_flight.remove(position).unwrap();
+
+        let device = &mut self.engines[engine];
+        let (pixels, time) = match finished.and_then(|()| download_job(device, &job)) {
+            Ok(pixels) => (pixels, device.last_gpu_time()),
+            // Tiles rendered again in pieces don't say how long the whole tile takes
+            Err(error) => {
+                let what = match job.work {
+                    Work::Sample => "Downloading sample",
+                    _ => "Downloading frame",
+                };
+                (self.retry(&job, error).context(what)?, None)
+            }
+        };
+
+        if let (Work::Sample, Some(accumulation)) = (job.work, &mut self.accumulation) {
+            accumulation.summed[job.tile_idx] += 1;
+        }
+        // Resolving only copies a sum out, which says nothing about how fast the device renders
+        let time = time.filter(|_| !matches!(job.work, Work::Resolve { .. }));
+        self.balancer.finished(engine, area(job.rect), time);
+        if let Some(time) = time.filter(|_| job.work == Work::Tile) {
+            self.scheduler.record(job.rect, time);
+        }
+        self.active_since[engine] = Instant::now();
+        self.downloaded.insert(job.key(), (job, pixels));
+        Ok(())
+    }
+
+    /// Render a tile whose download failed again, after rebuilding its device, in smaller pieces
+    /// on each attempt. Errors other than GPU faults, or faults past `gpu_retries` attempts,
+    /// are returned
+    fn retry(&mut self, job: &Job, mut error: Error) -> Result<Option<Vec<u8>>> {
+        for attempt in 1..=self.cfg.gpu_retries {
+            let fault = match error.downcast_ref::<GpuFault>() {
+                Some(&fault) => fault,
+                None => return Err(error),
+            };
+            let pieces = 1 << attempt.min(8);
+            self.retries.push_back(Retry {
+                frame_idx: job.frame_idx,
+                tile_idx: job.tile_idx,
+                tiles: job.tiles,
+                sample: Some(job.sample).filter(|_| job.work == Work::Sample),
+                rect: job.rect,
+                device: job.engine,
+                fault,
+                attempt,
+                max_attempts: self.cfg.gpu_retries,
+                pieces: Some(pieces).filter(|_| job.work == Work::Tile),
+            });
+
+            self.rebuild(job.engine)?;
+            let rendered = match job.work {
+                Work::Tile => self.render_pieces(job, pieces).map(Some),
+                _ => self.render_progressive(job),
+            };
+            match rendered {
+                Ok(result) => {
+                    // The tiles that were in flight on the device were lost with it
+                    for lost in self
+                        .in_flight
+                        .iter()
+                        .filter(|lost| lost.engine == job.engine)
+                    {
+                        submit(&self.cfg, &mut self.engines[job.engine], lost)?;
+                    }
+                    return Ok(result);
+                }
+                Err(e) => error = e,
+            }
+        }
+        Err(error.context(format!(
+            "{} failed after {} retries",
+            self.describe(job),
+            self.cfg.gpu_retries
+        )))
+    }
+
+    /// Which tile a job is, for messages
+    fn describe(&self, job: &Job) -> String {
+        let sample = match job.work {
+            Work::Tile => String::new(),
+            Work::Sample => format!(", sample {}", job.sample + 1),
+            Work::Resolve { .. } => format!(", resolving {} samples", job.sample),
+        };
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = job.rect;
+        format!(
+            "Frame {}{}, tile {}/{} ({}x{} at {},{})",
+            job.frame_idx,
+            sample,
+            job.tile_idx + 1,
+            job.tiles,
+            width,
+            height,
+            x,
+            y
+        )
+    }
+
+    /// Replace a device's core and engine after a fault
+    fn rebuild(&mut self, engine: usize) -> Result<()> {
+        let gpu = self.gpus[engine]
+            .rebuild()
+            .with_context(|| format!("Rebuilding device {}", engine))?;
+        self.engines[engine] = gpu.engine(&self.cfg)?;
+        self.gpus[engine] = gpu;
+        Ok(())
+    }
+
+    /// Render a tile on its device as a grid of smaller tiles, one at a time, and put them
+    /// together
+    fn render_pieces(&mut self, job: &Job, pieces: u32) -> Result<Vec<u8>> {
+        let bytes_per_pixel = output::tile_bytes_per_pixel(&self.cfg);
+        let dims = (job.rect.width as usize, job.rect.height as usize);
+        let mut pixels = vec![0; dims.0 * dims.1 * bytes_per_pixel];
+        let engine = &mut self.engines[job.engine];
+        for piece in split(job.rect, pieces) {
+            engine.submit_rect(job.time, piece)?;
+            blit(
+                &engine.download_frame()?,
+                &mut pixels,
+                (
+                    (piece.x - job.rect.x) as usize,
+                    (piece.y - job.rect.y) as usize,
+                ),
+                dims,
+                (piece.width as usize, piece.height as usize),
+                bytes_per_pixel,
+            );
+        }
+        Ok(pixels)
+    }
+
+    /// Render a progressive job again on its rebuilt device. The sums kept on the device were
+    /// lost with the old one, so the samples already added to them are rendered again first, one
+    /// at a time
+    fn render_progressive(&mut self, job: &Job) -> Result<Option<Vec<u8>>> {
+        let accumulation = self
+            .accumulation
+            .as_ref()
+            .expect("Progressive job outside of a progressive frame");
+        let engine = &mut self.engines[job.engine];
+        for (tile_idx, &rect) in accumulation.rects.iter().enumerate() {
+            if accumulation.devices[tile_idx] != job.engine {
+                continue;
+            }
+            for sample in 0..accumulation.summed[tile_idx] {
+                let seed = progressive::sample_seed(self.cfg.seed, accumulation.frame_idx, sample);
+                engine.submit_sample(accumulation.time, rect, sample, seed, tile_idx)?;
+                engine.finish_sample()?;
+            }
+        }
+        submit(&self.cfg, engine, job)?;
+        download_job(engine, job)
+    }
+
+    /// Queue the tiles of the next frame, or the next pass over a progressive frame. Returns
+    /// false if there is nothing to queue yet
+    fn queue_work(&mut self) -> Result<bool> {
+        if !self.cfg.progressive() {
+            let frame_idx = match self.work_order.next().transpose()? {
+                Some(frame_idx) => frame_idx,
+                None => return Ok(false),
+            };
+            let rects = self.scheduler.next_frame();
+            let time = self.cfg.frame_time(frame_idx);
+            self.queue_pass(frame_idx, time, &rects, 0, Work::Tile);
+            return Ok(true);
+        }
+
+        // Whether to take another sample depends on how long the last pass took
+        if !self.in_flight.is_empty() || !self.downloaded.is_empty() || !self.ready.is_empty() {
+            return Ok(false);
+        }
+        if self.accumulation.is_none() {
+            let frame_idx = match self.work_order.next().transpose()? {
+                Some(frame_idx) => frame_idx,
+                None => return Ok(false),
+            };
+            let rects = self.scheduler.next_frame();
+            self.accumulation = Some(Accumulation {
+                frame_idx,
+                time: self.cfg.frame_time(frame_idx),
+                samples: 0,
+                devices: vec![0; rects.len()],
+                summed: vec![0; rects.len()],
+                budget: Budget::new(
+                    self.cfg.samples,
+                    self.cfg.sample_budget.map(Duration::from_secs_f32),
+                ),
+                rects,
+            });
+        }
+
+        let accumulation = self.accumulation.take().unwrap();
+        self.queue_pass(
+            accumulation.frame_idx,
+            accumulation.time,
+            &accumulation.rects,
+            accumulation.samples,
+            Work::Sample,
+        );
+        self.accumulation = Some(accumulation);
+        Ok(true)
+    }
+
+    fn queue_pass(&mut self, frame_idx: usize, time: f32, rects: &[Rect], sample: u32, work: Work) {
+        let tiles = rects.len();
+        let pass = self.passes_queued;
+        self.passes_queued += 1;
+        self.pending
+            .extend(rects.iter().enumerate().map(|(tile_idx, &rect)| Job {
+                pass,
+                work,
+                rect,
+                time,
+                frame_idx,
+                tile_idx,
+                tiles,
+                sample,
+                engine: 0,
+            }));
+    }
+}
+
+impl<D: Device> Iterator for Renderer<D> {
+    type Item = Result<Frame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().transpose()
+    }
+}
+
+/// Submit a job's work
+fn submit(cfg: &Settings, engine: &mut impl TileEngine, job: &Job) -> Result<()> {
+    match job.work {
+        Work::Tile => engine.submit_rect(job.time, job.rect),
+        Work::Sample => {
+            let seed = progressive::sample_seed(cfg.seed, job.frame_idx, job.sample);
+            engine.submit_sample(job.time, job.rect, job.sample, seed, job.tile_idx)
+        }
+        Work::Resolve { last, .. } => {
+            engine.submit_resolve(job.rect, job.tile_idx, job.sample, last)
+        }
+    }
+}
+
+/// Wait for a job submitted to a device, and download its pixels unless it's a sample
+fn download_job(engine: &mut impl TileEngine, job: &Job) -> Result<Option<Vec<u8>>> {
+    match job.work {
+        Work::Sample => engine.finish_sample().map(|()| None),
+        _ => engine.download_frame().map(Some),
+    }
+}
+
+/// Number of pixels in a rect
+fn area(rect: Rect) -> u64 {
+    rect.width as u64 * rect.height as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use structopt::StructOpt;
+
+    /// Renders on the CPU instead. Each pixel is its position and the index of its frame, and
+    /// each progressive sample is its own index. Tiles take `polls` waits to finish, and are
+    /// timed as taking longer the more they take. The device is lost when waited on after
+    /// `lost_after` tiles, until rebuilt
+    #[derive(Clone)]
+    struct FakeDevice {
+        polls: u32,
+        lost_after: Option<usize>,
+    }
+
+    struct FakeEngine {
+        cfg: Settings,
+        polls: u32,
+        lost_after: Option<usize>,
+        /// Work in flight, and the waits left for each
+        in_flight: VecDeque<(Submitted, u32)>,
+        /// Running sum of each tile's samples, and their number
+        sums: HashMap<usize, (u32, u32)>,
+        last_time: Option<Duration>,
+        rendered: usize,
+    }
+
+    enum Submitted {
+        Tile {
+            time: f32,
+            rect: Rect,
+        },
+        Sample {
+            rect: Rect,
+            sample: u32,
+            tile_idx: usize,
+        },
+        Resolve {
+            rect: Rect,
+            tile_idx: usize,
+            samples: u32,
+            last: bool,
+        },
+    }
+
+    impl Device for FakeDevice {
+        type Engine = FakeEngine;
+
+        fn engine(&self, cfg: &Settings) -> Result<FakeEngine> {
+            Ok(FakeEngine {
+                cfg: cfg.clone(),
+                polls: self.polls,
+                lost_after: self.lost_after,
+                in_flight: VecDeque::new(),
+                sums: HashMap::new(),
+                last_time: None,
+                rendered: 0,
+            })
+        }
+
+        fn rebuild(&self) -> Result<Self> {
+            Ok(FakeDevice {
+                lost_after: None,
+                ..self.clone()
+            })
+        }
+    }
+
+    impl FakeEngine {
+        fn submit(&mut self, work: Submitted) -> Result<()> {
+            assert!(
+                self.in_flight.len() < self.cfg.frames_in_flight,
+                "More tiles submitted than can be in flight"
+            );
+            self.in_flight.push_back((work, self.polls));
+            Ok(())
+        }
+
+        fn download(&mut self) -> Submitted {
+            let (work, polls) = self
+                .in_flight
+                .pop_front()
+                .expect("Downloaded a tile that wasn't submitted");
+            assert_eq!(polls, 0, "Downloaded a tile that isn't finished");
+            let rect = match work {
+                Submitted::Tile { rect, .. }
+                | Submitted::Sample { rect, .. }
+                | Submitted::Resolve { rect, .. } => rect,
+            };
+            let micros = area(rect) * (1 + self.polls as u64);
+            self.last_time = Some(Duration::from_micros(micros));
+            self.rendered += 1;
+            work
+        }
+    }
+
+    impl TileEngine for FakeEngine {
+        fn submit_rect(&mut self, time: f32, rect: Rect) -> Result<()> {
+            self.submit(Submitted::Tile { time, rect })
+        }
+
+        fn submit_sample(
+            &mut self,
+            _time: f32,
+            rect: Rect,
+            sample: u32,
+            _seed: u32,
+            tile_idx: usize,
+        ) -> Result<()> {
+            self.submit(Submitted::Sample {
+                rect,
+                sample,
+                tile_idx,
+            })
+        }
+
+        fn submit_resolve(
+            &mut self,
+            rect: Rect,
+            tile_idx: usize,
+            samples: u32,
+            last: bool,
+        ) -> Result<()> {
+            self.submit(Submitted::Resolve {
+                rect,
+                tile_idx,
+                samples,
+                last,
+            })
+        }
+
+        fn download_frame(&mut self) -> Result<Vec<u8>> {
+            let pixels = match self.download() {
+                Submitted::Tile { time, rect } => {
+                    let frame_idx = (time / self.cfg.rate).round() as u8;
+                    (rect.y..rect.y + rect.height)
+                        .flat_map(|y| (rect.x..rect.x + rect.width).map(move |x| (x, y)))
+                        .flat_map(|(x, y)| [x as u8, y as u8, frame_idx])
+                        .collect()
+                }
+                Submitted::Resolve {
+                    rect,
+                    tile_idx,
+                    samples,
+                    last,
+                } => {
+                    let (sum, count) = self.sums[&tile_idx];
+                    if last {
+                        self.sums.remove(&tile_idx);
+                    }
+                    assert_eq!(count, samples, "Resolved the wrong number of samples");
+                    let mean = (sum as f32 / samples as f32).round() as u8;
+                    vec![mean; area(rect) as usize * 3]
+                }
+                Submitted::Sample { .. } => panic!("Downloaded a sample"),
+            };
+            Ok(pixels)
+        }
+
+        fn finish_sample(&mut self) -> Result<()> {
+            match self.download() {
+                Submitted::Sample {
+                    sample, tile_idx, ..
+                } => {
+                    let sum = self.sums.entry(tile_idx).or_default();
+                    if sample == 0 {
+                        *sum = (0, 0);
+                    }
+                    assert_eq!(sum.1, sample, "Sample added out of turn");
+                    *sum = (sum.0 + sample, sum.1 + 1);
+                }
+                _ => panic!("Finished a tile as a sample"),
+            }
+            Ok(())
+        }
+
+        fn wait(&mut self, _timeout: Duration) -> Result<bool> {
+            if self.lost_after == Some(self.rendered) {
+                return Err(GpuFault::DeviceLost.into());
+            }
+            let polls = &mut self.in_flight.front_mut().unwrap().1;
+            *polls = polls.saturating_sub(1);
+            Ok(*polls == 0)
+        }
+
+        fn last_gpu_time(&self) -> Option<Duration> {
+            self.last_time
+        }
+    }
+
+    const FAST: FakeDevice = FakeDevice {
+        polls: 0,
+        lost_after: None,
+    };
+
+    /// Three 8x6 frames in four tiles each
+    fn fake_renderer(args: &[&str], devices: &[FakeDevice]) -> Renderer<FakeDevice> {
+        let base = [
+            "bosrender",
+            "a_spot.frag",
+            "-w",
+            "8",
+            "-h",
+            "6",
+            "--tile-width",
+            "4",
+            "--tile-height",
+            "4",
+            "-f",
+            "3",
+        ];
+        let cfg = Settings::from_iter(base.iter().chain(args));
+        let work_order = Box::new(cfg.frame_indices().map(Ok));
+        Renderer::on_gpus(cfg, work_order, devices).unwrap()
+    }
+
+    #[test]
+    fn test_tile_order() {
+        // Tiles come out in order, however many devices they are spread over
+        for count in 1..=3 {
+            let devices = vec![FAST; count];
+            let mut renderer = fake_renderer(&[], &devices);
+            let mut order = vec![];
+            while let Some(tile) = renderer.next_tile().unwrap() {
+                assert_eq!(tile.tiles, 4);
+                order.push((tile.frame_idx, tile.tile_idx));
+            }
+            let expected: Vec<_> = (0..3)
+                .flat_map(|frame_idx| (0..4).map(move |tile_idx| (frame_idx, tile_idx)))
+                .collect();
+            assert_eq!(order, expected, "{} devices", count);
+        }
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut renderer = fake_renderer(&[], &[FAST, FAST]);
+        for index in 0..3 {
+            let frame = renderer.next_frame().unwrap().unwrap();
+            assert_eq!((frame.index, frame.samples), (index, 1));
+            let expected: Vec<u8> = (0..6)
+                .flat_map(|y| (0..8).flat_map(move |x| [x, y, index as u8]))
+                .collect();
+            assert_eq!(frame.pixels, expected);
+        }
+        assert!(renderer.next_frame().unwrap().is_none());
+
+        let progress = renderer.progress();
+        assert_eq!((progress.frame, progress.frames), (3, Some(3)));
+        assert_eq!((progress.pixels, progress.total_pixels), (144, Some(144)));
+    }
+
+    #[test]
+    fn test_progressive_passes() {
+        let mut renderer = fake_renderer(&["--samples", "4"], &[FAST, FAST]);
+        let mut samples = vec![];
+        let tile = loop {
+            match renderer.next_event().unwrap().unwrap() {
+                Event::Sample => samples.push(renderer.progress().sample.unwrap()),
+                Event::Tile(tile) => break tile,
+                event => panic!("Unexpected {:?}", event),
+            }
+        };
+
+        // Every tile of a pass is rendered before the next pass starts
+        let passes: Vec<u32> = (0..4).flat_map(|pass| vec![pass; 4]).collect();
+        assert_eq!(samples, passes);
+        assert_eq!((tile.frame_idx, tile.tile_idx, tile.samples), (0, 0, 4));
+        // The mean of samples 0 to 3
+        assert!(tile.pixels.iter().all(|&v| v == 2));
+        assert_eq!(renderer.progress().pixels, 4 * 48);
+
+        // The running sums are freed once the frame is finished
+        while let Some(event) = renderer.next_event().unwrap() {
+            if matches!(event, Event::Tile(tile) if tile.tile_idx == 3) {
+                break;
+            }
+        }
+        assert!(renderer.engines.iter().all(|engine| engine.sums.is_empty()));
+    }
+
+    #[test]
+    fn test_stop() {
+        // The frame being rendered is finished, and no more are started
+        let mut renderer = fake_renderer(&["-l", "1"], &[FAST]);
+        renderer.next_tile().unwrap().unwrap();
+        renderer.stop();
+        assert_eq!(renderer.next_frame().unwrap().unwrap().index, 0);
+        assert!(renderer.next_frame().unwrap().is_none());
+
+        // A progressive frame keeps the samples of the pass it's on
+        let mut renderer = fake_renderer(&["--samples", "4"], &[FAST, FAST]);
+        renderer.next_event().unwrap().unwrap();
+        renderer.stop();
+        let frame = renderer.next_frame().unwrap().unwrap();
+        assert_eq!((frame.index, frame.samples), (0, 1));
+        assert!(frame.pixels.iter().all(|&v| v == 0));
+        assert!(renderer.next_frame().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_snapshots() {
+        // Snapshots are resolved a tile at a time, and the last one is also the finished frame
+        let mut renderer = fake_renderer(&["--samples", "4", "--snapshot-every", "2"], &[FAST]);
+        let mut events = vec![];
+        while let Some(event) = renderer.next_event().unwrap() {
+            match event {
+                Event::Sample => (),
+                Event::Snapshot(tile) => events.push(('s', tile.frame_idx, tile.samples)),
+                Event::Tile(tile) => events.push(('t', tile.frame_idx, tile.samples)),
+                event => panic!("Unexpected {:?}", event),
+            }
+            if events.len() == 12 {
+                break;
+            }
+        }
+        let mut expected = vec![('s', 0, 2); 4];
+        for _ in 0..4 {
+            expected.extend([('s', 0, 4), ('t', 0, 4)]);
+        }
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn test_lost_sums() {
+        // The samples summed on a lost device are rendered again on its replacement
+        for lost_after in [0, 5, 9, 13] {
+            let lost = FakeDevice {
+                lost_after: Some(lost_after),
+                ..FAST
+            };
+            let mut renderer = fake_renderer(&["--samples", "3"], &[lost]);
+            let mut retries = 0;
+            let frame = loop {
+                match renderer.next_event().unwrap().unwrap() {
+                    Event::Retried(retry) => {
+                        assert_eq!(retry.fault, GpuFault::DeviceLost);
+                        assert_eq!((retry.device, retry.attempt), (0, 1));
+                        assert_eq!(retry.pieces, None);
+                        retries += 1;
+                    }
+                    Event::Tile(tile) if tile.tile_idx == 0 => {
+                        assert!(tile.pixels.iter().all(|&v| v == 1));
+                    }
+                    Event::Tile(tile) if tile.tile_idx == 3 => break tile.frame_idx,
+                    _ => (),
+                }
+            };
+            assert_eq!((frame, retries), (0, 1), "Lost after {}", lost_after);
+        }
+    }
+
+    #[test]
+    fn test_slow_device() {
+        // Tiles finished on the fast device are downloaded ahead of those still on the slow one,
+        // which is given no more once it's measured
+        let slow = FakeDevice { polls: 50, ..FAST };
+        let mut renderer = fake_renderer(&[], &[FAST, slow]);
+        let (mut order, mut ahead) = (vec![], 0);
+        while let Some(tile) = renderer.next_tile().unwrap() {
+            order.push((tile.frame_idx, tile.tile_idx));
+            ahead = ahead.max(renderer.downloaded.len());
+        }
+        let expected: Vec<_> = (0..3)
+            .flat_map(|frame_idx| (0..4).map(move |tile_idx| (frame_idx, tile_idx)))
+            .collect();
+        assert_eq!(order, expected);
+        assert!(ahead > 0);
+        let rendered = (renderer.engines[0].rendered, renderer.engines[1].rendered);
+        assert_eq!(rendered, (9, 3));
+    }
+}
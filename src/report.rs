@@ -0,0 +1,270 @@
+use anyhow::{bail, Error};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How progress is shown
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgressMode {
+    /// A status line, redrawn in place on a terminal
+    Line,
+    /// A JSON object per event, one per line
+    Json,
+}
+
+impl FromStr for ProgressMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "line" => Ok(ProgressMode::Line),
+            "json" => Ok(ProgressMode::Json),
+            _ => bail!("Unknown progress mode \"{}\", expected line or json", s),
+        }
+    }
+}
+
+/// Number of recent progress updates the rate is measured over
+const WINDOW: usize = 64;
+
+/// Rendering speed over the most recent tiles, and the time left at that speed
+pub struct Throughput {
+    /// Time since the start and the pixels rendered by then, oldest first
+    points: VecDeque<(Duration, u64)>,
+}
+
+impl Default for Throughput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Throughput {
+    pub fn new() -> Self {
+        Self {
+            points: vec![(Duration::ZERO, 0)].into(),
+        }
+    }
+
+    /// Note that `pixels` in total had been rendered `at` after the start
+    pub fn record(&mut self, at: Duration, pixels: u64) {
+        if self.points.back().map(|&(_, last)| last) == Some(pixels) {
+            return;
+        }
+        self.points.push_back((at, pixels));
+        if self.points.len() > WINDOW {
+            self.points.pop_front();
+        }
+    }
+
+    /// Pixels rendered per second, if any have been
+    pub fn rate(&self) -> Option<f64> {
+        let &(first_at, first) = self.points.front()?;
+        let &(last_at, last) = self.points.back()?;
+        let secs = last_at.checked_sub(first_at)?.as_secs_f64();
+        if last == first || secs <= 0. {
+            return None;
+        }
+        Some((last - first) as f64 / secs)
+    }
+
+    /// Time to render `remaining` more pixels at the current rate
+    pub fn eta(&self, remaining: u64) -> Option<Duration> {
+        self.rate()
+            .map(|rate| Duration::from_secs_f64(remaining as f64 / rate))
+    }
+}
+
+/// A rate such as "12.3 Mpx/s"
+pub fn format_rate(pixels_per_second: f64) -> String {
+    let (value, unit) = if pixels_per_second >= 1e9 {
+        (pixels_per_second / 1e9, "Gpx/s")
+    } else if pixels_per_second >= 1e6 {
+        (pixels_per_second / 1e6, "Mpx/s")
+    } else if pixels_per_second >= 1e3 {
+        (pixels_per_second / 1e3, "kpx/s")
+    } else {
+        (pixels_per_second, "px/s")
+    };
+    format!("{:.1} {}", value, unit)
+}
+
+/// A duration such as "1h02m03s", "4m05s" or "6s"
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, secs) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if hours > 0 {
+        format!("{}h{:02}m{:02}s", hours, minutes, secs)
+    } else if minutes > 0 {
+        format!("{}m{:02}s", minutes, secs)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+/// A value of a field of a JSON event
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonValue {
+    Null,
+    Bool(bool),
+    Integer(u64),
+    /// Written as null if not finite
+    Number(f64),
+    String(String),
+}
+
+impl From<bool> for JsonValue {
+    fn from(value: bool) -> Self {
+        JsonValue::Bool(value)
+    }
+}
+
+impl From<u32> for JsonValue {
+    fn from(value: u32) -> Self {
+        JsonValue::Integer(value.into())
+    }
+}
+
+impl From<u64> for JsonValue {
+    fn from(value: u64) -> Self {
+        JsonValue::Integer(value)
+    }
+}
+
+impl From<usize> for JsonValue {
+    fn from(value: usize) -> Self {
+        JsonValue::Integer(value as u64)
+    }
+}
+
+impl From<f64> for JsonValue {
+    fn from(value: f64) -> Self {
+        JsonValue::Number(value)
+    }
+}
+
+impl From<&str> for JsonValue {
+    fn from(value: &str) -> Self {
+        JsonValue::String(value.into())
+    }
+}
+
+impl From<String> for JsonValue {
+    fn from(value: String) -> Self {
+        JsonValue::String(value)
+    }
+}
+
+impl<T: Into<JsonValue>> From<Option<T>> for JsonValue {
+    fn from(value: Option<T>) -> Self {
+        value.map_or(JsonValue::Null, Into::into)
+    }
+}
+
+impl fmt::Display for JsonValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JsonValue::Null => write!(f, "null"),
+            JsonValue::Bool(value) => write!(f, "{}", value),
+            JsonValue::Integer(value) => write!(f, "{}", value),
+            JsonValue::Number(value) if value.is_finite() => write!(f, "{}", value),
+            JsonValue::Number(_) => write!(f, "null"),
+            JsonValue::String(value) => write!(f, "{}", quote(value)),
+        }
+    }
+}
+
+/// A progress event for `--progress json`, written as one line of JSON with its kind in the
+/// "event" field
+pub struct JsonEvent {
+    fields: String,
+}
+
+impl JsonEvent {
+    pub fn new(event: &str) -> Self {
+        Self {
+            fields: format!("\"event\":{}", quote(event)),
+        }
+    }
+
+    pub fn field(mut self, name: &str, value: impl Into<JsonValue>) -> Self {
+        self.fields += &format!(",{}:{}", quote(name), value.into());
+        self
+    }
+}
+
+impl fmt::Display for JsonEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{{}}}", self.fields)
+    }
+}
+
+/// A JSON string literal
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted += "\\\"",
+            '\\' => quoted += "\\\\",
+            '\n' => quoted += "\\n",
+            '\r' => quoted += "\\r",
+            '\t' => quoted += "\\t",
+            c if c.is_control() => quoted += &format!("\\u{:04x}", c as u32),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throughput() {
+        let mut throughput = Throughput::new();
+        assert_eq!(throughput.rate(), None);
+        assert_eq!(throughput.eta(100), None);
+
+        throughput.record(Duration::from_secs(2), 1000);
+        assert_eq!(throughput.rate(), Some(500.));
+        throughput.record(Duration::from_secs(3), 1000);
+        throughput.record(Duration::from_secs(4), 3000);
+        assert_eq!(throughput.rate(), Some(750.));
+        assert_eq!(throughput.eta(1500), Some(Duration::from_secs(2)));
+
+        // Only the most recent updates count
+        for i in 1..=WINDOW as u64 {
+            throughput.record(Duration::from_secs(4 + i), 3000 + 100 * i);
+        }
+        assert_eq!(throughput.rate(), Some(100.));
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(format_rate(950.), "950.0 px/s");
+        assert_eq!(format_rate(12_340_000.), "12.3 Mpx/s");
+        assert_eq!(format_duration(Duration::from_secs_f32(6.7)), "6s");
+        assert_eq!(format_duration(Duration::from_secs(245)), "4m05s");
+        assert_eq!(format_duration(Duration::from_secs(3723)), "1h02m03s");
+        assert_eq!("json".parse::<ProgressMode>().unwrap(), ProgressMode::Json);
+        assert!("xml".parse::<ProgressMode>().is_err());
+    }
+
+    #[test]
+    fn test_json_event() {
+        let event = JsonEvent::new("tile_done")
+            .field("frame", 3usize)
+            .field("eta", None::<f64>)
+            .field("rate", 1.5)
+            .field("stopped", false)
+            .field("message", "Said \"no\"\n\u{1}");
+        assert_eq!(
+            event.to_string(),
+            r#"{"event":"tile_done","frame":3,"eta":null,"rate":1.5,"stopped":false,"message":"Said \"no\"\n\u0001"}"#
+        );
+        assert_eq!(JsonValue::from(f64::NAN).to_string(), "null");
+    }
+}
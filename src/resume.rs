@@ -0,0 +1,180 @@
+use crate::output::verify_image;
+use crate::settings::{Existing, Settings};
+use crate::template::{output_path, Placeholders};
+use anyhow::{bail, Result};
+use std::path::Path;
+
+/// Frames to render, in order, or an error for a frame whose file must not be replaced
+pub type WorkOrder = Box<dyn Iterator<Item = Result<usize>> + Send>;
+
+/// Indices of the frames still to be rendered, in order. Frames whose output files already exist
+/// are skipped, or refused, as `Settings::existing` says. Finite renders check every frame up
+/// front; endless ones check each frame as it comes up. Only files that existed before the run are
+/// considered
+pub fn work_order(cfg: &Settings) -> Result<WorkOrder> {
+    let existing = cfg.existing();
+
+    // Videos and atlases are a single file, which is either all there or not
+    let single = match (&cfg.atlas, &cfg.video) {
+        (Some(path), _) => Some(Some(cfg.output.join(path))),
+        (None, Some(path)) if path == Path::new("-") => Some(None),
+        (None, Some(path)) => Some(Some(cfg.output.join(path))),
+        (None, None) => None,
+    };
+    if let Some(path) = single {
+        if existing == Existing::Resume {
+            bail!("Only image sequences can be resumed");
+        }
+        if let Some(path) = path.filter(|path| path.exists()) {
+            if existing != Existing::Overwrite {
+                bail!(
+                    "{} already exists, use --force to replace it",
+                    path.display()
+                );
+            }
+        }
+        return Ok(Box::new(cfg.frame_indices().map(Ok)));
+    }
+
+    // A pattern naming the same file for every frame is a single image, overwritten by each frame
+    // of this run, so only what was there before the run counts
+    let cfg = sequence_settings(cfg);
+    let frame_path = |frame_idx| output_path(&cfg, &Placeholders::frame(&cfg, frame_idx));
+    if frame_path(0)? == frame_path(1)? {
+        if is_done(&cfg, existing, 0)? {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        return Ok(Box::new(cfg.frame_indices().map(Ok)));
+    }
+
+    let endless = cfg.frame_count().is_none();
+    let frames =
+        cfg.frame_indices()
+            .filter_map(move |frame_idx| match is_done(&cfg, existing, frame_idx) {
+                Ok(true) => None,
+                Ok(false) => Some(Ok(frame_idx)),
+                Err(e) => Some(Err(e)),
+            });
+
+    if endless {
+        Ok(Box::new(frames))
+    } else {
+        let order = frames.collect::<Result<Vec<_>>>()?;
+        Ok(Box::new(order.into_iter().map(Ok)))
+    }
+}
+
+/// Whether the frame's file can be left as it is, or an error if it must not be replaced
+fn is_done(cfg: &Settings, existing: Existing, frame_idx: usize) -> Result<bool> {
+    let path = output_path(cfg, &Placeholders::frame(cfg, frame_idx))?;
+    Ok(match existing {
+        Existing::Refuse if path.exists() => bail!(
+            "{} already exists, use --resume, --no-clobber or --force",
+            path.display()
+        ),
+        Existing::Refuse | Existing::Overwrite => false,
+        Existing::Skip => path.exists(),
+        Existing::Resume => is_complete(cfg, &path),
+    })
+}
+
+/// Settings as seen by the image sequence; cropped, unless pasting into a full size base image
+fn sequence_settings(cfg: &Settings) -> Settings {
+    match cfg.crop_base {
+        Some(_) => cfg.clone(),
+        None => cfg.cropped(),
+    }
+}
+
+/// Whether `path` holds a whole frame that decodes
+fn is_complete(cfg: &Settings, path: &Path) -> bool {
+    path.exists() && verify_image(cfg, (cfg.width, cfg.height), path).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::output::write_image;
+    use structopt::StructOpt;
+
+    fn settings(dir: &Path, args: &[&str]) -> Settings {
+        let base = [
+            "bosrender",
+            "shaders/a_spot.frag",
+            "-w",
+            "4",
+            "-h",
+            "2",
+            "-p",
+            "%f.png",
+            "-o",
+        ];
+        let args = base
+            .iter()
+            .copied()
+            .chain(dir.to_str())
+            .chain(args.iter().copied());
+        Settings::from_iter(args)
+    }
+
+    /// Work order of a four frame render
+    fn order(dir: &Path, args: &[&str]) -> Vec<usize> {
+        let args: Vec<_> = ["-f", "4"].iter().chain(args).copied().collect();
+        work_order(&settings(dir, &args))
+            .unwrap()
+            .collect::<Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_existing_frames() {
+        let dir = std::env::temp_dir().join(format!("bosrender_resume_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let cfg = settings(&dir, &["-f", "4"]);
+
+        // Frame 1 is complete, frame 2 was cut off part way through and frame 3 is the wrong size
+        write_image(&cfg, (4, 2), &[7; 4 * 2 * 3], &dir.join("1.png")).unwrap();
+        let whole = std::fs::read(dir.join("1.png")).unwrap();
+        std::fs::write(dir.join("2.png"), &whole[..whole.len() - 20]).unwrap();
+        write_image(&cfg, (5, 2), &[7; 5 * 2 * 3], &dir.join("3.png")).unwrap();
+        assert!(!dir.join(".1.png.partial").exists());
+
+        assert!(work_order(&cfg).is_err());
+        assert_eq!(order(&dir, &["--resume"]), vec![0, 2, 3]);
+        assert_eq!(order(&dir, &["--no-clobber"]), vec![0]);
+        assert_eq!(order(&dir, &["--force"]), vec![0, 1, 2, 3]);
+
+        // Endless renders only stop at an existing frame once they get to it
+        let mut endless = work_order(&settings(&dir, &["-f", "0"])).unwrap();
+        assert_eq!(endless.next().unwrap().unwrap(), 0);
+        assert!(endless.next().unwrap().is_err());
+        let endless = work_order(&settings(&dir, &["-f", "0", "--no-clobber"])).unwrap();
+        let indices: Vec<_> = endless.take(3).map(Result::unwrap).collect();
+        assert_eq!(indices, vec![0, 4, 5]);
+
+        // A fixed name is one file, refused once if it's already there and then written each frame
+        let latest = |args: &[&str]| {
+            let mut cfg = settings(&dir, args);
+            cfg.pattern = "latest.png".into();
+            cfg
+        };
+        let mut endless = work_order(&latest(&["-f", "0"])).unwrap();
+        assert_eq!(endless.nth(2).unwrap().unwrap(), 2);
+        write_image(&cfg, (4, 2), &[7; 4 * 2 * 3], &dir.join("latest.png")).unwrap();
+        assert!(work_order(&latest(&["-f", "0"])).is_err());
+        assert_eq!(
+            work_order(&latest(&["-f", "4", "--no-clobber"]))
+                .unwrap()
+                .count(),
+            0
+        );
+        assert_eq!(
+            work_order(&latest(&["-f", "4", "--force"]))
+                .unwrap()
+                .count(),
+            4
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,248 @@
+use crate::tiles::{tiles, Rect};
+use std::time::Duration;
+
+/// Smallest tile side the scheduler will split down to
+const MIN_TILE_SIDE: u32 = 16;
+
+/// Splits the rendered region into the tiles of each frame. Without a target, this is the fixed
+/// grid of `tiles::tiles`. With one, tiles whose submission took longer than the target in an
+/// earlier frame are halved, and halves which together took well under it are merged again. Tiles
+/// never grow past the grid cells, which are as large as the render target
+pub struct TileScheduler {
+    target: Option<Duration>,
+    cells: Vec<Node>,
+}
+
+/// A tile, or a tile split in two
+struct Node {
+    rect: Rect,
+    /// Measured time of the last submission, for leaves
+    time: Option<Duration>,
+    children: Option<Box<(Node, Node)>>,
+}
+
+impl TileScheduler {
+    pub fn new(
+        region: Rect,
+        (tile_width, tile_height): (u32, u32),
+        target: Option<Duration>,
+    ) -> Self {
+        let cells = tiles(
+            (region.width as usize, region.height as usize),
+            (tile_width as usize, tile_height as usize),
+        )
+        .into_iter()
+        .map(|(x, y)| {
+            let (x, y) = (x as u32, y as u32);
+            Node::leaf(Rect {
+                x: region.x + x,
+                y: region.y + y,
+                width: tile_width.min(region.width - x),
+                height: tile_height.min(region.height - y),
+            })
+        })
+        .collect();
+
+        Self { target, cells }
+    }
+
+    /// Record how long the submission of `rect` took on the GPU
+    pub fn record(&mut self, rect: Rect, time: Duration) {
+        let contains = |node: &Node| {
+            (node.rect.x..node.rect.x + node.rect.width).contains(&rect.x)
+                && (node.rect.y..node.rect.y + node.rect.height).contains(&rect.y)
+        };
+
+        let mut node = match self.cells.iter_mut().find(|cell| contains(cell)) {
+            Some(cell) => cell,
+            None => return,
+        };
+        while let Some(children) = &mut node.children {
+            node = if contains(&children.0) {
+                &mut children.0
+            } else {
+                &mut children.1
+            };
+        }
+
+        // Tiles from before the last adaptation no longer match
+        if node.rect == rect {
+            node.time = Some(time);
+        }
+    }
+
+    /// Adapt to the times recorded so far, and list the tiles of the next frame in order
+    pub fn next_frame(&mut self) -> Vec<Rect> {
+        if let Some(target) = self.target {
+            for cell in &mut self.cells {
+                cell.adapt(target);
+            }
+        }
+
+        let mut rects = vec![];
+        for cell in &self.cells {
+            cell.leaves(&mut rects);
+        }
+        rects
+    }
+}
+
+impl Node {
+    fn leaf(rect: Rect) -> Self {
+        Self {
+            rect,
+            time: None,
+            children: None,
+        }
+    }
+
+    fn adapt(&mut self, target: Duration) {
+        match &mut self.children {
+            None => {
+                if matches!(self.time.take(), Some(time) if time > target) {
+                    self.split();
+                }
+            }
+            Some(children) => {
+                let (first, second) = &mut **children;
+                let both_leaves = first.children.is_none() && second.children.is_none();
+                match (first.time, second.time) {
+                    // Merge below half the target, so that the merged tile isn't split right away
+                    (Some(a), Some(b)) if both_leaves && (a + b) * 2 < target => {
+                        self.children = None;
+                        self.time = None;
+                    }
+                    _ => {
+                        first.adapt(target);
+                        second.adapt(target);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Halve along the longer side, unless that would make tiles too small
+    fn split(&mut self) {
+        let Rect {
+            x,
+            y,
+            width,
+            height,
+        } = self.rect;
+
+        let children = if width >= height && width >= 2 * MIN_TILE_SIDE {
+            let half = width / 2;
+            (
+                Rect {
+                    width: half,
+                    ..self.rect
+                },
+                Rect {
+                    x: x + half,
+                    width: width - half,
+                    ..self.rect
+                },
+            )
+        } else if height >= 2 * MIN_TILE_SIDE {
+            let half = height / 2;
+            (
+                Rect {
+                    height: half,
+                    ..self.rect
+                },
+                Rect {
+                    y: y + half,
+                    height: height - half,
+                    ..self.rect
+                },
+            )
+        } else {
+            return;
+        };
+
+        self.children = Some(Box::new((Node::leaf(children.0), Node::leaf(children.1))));
+    }
+
+    fn leaves(&self, out: &mut Vec<Rect>) {
+        match &self.children {
+            Some(children) => {
+                children.0.leaves(out);
+                children.1.leaves(out);
+            }
+            None => out.push(self.rect),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: u32, y: u32, width: u32, height: u32) -> Rect {
+        Rect {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    fn area(rects: &[Rect]) -> u32 {
+        rects.iter().map(|r| r.width * r.height).sum()
+    }
+
+    #[test]
+    fn test_fixed_grid() {
+        let mut scheduler = TileScheduler::new(rect(10, 20, 100, 50), (64, 64), None);
+        let expected = vec![rect(10, 20, 64, 50), rect(74, 20, 36, 50)];
+        assert_eq!(scheduler.next_frame(), expected);
+
+        // Without a target, times are ignored
+        scheduler.record(expected[0], Duration::from_secs(10));
+        assert_eq!(scheduler.next_frame(), expected);
+    }
+
+    #[test]
+    fn test_split_and_merge() {
+        let target = Duration::from_millis(100);
+        let mut scheduler = TileScheduler::new(rect(0, 0, 256, 128), (256, 128), Some(target));
+        let slow = Duration::from_millis(300);
+        let fast = Duration::from_millis(10);
+
+        // A slow tile is halved along its longer side
+        let tiles = scheduler.next_frame();
+        scheduler.record(tiles[0], slow);
+        let tiles = scheduler.next_frame();
+        assert_eq!(tiles, vec![rect(0, 0, 128, 128), rect(128, 0, 128, 128)]);
+
+        // Only the half that is still slow splits again
+        scheduler.record(tiles[0], slow);
+        scheduler.record(tiles[1], fast);
+        let tiles = scheduler.next_frame();
+        assert_eq!(tiles.len(), 3);
+        assert_eq!(area(&tiles), 256 * 128);
+
+        // Quick halves merge back
+        for &tile in &tiles {
+            scheduler.record(tile, fast);
+        }
+        let tiles = scheduler.next_frame();
+        assert_eq!(tiles, vec![rect(0, 0, 128, 128), rect(128, 0, 128, 128)]);
+    }
+
+    #[test]
+    fn test_minimum_size() {
+        let target = Duration::from_millis(1);
+        let mut scheduler = TileScheduler::new(rect(0, 0, 40, 20), (40, 20), Some(target));
+        for _ in 0..8 {
+            for tile in scheduler.next_frame() {
+                scheduler.record(tile, Duration::from_secs(1));
+            }
+        }
+        let tiles = scheduler.next_frame();
+        assert!(tiles
+            .iter()
+            .all(|t| t.width >= MIN_TILE_SIDE && t.height >= MIN_TILE_SIDE));
+        assert_eq!(area(&tiles), 40 * 20);
+    }
+}
@@ -1,4 +1,16 @@
-use std::path::PathBuf;
+use crate::dither::Dither;
+use crate::dzi::TileFormat;
+use crate::filter::Filter;
+use crate::quantize::Quantizer;
+use crate::ranges::FrameRanges;
+use crate::remap::Remap;
+use crate::report::ProgressMode;
+use crate::tiles::Rect;
+use crate::uniforms::Uniform;
+use crate::view::View;
+use anyhow::{bail, Error};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use structopt::StructOpt;
 
 #[derive(StructOpt, Debug, Clone)]
@@ -13,32 +25,71 @@ pub struct Settings {
 
     /// First frame to render
     #[structopt(long, default_value = "0")]
-    pub first_frame: usize, // TODO: Maybe this should be a list of ranges...
+    pub first_frame: usize,
 
-    /// Number of frames to render. Infinite if 0
+    /// Number of frames to render. Endless if 0, until interrupted with Ctrl-C
     #[structopt(short, long, default_value = "1")]
-    pub frames: usize, // TODO: Maybe this should be a list of ranges...
+    pub frames: usize,
 
-    /// Number of frames to render. Infinite if 0
+    /// Frames to render instead of --first-frame and --frames, as comma separated indices and
+    /// inclusive ranges with an optional step, e.g. "0-99,150,200-400:5". Frames keep the times
+    /// and file names they have in a full render
+    #[structopt(long, value_name = "ranges", conflicts_with_all = &["first-frame", "frames"])]
+    pub frame_ranges: Option<FrameRanges>,
+
+    /// Tiles each device can have submitted to the GPU at once, waiting to be downloaded
     #[structopt(short = "l", long, default_value = "3")]
-    pub frames_in_flight: usize, // TODO: Maybe this should be a list of ranges...
+    pub frames_in_flight: usize,
 
     /// How much to increment `anim` by each frame
     #[structopt(short, long, default_value = "0.01666")]
     pub rate: f32,
 
-    /*
-    /// Output format. Replaces $f with the file stem of the shader path and $i with the stem of
-    /// the shader path. If $f is not found in the pattern, the pattern is assumed to end with "_%f"
-    #[structopt(long, value_name="pattern", default_value = "%i_%f.png")]
-    */
+    /// Output file name pattern, relative to the output directory. The extension selects the
+    /// image format. Replaces %n with the name, %s with the file stem of the shader path, %f with
+    /// the frame index, %t with the time, %w, %h and %r with the width, height and resolution,
+    /// %T with the tile index and %S with the seed. A width may follow the %, e.g. %4f pads the
+    /// frame index to 4 digits and %2t prints the time with 2 decimal places
+    #[structopt(long, short, value_name = "pattern", default_value = "%n_%4f.png")]
+    pub pattern: String,
+
+    /// Name substituted for %n in the output pattern
     #[structopt(long, short, default_value = "out")]
     pub name: String,
 
-    /// Output directory
+    /// Output directory. Created if missing
     #[structopt(short, long, default_value = "")]
     pub output: PathBuf,
 
+    /// Skip frames whose output files are already complete, checking that each decodes, and
+    /// render the rest
+    #[structopt(long, conflicts_with_all = &["no-clobber", "force"])]
+    pub resume: bool,
+
+    /// Skip frames whose output files already exist, without checking them
+    #[structopt(long, conflicts_with = "force")]
+    pub no_clobber: bool,
+
+    /// Overwrite existing output files. Otherwise nothing is rendered if any exist, unless
+    /// --resume or --no-clobber is given
+    #[structopt(long)]
+    pub force: bool,
+
+    /// Keep only this many of the latest frame images, deleting older ones as new frames are
+    /// written. Useful for watching endless renders
+    #[structopt(long, value_name = "count")]
+    pub keep_last: Option<usize>,
+
+    /// Serve frames to bosrender-worker processes connecting to this address, e.g.
+    /// "0.0.0.0:7878", instead of rendering them here
+    #[structopt(long, value_name = "address")]
+    pub coordinator: Option<String>,
+
+    /// Random seed, substituted for %S in the output pattern. Progressive samples each derive
+    /// their own `u_seed` from it
+    #[structopt(long, default_value = "0")]
+    pub seed: u32,
+
     /// Fragment shader path
     pub shader: PathBuf,
 
@@ -46,6 +97,26 @@ pub struct Settings {
     #[structopt(long)]
     pub validation: bool,
 
+    /// How to show progress: "line" for a status line, or "json" for a JSON object per event,
+    /// one per line, on the same stream
+    #[structopt(long, value_name = "mode", default_value = "line")]
+    pub progress: ProgressMode,
+
+    /// Vulkan devices to render on, by their index in the order the Vulkan loader lists them,
+    /// e.g. "0,1". Tiles are spread across them by how fast each renders them. If not given, a
+    /// single device is picked automatically
+    #[structopt(long, value_name = "indices", use_delimiter = true)]
+    pub devices: Vec<usize>,
+
+    /// Seconds to wait for a tile on the GPU before giving up on it and rebuilding the device
+    #[structopt(long, value_name = "seconds", default_value = "5")]
+    pub gpu_timeout: f32,
+
+    /// Times to render a tile again after it timed out or lost the device, each time on a
+    /// rebuilt device and split into smaller pieces
+    #[structopt(long, value_name = "count", default_value = "3")]
+    pub gpu_retries: u32,
+
     /// Tile width
     #[structopt(long)]
     pub tile_width: Option<u32>,
@@ -53,4 +124,374 @@ pub struct Settings {
     /// Tile height
     #[structopt(long)]
     pub tile_height: Option<u32>,
+
+    /// Adapt tiles between frames so that each takes about this many milliseconds on the GPU,
+    /// splitting slow tiles and merging quick ones. The tile size becomes the largest allowed
+    #[structopt(long, value_name = "ms")]
+    pub tile_target_ms: Option<f32>,
+
+    /// Render only this region of the image, given as x,y,width,height from the top left. The
+    /// shader still sees the full resolution
+    #[structopt(long, value_name = "x,y,w,h")]
+    pub crop: Option<Rect>,
+
+    /// Existing full size PNG to paste the cropped region into, writing the whole canvas instead
+    /// of just the region
+    #[structopt(long, value_name = "image")]
+    pub crop_base: Option<PathBuf>,
+
+    /// Bits per channel of the render target and output images (8 or 16)
+    #[structopt(long, default_value = "8")]
+    pub depth: BitDepth,
+
+    /// Dither 8-bit output down from 16 bits: "bayer", "blue-noise" or "error-diffusion". The
+    /// ordered patterns are fixed to the image, so they don't shimmer between frames
+    #[structopt(long, value_name = "method")]
+    pub dither: Option<Dither>,
+
+    /// Compress TIFF output with deflate
+    #[structopt(long)]
+    pub tiff_deflate: bool,
+
+    /// Keep the alpha channel, clear to a transparent background and write RGBA images. Alpha is
+    /// either "straight" or "premultiplied"
+    #[structopt(long, value_name = "mode")]
+    pub alpha: Option<AlphaMode>,
+
+    /// Write frames to a video instead of images. A ".y4m" path or "-" (stdout) is written as
+    /// YUV4MPEG2, ".gif" and ".apng"/".png" as animations, anything else is encoded by ffmpeg
+    #[structopt(long, value_name = "path")]
+    pub video: Option<PathBuf>,
+
+    /// Video frame rate. Defaults to 1 / rate
+    #[structopt(long)]
+    pub fps: Option<f32>,
+
+    /// ffmpeg executable used for video encoding
+    #[structopt(long, default_value = "ffmpeg")]
+    pub ffmpeg: PathBuf,
+
+    /// ffmpeg video codec
+    #[structopt(long, default_value = "libx264")]
+    pub codec: String,
+
+    /// ffmpeg constant rate factor, for codecs that have one such as libx264. Left to the codec
+    /// if not given, as codecs like prores, ffv1 and png reject it
+    #[structopt(long)]
+    pub crf: Option<u32>,
+
+    /// ffmpeg output pixel format
+    #[structopt(long, default_value = "yuv420p")]
+    pub pix_fmt: String,
+
+    /// Audio track to mux into the ffmpeg output
+    #[structopt(long)]
+    pub audio: Option<PathBuf>,
+
+    /// Number of times GIF and APNG animations play. Loops forever if 0
+    #[structopt(long, default_value = "0")]
+    pub loop_count: u16,
+
+    /// GIF palette, either "global" (shared by all frames) or "per-frame"
+    #[structopt(long, default_value = "global")]
+    pub gif_palette: PaletteMode,
+
+    /// GIF palette generation, either "median-cut" or "octree"
+    #[structopt(long, default_value = "median-cut")]
+    pub quantizer: Quantizer,
+
+    /// Dither GIF frames against their palette
+    #[structopt(long)]
+    pub gif_dither: bool,
+
+    /// Write all frames into a single grid image instead, alongside a JSON descriptor of the
+    /// frame rectangles and timings
+    #[structopt(long, value_name = "path")]
+    pub atlas: Option<PathBuf>,
+
+    /// Atlas columns. Defaults to a roughly square grid
+    #[structopt(long)]
+    pub atlas_columns: Option<usize>,
+
+    /// Scale applied to each frame placed in the atlas
+    #[structopt(long, default_value = "1")]
+    pub atlas_scale: f32,
+
+    /// Space in pixels around each atlas cell
+    #[structopt(long, default_value = "0")]
+    pub atlas_padding: usize,
+
+    /// Label each atlas cell with its frame index
+    #[structopt(long)]
+    pub atlas_labels: bool,
+
+    /// Stream each row of tiles straight into the image encoder instead of assembling whole
+    /// frames in memory, for very large PNG and TIFF renders. Defaults to 4096x256 tiles
+    #[structopt(long)]
+    pub stream: bool,
+
+    /// Edge length of Deep Zoom tiles, written when the output pattern ends in ".dzi"
+    #[structopt(long, default_value = "254")]
+    pub dzi_tile_size: usize,
+
+    /// Pixels each Deep Zoom tile overlaps its neighbours by
+    #[structopt(long, default_value = "1")]
+    pub dzi_overlap: usize,
+
+    /// Deep Zoom tile format, either "jpg" or "png"
+    #[structopt(long, default_value = "jpg")]
+    pub dzi_format: TileFormat,
+
+    /// Quality of JPEG tiles, from 1 to 100
+    #[structopt(long, default_value = "90")]
+    pub jpeg_quality: u8,
+
+    /// Map output pixels to shader coordinates non-linearly: "fisheye[:fov]" for a dome master,
+    /// "equirect[:hfov,vfov]" for a 360 panorama, "cylindrical[:hfov]", "polar[:radius]" to unwrap
+    /// around the center, "kaleidoscope[:segments]" or "mirror". Angles are in degrees
+    #[structopt(long, value_name = "mapping")]
+    pub remap: Option<Remap>,
+
+    /// Leave pixels outside the remap's valid domain (e.g. beyond the dome circle) blank
+    #[structopt(long)]
+    pub remap_mask: bool,
+
+    /// Transform the coordinates passed to the shader, either as a 2x3 matrix "a,b,c,d,e,f"
+    /// mapping (x, y) to (ax + by + c, dx + ey + f), or as "zoom=4,center=x:y,rotate=30,mirror"
+    /// with any of the parts omitted. Coordinates are in pixels with y up
+    #[structopt(long, value_name = "transform")]
+    pub view: Option<View>,
+
+    /// Fix a uniform the shader declares to a constant, as name=value with the components of
+    /// vectors separated by commas, e.g. "u_color=1,0.5,0". May be given several times
+    #[structopt(long = "uniform", value_name = "name=value", number_of_values = 1)]
+    pub uniforms: Vec<Uniform>,
+
+    /// Supersampling factor. Each tile is rendered at this many times the resolution on each axis
+    /// and filtered down
+    #[structopt(long, default_value = "1")]
+    pub ssaa: u32,
+
+    /// Filter used to downsample supersampled tiles: "box", "tent", "mitchell" or "lanczos"
+    #[structopt(long, default_value = "mitchell")]
+    pub ssaa_filter: Filter,
+
+    /// Average this many renders spread across the shutter interval into each frame
+    #[structopt(long, value_name = "samples", default_value = "1")]
+    pub motion_blur: u32,
+
+    /// Fraction of the frame interval the shutter is open for, in degrees. 360 blurs across the
+    /// whole interval
+    #[structopt(long, default_value = "180")]
+    pub shutter_angle: f32,
+
+    /// Samples accumulated into each frame for progressive rendering. Each sample is rendered with
+    /// its own `u_sample` index and `u_seed`
+    #[structopt(long, default_value = "1")]
+    pub samples: u32,
+
+    /// Stop adding samples to a frame after this many seconds
+    #[structopt(long, value_name = "seconds")]
+    pub sample_budget: Option<f32>,
+
+    /// Write the partially accumulated frame every this many samples
+    #[structopt(long, value_name = "samples")]
+    pub snapshot_every: Option<u32>,
+
+    /// Pattern for progressive snapshots, as for --pattern with %c replaced by the sample count
+    #[structopt(long, value_name = "pattern", default_value = "%n_%4f_%4c.png")]
+    pub snapshot_pattern: String,
+
+    /// GPU memory each device may keep progressive running sums in, in MiB. Every tile of a
+    /// progressive frame keeps a float sum of its samples on its device until the frame is done
+    #[structopt(long, value_name = "MiB", default_value = "4096")]
+    pub sum_memory: u64,
+}
+
+impl Settings {
+    /// Region of the image being rendered
+    pub fn region(&self) -> Rect {
+        self.crop.unwrap_or(Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
+        })
+    }
+
+    /// These settings as seen by outputs that receive only the rendered region
+    pub fn cropped(&self) -> Settings {
+        let region = self.region();
+        Settings {
+            width: region.width,
+            height: region.height,
+            ..self.clone()
+        }
+    }
+
+    /// Whether frames are refined over several samples
+    pub fn progressive(&self) -> bool {
+        self.samples > 1
+    }
+
+    /// What to do about output files that already exist
+    pub fn existing(&self) -> Existing {
+        if self.resume {
+            Existing::Resume
+        } else if self.no_clobber {
+            Existing::Skip
+        } else if self.force {
+            Existing::Overwrite
+        } else {
+            Existing::Refuse
+        }
+    }
+
+    /// Indices of the frames to render, in order. Endless if `frames` is 0
+    pub fn frame_indices(&self) -> Box<dyn Iterator<Item = usize> + Send> {
+        match (&self.frame_ranges, self.frames) {
+            (Some(ranges), _) => Box::new(ranges.indices().into_iter()),
+            (None, 0) => Box::new(self.first_frame..),
+            (None, frames) => Box::new((self.first_frame..).take(frames)),
+        }
+    }
+
+    /// Number of frames to render, or `None` if endless
+    pub fn frame_count(&self) -> Option<usize> {
+        match (&self.frame_ranges, self.frames) {
+            (Some(ranges), _) => Some(ranges.indices().len()),
+            (None, 0) => None,
+            (None, frames) => Some(frames),
+        }
+    }
+
+    /// Shader time for the given frame index
+    pub fn frame_time(&self, frame_idx: usize) -> f32 {
+        self.rate * frame_idx as f32
+    }
+
+    /// Playback frame rate as a fraction, either from `fps` or derived from `rate`
+    pub fn frame_rate(&self) -> (u32, u32) {
+        let fps = self.fps.unwrap_or(1. / self.rate);
+        if (fps - fps.round()).abs() < 0.05 {
+            (fps.round() as u32, 1)
+        } else {
+            ((fps * 1000.).round() as u32, 1000)
+        }
+    }
+}
+
+/// Bits per color channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BitDepth {
+    Eight,
+    Sixteen,
+}
+
+impl BitDepth {
+    pub fn bytes_per_channel(self) -> usize {
+        match self {
+            BitDepth::Eight => 1,
+            BitDepth::Sixteen => 2,
+        }
+    }
+}
+
+impl FromStr for BitDepth {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(BitDepth::Eight),
+            "16" => Ok(BitDepth::Sixteen),
+            _ => bail!("Unsupported bit depth \"{}\", expected 8 or 16", s),
+        }
+    }
+}
+
+/// Policy for output files left by an earlier render
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+    /// Stop before rendering anything
+    Refuse,
+    /// Skip frames whose files exist
+    Skip,
+    /// Skip frames whose files are complete, re-rendering damaged ones
+    Resume,
+    /// Render everything, replacing the files
+    Overwrite,
+}
+
+/// How color relates to alpha in RGBA output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlphaMode {
+    /// Color is independent of alpha, as the shader wrote it
+    Straight,
+    /// Color has been multiplied by alpha
+    Premultiplied,
+}
+
+impl FromStr for AlphaMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "straight" => Ok(AlphaMode::Straight),
+            "premultiplied" => Ok(AlphaMode::Premultiplied),
+            _ => bail!(
+                "Unknown alpha mode \"{}\", expected straight or premultiplied",
+                s
+            ),
+        }
+    }
+}
+
+/// Whether GIF frames share one palette
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaletteMode {
+    Global,
+    PerFrame,
+}
+
+impl FromStr for PaletteMode {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "global" => Ok(PaletteMode::Global),
+            "per-frame" => Ok(PaletteMode::PerFrame),
+            _ => bail!(
+                "Unknown palette mode \"{}\", expected global or per-frame",
+                s
+            ),
+        }
+    }
+}
+
+/// Image file formats we know how to write
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Tiff,
+    /// Deep Zoom descriptor, with a pyramid of tiles alongside
+    Dzi,
+}
+
+impl ImageFormat {
+    /// Determine the format from a file's extension
+    pub fn from_path(path: &Path) -> Result<Self, Error> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) => ext.parse(),
+            None => bail!("Output path \"{}\" has no extension", path.display()),
+        }
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "tif" | "tiff" => Ok(ImageFormat::Tiff),
+            "dzi" => Ok(ImageFormat::Dzi),
+            _ => bail!("Unsupported image format \"{}\"", s),
+        }
+    }
 }
@@ -0,0 +1,157 @@
+use crate::settings::Settings;
+use anyhow::{bail, Result};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// Per-file values substituted into an output pattern
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Placeholders {
+    pub frame_idx: usize,
+    pub time: f32,
+    pub tile_idx: usize,
+    pub samples: u32,
+}
+
+impl Placeholders {
+    /// Values for a whole frame
+    pub fn frame(cfg: &Settings, frame_idx: usize) -> Self {
+        Self {
+            frame_idx,
+            time: cfg.frame_time(frame_idx),
+            tile_idx: 0,
+            samples: 0,
+        }
+    }
+}
+
+/// Expand `pattern` as described for `Settings::pattern`
+pub fn expand(pattern: &str, cfg: &Settings, values: &Placeholders) -> Result<String> {
+    let mut out = String::new();
+    let mut chars = pattern.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+
+        let mut width = String::new();
+        while let Some(&digit) = chars.peek().filter(|c| c.is_ascii_digit()) {
+            width.push(digit);
+            chars.next();
+        }
+        let width: Option<usize> = width.parse().ok();
+        let pad = width.unwrap_or(0);
+
+        match chars.next() {
+            Some('%') => out.push('%'),
+            Some('n') => out.push_str(&cfg.name),
+            Some('s') => out.push_str(&shader_stem(cfg)),
+            Some('f') => write!(out, "{:0pad$}", values.frame_idx, pad = pad)?,
+            Some('t') => write!(out, "{:.prec$}", values.time, prec = width.unwrap_or(3))?,
+            Some('w') => write!(out, "{:0pad$}", cfg.width, pad = pad)?,
+            Some('h') => write!(out, "{:0pad$}", cfg.height, pad = pad)?,
+            Some('r') => write!(out, "{}x{}", cfg.width, cfg.height)?,
+            Some('T') => write!(out, "{:0pad$}", values.tile_idx, pad = pad)?,
+            Some('S') => write!(out, "{:0pad$}", cfg.seed, pad = pad)?,
+            Some('c') => write!(out, "{:0pad$}", values.samples, pad = pad)?,
+            Some(other) => bail!("Unknown placeholder %{} in pattern \"{}\"", other, pattern),
+            None => bail!(
+                "Pattern \"{}\" ends with an incomplete placeholder",
+                pattern
+            ),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Expand the configured pattern into a path within the output directory
+pub fn output_path(cfg: &Settings, values: &Placeholders) -> Result<PathBuf> {
+    Ok(cfg.output.join(expand(&cfg.pattern, cfg, values)?))
+}
+
+/// Expand the progressive snapshot pattern into a path within the output directory
+pub fn snapshot_path(cfg: &Settings, values: &Placeholders) -> Result<PathBuf> {
+    Ok(cfg.output.join(expand(&cfg.snapshot_pattern, cfg, values)?))
+}
+
+fn shader_stem(cfg: &Settings) -> String {
+    cfg.shader
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structopt::StructOpt;
+
+    fn settings(args: &[&str]) -> Settings {
+        let args = ["bosrender", "shaders/a_spot.frag"].iter().chain(args);
+        Settings::from_iter(args)
+    }
+
+    #[test]
+    fn test_default_pattern() {
+        let cfg = settings(&[]);
+        let path = output_path(&cfg, &Placeholders::frame(&cfg, 12)).unwrap();
+        assert_eq!(path, PathBuf::from("out_0012.png"));
+    }
+
+    #[test]
+    fn test_placeholders() {
+        let cfg = settings(&["-w", "640", "-h", "480", "--seed", "7", "--rate", "0.5"]);
+        let values = Placeholders {
+            tile_idx: 3,
+            ..Placeholders::frame(&cfg, 5)
+        };
+        let expanded = expand("%s/%r_%w_%h/%3f_%2t_%T_%2S_%%.tiff", &cfg, &values).unwrap();
+        assert_eq!(expanded, "a_spot/640x480_640_480/005_2.50_3_07_%.tiff");
+    }
+
+    #[test]
+    fn test_frames_match_full_render() {
+        // Time and name depend only on the frame index, however the frames were selected
+        let full = settings(&["--rate", "0.5", "-f", "20"]);
+        let offset = settings(&["--rate", "0.5", "--first-frame", "10"]);
+        let ranges = settings(&["--rate", "0.5", "--frame-ranges", "0-5,12"]);
+        let indices: Vec<_> = ranges.frame_indices().collect();
+        assert_eq!(indices, vec![0, 1, 2, 3, 4, 5, 12]);
+        for cfg in &[&offset, &ranges] {
+            let values = Placeholders::frame(cfg, 12);
+            assert_eq!(values.time, 6.);
+            assert_eq!(
+                output_path(cfg, &values).unwrap(),
+                output_path(&full, &Placeholders::frame(&full, 12)).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn test_snapshot_path() {
+        let cfg = settings(&["--samples", "64"]);
+        let values = Placeholders {
+            samples: 16,
+            ..Placeholders::frame(&cfg, 2)
+        };
+        let path = snapshot_path(&cfg, &values).unwrap();
+        assert_eq!(path, PathBuf::from("out_0002_0016.png"));
+    }
+
+    #[test]
+    fn test_output_directory() {
+        let cfg = settings(&["-o", "renders", "-p", "%f.png"]);
+        let path = output_path(&cfg, &Placeholders::frame(&cfg, 1)).unwrap();
+        assert_eq!(path, PathBuf::from("renders/1.png"));
+    }
+
+    #[test]
+    fn test_bad_placeholders() {
+        let cfg = settings(&[]);
+        let values = Placeholders::default();
+        assert!(expand("%q.png", &cfg, &values).is_err());
+        assert!(expand("frame_%4", &cfg, &values).is_err());
+    }
+}
@@ -0,0 +1,561 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use miniz_oxide::inflate::stream::{inflate, InflateState};
+use miniz_oxide::{DataFormat, MZError, MZFlush, MZStatus};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Approximate size of each strip before compression
+const STRIP_SIZE_BYTES: usize = 64 * 1024;
+
+/// Images whose raw pixel data exceeds this are written as BigTIFF, leaving room for the directory
+const CLASSIC_LIMIT_BYTES: u64 = u32::MAX as u64 - (64 << 20);
+
+// Tag field types
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const LONG8: u16 = 16;
+
+/// Minimal baseline TIFF encoder for interleaved RGB(A) images at 8 or 16 bits per channel. Rows
+/// are accepted in order and written out in strips, so the whole image never needs to be in memory.
+/// Images too large for 32-bit offsets are written as BigTIFF.
+pub struct TiffWriter<W: Write + Seek> {
+    writer: W,
+    width: u32,
+    height: u32,
+    channels: u16,
+    bytes_per_channel: usize,
+    deflate: bool,
+    big: bool,
+    associated_alpha: bool,
+    rows_per_strip: u32,
+    strip_buf: Vec<u8>,
+    strip_offsets: Vec<u64>,
+    strip_byte_counts: Vec<u64>,
+    rows_written: u32,
+    position: u64,
+}
+
+impl<W: Write + Seek> TiffWriter<W> {
+    /// Write the header and prepare to receive rows. `bytes_per_channel` must be 1 or 2
+    pub fn new(
+        writer: W,
+        width: u32,
+        height: u32,
+        channels: u16,
+        bytes_per_channel: usize,
+        deflate: bool,
+    ) -> Result<Self> {
+        let raw_size = width as u64 * height as u64 * channels as u64 * bytes_per_channel as u64;
+        let big = raw_size > CLASSIC_LIMIT_BYTES;
+        Self::create(
+            writer,
+            width,
+            height,
+            channels,
+            bytes_per_channel,
+            deflate,
+            big,
+        )
+    }
+
+    fn create(
+        mut writer: W,
+        width: u32,
+        height: u32,
+        channels: u16,
+        bytes_per_channel: usize,
+        deflate: bool,
+        big: bool,
+    ) -> Result<Self> {
+        ensure!(
+            bytes_per_channel == 1 || bytes_per_channel == 2,
+            "TIFF output supports 8 or 16 bits per channel"
+        );
+
+        // Little-endian header; the IFD offset is patched in `finish()`
+        writer.write_all(b"II")?;
+        let position = if big {
+            writer.write_all(&43u16.to_le_bytes())?;
+            writer.write_all(&8u16.to_le_bytes())?;
+            writer.write_all(&0u16.to_le_bytes())?;
+            writer.write_all(&0u64.to_le_bytes())?;
+            16
+        } else {
+            writer.write_all(&42u16.to_le_bytes())?;
+            writer.write_all(&0u32.to_le_bytes())?;
+            8
+        };
+
+        let row_bytes = width as usize * channels as usize * bytes_per_channel;
+        let rows_per_strip = (STRIP_SIZE_BYTES / row_bytes.max(1)).max(1) as u32;
+
+        Ok(Self {
+            writer,
+            width,
+            height,
+            channels,
+            bytes_per_channel,
+            deflate,
+            big,
+            associated_alpha: false,
+            rows_per_strip,
+            strip_buf: Vec::with_capacity(rows_per_strip as usize * row_bytes),
+            strip_offsets: vec![],
+            strip_byte_counts: vec![],
+            rows_written: 0,
+            position,
+        })
+    }
+
+    /// Mark the fourth channel of RGBA images as premultiplied (associated) rather than straight
+    pub fn set_associated_alpha(&mut self, associated: bool) {
+        self.associated_alpha = associated;
+    }
+
+    fn row_bytes(&self) -> usize {
+        self.width as usize * self.channels as usize * self.bytes_per_channel
+    }
+
+    /// Append one or more whole rows. 16-bit samples are expected in native byte order
+    pub fn write_rows(&mut self, data: &[u8]) -> Result<()> {
+        let row_bytes = self.row_bytes();
+        let rows = data.chunks_exact(row_bytes);
+        ensure!(
+            rows.remainder().is_empty(),
+            "Partial row passed to TIFF writer"
+        );
+
+        for row in rows {
+            ensure!(
+                self.rows_written < self.height,
+                "Too many rows passed to TIFF writer"
+            );
+
+            match self.bytes_per_channel {
+                1 => self.strip_buf.extend_from_slice(row),
+                _ => {
+                    for sample in row.chunks_exact(2) {
+                        let sample = u16::from_ne_bytes([sample[0], sample[1]]);
+                        self.strip_buf.extend_from_slice(&sample.to_le_bytes());
+                    }
+                }
+            }
+
+            self.rows_written += 1;
+            let strip_rows = self.strip_buf.len() / row_bytes;
+            if strip_rows == self.rows_per_strip as usize || self.rows_written == self.height {
+                self.flush_strip()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn flush_strip(&mut self) -> Result<()> {
+        let compressed;
+        let strip: &[u8] = if self.deflate {
+            compressed = miniz_oxide::deflate::compress_to_vec_zlib(&self.strip_buf, 6);
+            &compressed
+        } else {
+            &self.strip_buf
+        };
+
+        self.strip_offsets.push(self.position);
+        self.strip_byte_counts.push(strip.len() as u64);
+        self.writer.write_all(strip)?;
+        self.position += strip.len() as u64;
+        self.strip_buf.clear();
+
+        ensure!(
+            self.big || self.position <= CLASSIC_LIMIT_BYTES,
+            "Image too large for TIFF (exceeds 4 GiB)"
+        );
+
+        Ok(())
+    }
+
+    /// Write the image directory and return the underlying writer
+    pub fn finish(mut self) -> Result<W> {
+        ensure!(
+            self.rows_written == self.height,
+            "TIFF writer expected {} rows, got {}",
+            self.height,
+            self.rows_written
+        );
+
+        let offset_type = if self.big { LONG8 } else { LONG };
+        let bits_per_sample = vec![(self.bytes_per_channel * 8) as u64; self.channels as usize];
+        let compression = if self.deflate { 8 } else { 1 };
+        let strip_offsets = std::mem::take(&mut self.strip_offsets);
+        let strip_byte_counts = std::mem::take(&mut self.strip_byte_counts);
+
+        let mut entries = vec![
+            (256, LONG, vec![self.width as u64]),
+            (257, LONG, vec![self.height as u64]),
+            (258, SHORT, bits_per_sample),
+            (259, SHORT, vec![compression]),
+            (262, SHORT, vec![2]),
+            (273, offset_type, strip_offsets),
+            (277, SHORT, vec![self.channels as u64]),
+            (278, LONG, vec![self.rows_per_strip as u64]),
+            (279, offset_type, strip_byte_counts),
+            (284, SHORT, vec![1]),
+        ];
+        if self.channels == 4 {
+            let extra_samples = if self.associated_alpha { 1 } else { 2 };
+            entries.push((338, SHORT, vec![extra_samples]));
+        }
+        entries.sort_by_key(|e| e.0);
+
+        // Values too large for an entry's value field are written ahead of the directory
+        let inline_size = if self.big { 8 } else { 4 };
+        let mut fields = vec![];
+        for (tag, ty, values) in entries {
+            let bytes = encode_values(ty, &values);
+            let field = if bytes.len() <= inline_size {
+                // Inline values are left-justified within the value field
+                let mut field = bytes;
+                field.resize(inline_size, 0);
+                field
+            } else {
+                // Out-of-line values must begin on a word boundary
+                if self.position % 2 == 1 {
+                    self.writer.write_all(&[0])?;
+                    self.position += 1;
+                }
+                let offset = self.position;
+                self.writer.write_all(&bytes)?;
+                self.position += bytes.len() as u64;
+                self.encode_offset(offset)
+            };
+            fields.push((tag as u16, ty, values.len() as u64, field));
+        }
+
+        if self.position % 2 == 1 {
+            self.writer.write_all(&[0])?;
+            self.position += 1;
+        }
+
+        let ifd_offset = self.position;
+        if self.big {
+            self.writer
+                .write_all(&(fields.len() as u64).to_le_bytes())?;
+        } else {
+            self.writer
+                .write_all(&(fields.len() as u16).to_le_bytes())?;
+        }
+        for (tag, ty, count, field) in fields {
+            self.writer.write_all(&tag.to_le_bytes())?;
+            self.writer.write_all(&ty.to_le_bytes())?;
+            let count = self.encode_offset(count);
+            self.writer.write_all(&count)?;
+            self.writer.write_all(&field)?;
+        }
+        let next_ifd = self.encode_offset(0);
+        self.writer.write_all(&next_ifd)?;
+
+        // Point the header at the directory
+        let (header_offset, ifd_offset) = if self.big {
+            (8, self.encode_offset(ifd_offset))
+        } else {
+            (4, self.encode_offset(ifd_offset))
+        };
+        self.writer.seek(SeekFrom::Start(header_offset))?;
+        self.writer.write_all(&ifd_offset)?;
+        self.writer.seek(SeekFrom::End(0))?;
+        self.writer.flush()?;
+
+        Ok(self.writer)
+    }
+
+    /// Encode an offset or count at the width used by this file
+    fn encode_offset(&self, value: u64) -> Vec<u8> {
+        if self.big {
+            value.to_le_bytes().to_vec()
+        } else {
+            (value as u32).to_le_bytes().to_vec()
+        }
+    }
+}
+
+/// Check that `reader` holds a complete little-endian TIFF of the given size and channel count, as
+/// written by `TiffWriter`, by locating every strip and decompressing them one at a time
+pub fn verify<R: Read + Seek>(
+    mut reader: R,
+    (width, height): (u32, u32),
+    channels: u16,
+) -> Result<()> {
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    let mut read = |at: u64, len: usize| -> Result<u64> {
+        ensure!(
+            at.saturating_add(len as u64) <= file_len,
+            "TIFF is truncated"
+        );
+        let mut bytes = [0; 8];
+        reader.seek(SeekFrom::Start(at))?;
+        reader.read_exact(&mut bytes[..len])?;
+        Ok(u64::from_le_bytes(bytes))
+    };
+
+    ensure!(
+        read(0, 2)? == u16::from_le_bytes(*b"II") as u64,
+        "Not a little-endian TIFF"
+    );
+    let big = match read(2, 2)? {
+        42 => false,
+        43 => true,
+        version => bail!("Unknown TIFF version {}", version),
+    };
+    let (offset_len, count_len, entry_len) = if big { (8, 8, 20) } else { (4, 2, 12) };
+    let ifd = read(if big { 8 } else { 4 }, offset_len)?;
+
+    // Values of each tag, read out of line where they don't fit in the entry
+    let entries = read(ifd, count_len)?;
+    let mut tags = HashMap::new();
+    for i in 0..entries {
+        let entry = ifd + count_len as u64 + i * entry_len;
+        let tag = read(entry, 2)? as u16;
+        let size = match read(entry + 2, 2)? as u16 {
+            SHORT => 2,
+            LONG => 4,
+            LONG8 => 8,
+            _ => continue,
+        };
+        let count = read(entry + 4, offset_len)?;
+        let field = entry + 4 + offset_len as u64;
+        let at = if count * size as u64 <= offset_len as u64 {
+            field
+        } else {
+            read(field, offset_len)?
+        };
+        ensure!(
+            at.saturating_add(count.saturating_mul(size as u64)) <= file_len,
+            "TIFF is truncated"
+        );
+        let values = (0..count)
+            .map(|i| read(at + i * size as u64, size))
+            .collect::<Result<Vec<_>>>()?;
+        tags.insert(tag, values);
+    }
+    let tag = |tag: u16| -> Result<&Vec<u64>> {
+        tags.get(&tag)
+            .with_context(|| format!("TIFF is missing tag {}", tag))
+    };
+
+    ensure!(
+        tag(256)?[..] == [width as u64] && tag(257)?[..] == [height as u64],
+        "TIFF is not {}x{}",
+        width,
+        height
+    );
+    ensure!(
+        tag(277)?[..] == [channels as u64],
+        "TIFF doesn't have {} channels",
+        channels
+    );
+    let bytes_per_channel = (tag(258)?[0] / 8) as usize;
+    let deflate = tag(259)?[..] == [8];
+    let rows_per_strip = tag(278)?[0].max(1);
+    let (offsets, counts) = (tag(273)?, tag(279)?);
+    let strips = (height as u64).div_ceil(rows_per_strip);
+    ensure!(
+        offsets.len() as u64 == strips && counts.len() as u64 == strips,
+        "TIFF has the wrong number of strips"
+    );
+
+    let row_bytes = width as usize * channels as usize * bytes_per_channel;
+    let mut strip = vec![];
+    for (i, (&offset, &count)) in offsets.iter().zip(counts).enumerate() {
+        let rows = rows_per_strip.min(height as u64 - i as u64 * rows_per_strip) as usize;
+        ensure!(
+            offset.saturating_add(count) <= file_len,
+            "TIFF is truncated"
+        );
+        let len = if deflate {
+            strip.clear();
+            reader.seek(SeekFrom::Start(offset))?;
+            (&mut reader).take(count).read_to_end(&mut strip)?;
+            inflated_len(&strip)
+                .map_err(|e| anyhow!("TIFF strip {} doesn't decompress: {:?}", i, e))?
+        } else {
+            count as usize
+        };
+        ensure!(len == rows * row_bytes, "TIFF strip {} is incomplete", i);
+    }
+
+    Ok(())
+}
+
+/// Size of a zlib stream once inflated, decompressing it a chunk at a time
+fn inflated_len(data: &[u8]) -> Result<usize, MZError> {
+    let mut state = InflateState::new_boxed(DataFormat::Zlib);
+    let mut chunk = vec![0; STRIP_SIZE_BYTES];
+    let (mut at, mut len) = (0, 0);
+    loop {
+        let result = inflate(&mut state, &data[at..], &mut chunk, MZFlush::None);
+        at += result.bytes_consumed;
+        len += result.bytes_written;
+        match result.status? {
+            MZStatus::StreamEnd => return Ok(len),
+            _ if result.bytes_consumed == 0 && result.bytes_written == 0 => {
+                return Err(MZError::Buf)
+            }
+            _ => (),
+        }
+    }
+}
+
+fn encode_values(ty: u16, values: &[u64]) -> Vec<u8> {
+    let mut bytes = vec![];
+    for &v in values {
+        match ty {
+            SHORT => bytes.extend_from_slice(&(v as u16).to_le_bytes()),
+            LONG => bytes.extend_from_slice(&(v as u32).to_le_bytes()),
+            _ => bytes.extend_from_slice(&v.to_le_bytes()),
+        }
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::convert::TryInto;
+    use std::io::Cursor;
+
+    fn read_u16(buf: &[u8], at: usize) -> u16 {
+        u16::from_le_bytes([buf[at], buf[at + 1]])
+    }
+
+    fn read_u32(buf: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([buf[at], buf[at + 1], buf[at + 2], buf[at + 3]])
+    }
+
+    fn find_tag(buf: &[u8], tag: u16) -> (u16, u32, u32) {
+        let ifd = read_u32(buf, 4) as usize;
+        let n = read_u16(buf, ifd) as usize;
+        for i in 0..n {
+            let entry = ifd + 2 + i * 12;
+            if read_u16(buf, entry) == tag {
+                return (
+                    read_u16(buf, entry + 2),
+                    read_u32(buf, entry + 4),
+                    read_u32(buf, entry + 8),
+                );
+            }
+        }
+        panic!("Tag {} not found", tag);
+    }
+
+    #[test]
+    fn test_uncompressed_roundtrip() {
+        let (width, height) = (300, 500);
+        let data: Vec<u8> = (0..width * height * 3).map(|i| (i % 251) as u8).collect();
+
+        let mut tiff = TiffWriter::new(Cursor::new(vec![]), width, height, 3, 1, false).unwrap();
+        tiff.write_rows(&data).unwrap();
+        let buf = tiff.finish().unwrap().into_inner();
+
+        assert_eq!(&buf[..4], b"II\x2a\x00");
+        assert_eq!(find_tag(&buf, 256).2, width);
+        assert_eq!(find_tag(&buf, 257).2, height);
+
+        // Reassemble the strips
+        let (_, n_strips, offsets) = find_tag(&buf, 273);
+        let (_, _, counts) = find_tag(&buf, 279);
+        assert!(n_strips > 1);
+        let mut decoded = vec![];
+        for i in 0..n_strips as usize {
+            let offset = read_u32(&buf, offsets as usize + i * 4) as usize;
+            let count = read_u32(&buf, counts as usize + i * 4) as usize;
+            decoded.extend_from_slice(&buf[offset..offset + count]);
+        }
+        assert_eq!(decoded, data);
+    }
+
+    #[test]
+    fn test_sixteen_bit_deflate() {
+        let (width, height) = (4, 2);
+        let samples: Vec<u16> = (0..width * height * 3).map(|i| (i * 1000) as u16).collect();
+        let data: Vec<u8> = samples.iter().flat_map(|s| s.to_ne_bytes()).collect();
+
+        let mut tiff = TiffWriter::new(Cursor::new(vec![]), width, height, 3, 2, true).unwrap();
+        tiff.write_rows(&data).unwrap();
+        let buf = tiff.finish().unwrap().into_inner();
+
+        assert_eq!(find_tag(&buf, 259).2, 8);
+        let (_, n_strips, offset) = find_tag(&buf, 273);
+        let (_, _, count) = find_tag(&buf, 279);
+        assert_eq!(n_strips, 1);
+
+        let strip = &buf[offset as usize..][..count as usize];
+        let decoded = miniz_oxide::inflate::decompress_to_vec_zlib(strip).unwrap();
+        let expected: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        assert_eq!(decoded, expected);
+    }
+
+    #[test]
+    fn test_rgba_extra_samples() {
+        let mut tiff = TiffWriter::new(Cursor::new(vec![]), 1, 1, 4, 1, false).unwrap();
+        tiff.set_associated_alpha(true);
+        tiff.write_rows(&[1, 2, 3, 4]).unwrap();
+        let buf = tiff.finish().unwrap().into_inner();
+
+        assert_eq!(find_tag(&buf, 277).2, 4);
+        assert_eq!(find_tag(&buf, 338).2, 1);
+    }
+
+    #[test]
+    fn test_rejects_short_image() {
+        let mut tiff = TiffWriter::new(Cursor::new(vec![]), 2, 2, 3, 1, false).unwrap();
+        tiff.write_rows(&[0; 6]).unwrap();
+        assert!(tiff.finish().is_err());
+    }
+
+    #[test]
+    fn test_verify() {
+        let (width, height) = (300, 500);
+        let data: Vec<u8> = (0..width * height * 3).map(|i| (i % 251) as u8).collect();
+
+        for &(deflate, big) in &[(false, false), (true, false), (false, true)] {
+            let mut tiff =
+                TiffWriter::create(Cursor::new(vec![]), width, height, 3, 1, deflate, big).unwrap();
+            tiff.write_rows(&data).unwrap();
+            let buf = tiff.finish().unwrap().into_inner();
+
+            assert!(verify(Cursor::new(&buf), (width, height), 3).is_ok());
+            assert!(verify(Cursor::new(&buf), (width, height + 1), 3).is_err());
+            assert!(verify(Cursor::new(&buf), (width, height), 4).is_err());
+
+            // Truncating the file loses strips or the directory
+            assert!(verify(Cursor::new(&buf[..buf.len() / 2]), (width, height), 3).is_err());
+        }
+    }
+
+    #[test]
+    fn test_bigtiff_layout() {
+        let (width, height) = (2, 3);
+        let data: Vec<u8> = (0..width * height * 3).map(|i| i as u8).collect();
+
+        let mut tiff =
+            TiffWriter::create(Cursor::new(vec![]), width, height, 3, 1, false, true).unwrap();
+        tiff.write_rows(&data).unwrap();
+        let buf = tiff.finish().unwrap().into_inner();
+
+        assert_eq!(&buf[..8], b"II\x2b\x00\x08\x00\x00\x00");
+        assert_eq!(&buf[16..16 + data.len()], &data[..]);
+
+        // Find the strip offset among the 20-byte entries
+        let read_u64 = |at: usize| u64::from_le_bytes(buf[at..at + 8].try_into().unwrap());
+        let ifd = read_u64(8) as usize;
+        let entries = read_u64(ifd) as usize;
+        let strip_offset = (0..entries)
+            .map(|i| ifd + 8 + i * 20)
+            .find(|&entry| read_u16(&buf, entry) == 273)
+            .unwrap();
+        assert_eq!(read_u16(&buf, strip_offset + 2), LONG8);
+        assert_eq!(read_u64(strip_offset + 4), 1);
+        assert_eq!(read_u64(strip_offset + 12), 16);
+        assert_eq!(read_u64(ifd + 8 + entries * 20), 0);
+    }
+}
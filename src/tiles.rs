@@ -1,3 +1,46 @@
+use anyhow::{ensure, Context, Error};
+use std::str::FromStr;
+
+/// Rectangle of an image in pixels, measured from the top left
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl FromStr for Rect {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts = s
+            .split(',')
+            .map(|p| p.trim().parse::<u32>())
+            .collect::<Result<Vec<_>, _>>()
+            .with_context(|| format!("Invalid rectangle \"{}\"", s))?;
+        ensure!(
+            parts.len() == 4,
+            "A rectangle needs 4 numbers (x,y,width,height), got {}",
+            parts.len()
+        );
+        ensure!(parts[2] > 0 && parts[3] > 0, "Rectangle \"{}\" is empty", s);
+        Ok(Self {
+            x: parts[0],
+            y: parts[1],
+            width: parts[2],
+            height: parts[3],
+        })
+    }
+}
+
+impl Rect {
+    /// Whether the rectangle lies within an image of the given size
+    pub fn fits(&self, (width, height): (u32, u32)) -> bool {
+        self.x as u64 + self.width as u64 <= width as u64
+            && self.y as u64 + self.height as u64 <= height as u64
+    }
+}
+
 /// Produces the coordinates of top-left corners of tiles for the given image and tile dimensions
 pub fn tiles(
     (image_width, image_height): (usize, usize),
@@ -21,13 +64,25 @@ const BYTES_PER_PIXEL: usize = 3;
 
 /// Blit from `src` to `(x, y)` in `dest` with the given dimensions of each. Assumes RGB at 8BPP
 pub fn blit_rgb(
+    src: &[u8],
+    dest: &mut [u8],
+    pos: (usize, usize),
+    dest_dims: (usize, usize),
+    src_dims: (usize, usize),
+) {
+    blit(src, dest, pos, dest_dims, src_dims, BYTES_PER_PIXEL)
+}
+
+/// Blit from `src` to `(x, y)` in `dest` with the given dimensions of each, for any pixel size
+pub fn blit(
     src: &[u8],
     dest: &mut [u8],
     (x, y): (usize, usize),
     (dest_width, dest_height): (usize, usize),
     (src_width, src_height): (usize, usize),
+    bytes_per_pixel: usize,
 ) {
-    debug_assert_eq!(src.len() % BYTES_PER_PIXEL, 0);
+    debug_assert_eq!(src.len() % bytes_per_pixel, 0);
 
     debug_assert_eq!(src.len() % src_height, 0);
     debug_assert_eq!(dest.len() % dest_height, 0);
@@ -35,24 +90,83 @@ pub fn blit_rgb(
     debug_assert_eq!(src.len() % src_width, 0);
     debug_assert_eq!(dest.len() % dest_width, 0);
 
-    debug_assert_eq!(src.len() / src_width, src_height * BYTES_PER_PIXEL);
-    debug_assert_eq!(dest.len() / dest_width, dest_height * BYTES_PER_PIXEL);
+    debug_assert_eq!(src.len() / src_width, src_height * bytes_per_pixel);
+    debug_assert_eq!(dest.len() / dest_width, dest_height * bytes_per_pixel);
 
     for (src_row, dest_row) in src
-        .chunks_exact(BYTES_PER_PIXEL * src_width)
-        .zip(dest.chunks_exact_mut(BYTES_PER_PIXEL * dest_width).skip(y))
+        .chunks_exact(bytes_per_pixel * src_width)
+        .zip(dest.chunks_exact_mut(bytes_per_pixel * dest_width).skip(y))
     {
         let length_pixels = (x + src_width).min(dest_width) - x;
 
-        dest_row[x * BYTES_PER_PIXEL..][..length_pixels * BYTES_PER_PIXEL]
-            .copy_from_slice(&src_row[..length_pixels * BYTES_PER_PIXEL])
+        dest_row[x * bytes_per_pixel..][..length_pixels * bytes_per_pixel]
+            .copy_from_slice(&src_row[..length_pixels * bytes_per_pixel])
     }
 }
 
+/// Split a rect into a grid of up to `pieces` by `pieces` rects of nearly equal size, row by row
+pub fn split(rect: Rect, pieces: u32) -> Vec<Rect> {
+    let columns = pieces.clamp(1, rect.width);
+    let rows = pieces.clamp(1, rect.height);
+    let edge = |start: u32, length: u32, count: u32, i: u32| {
+        start + (length as u64 * i as u64 / count as u64) as u32
+    };
+    (0..rows)
+        .flat_map(|row| {
+            let top = edge(rect.y, rect.height, rows, row);
+            let bottom = edge(rect.y, rect.height, rows, row + 1);
+            (0..columns).map(move |column| {
+                let left = edge(rect.x, rect.width, columns, column);
+                let right = edge(rect.x, rect.width, columns, column + 1);
+                Rect {
+                    x: left,
+                    y: top,
+                    width: right - left,
+                    height: bottom - top,
+                }
+            })
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_rect() {
+        let rect: Rect = "10, 20,300,400".parse().unwrap();
+        assert_eq!(
+            rect,
+            Rect {
+                x: 10,
+                y: 20,
+                width: 300,
+                height: 400
+            }
+        );
+        assert!(rect.fits((310, 420)));
+        assert!(!rect.fits((309, 420)));
+
+        assert!("1,2,3".parse::<Rect>().is_err());
+        assert!("0,0,0,5".parse::<Rect>().is_err());
+        assert!("0,0,-1,5".parse::<Rect>().is_err());
+    }
+
+    #[test]
+    fn test_split() {
+        let rect: Rect = "10,20,5,100".parse().unwrap();
+        assert_eq!(split(rect, 1), vec![rect]);
+
+        let pieces = split(rect, 8);
+        assert_eq!(pieces.len(), 5 * 8);
+        assert_eq!(pieces[0], "10,20,1,12".parse().unwrap());
+        assert_eq!(pieces[5], "10,32,1,13".parse().unwrap());
+        let area: u32 = pieces.iter().map(|piece| piece.width * piece.height).sum();
+        assert_eq!(area, 5 * 100);
+        assert_eq!(pieces.last().unwrap(), &"14,107,1,13".parse().unwrap());
+    }
+
     #[test]
     fn test_tiling() {
         let output_dims = (100, 200);
@@ -0,0 +1,117 @@
+use anyhow::{bail, ensure, Context, Error, Result};
+use std::str::FromStr;
+
+/// A uniform of the shader fixed to a constant, given as "name=value", with the components of
+/// vectors separated by commas, e.g. "u_speed=2" or "u_color=1,0.5,0"
+#[derive(Debug, Clone, PartialEq)]
+pub struct Uniform {
+    pub name: String,
+    pub values: Vec<f32>,
+}
+
+impl FromStr for Uniform {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, values) = s
+            .split_once('=')
+            .context("Uniforms must be given as name=value")?;
+        let name = name.trim();
+        ensure!(
+            name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+                && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+            "Invalid uniform name \"{}\"",
+            name
+        );
+        let values = values
+            .split(',')
+            .map(|value| {
+                value
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("Invalid value \"{}\" for uniform {}", value, name))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            name: name.into(),
+            values,
+        })
+    }
+}
+
+/// Replace the shader's declarations of the given uniforms with constants
+pub fn bind(source: &str, uniforms: &[Uniform]) -> Result<String> {
+    let mut lines: Vec<String> = source.lines().map(String::from).collect();
+    for uniform in uniforms {
+        let (line, ty) = lines
+            .iter()
+            .enumerate()
+            .find_map(|(idx, line)| Some((idx, declared_type(line, &uniform.name)?)))
+            .with_context(|| format!("Shader has no uniform {}", uniform.name))?;
+        ensure!(
+            components(&ty)? == uniform.values.len(),
+            "Uniform {} is a {}, but {} values were given",
+            uniform.name,
+            ty,
+            uniform.values.len()
+        );
+        let values: Vec<String> = uniform.values.iter().map(|v| format!("{:?}", v)).collect();
+        lines[line] = format!(
+            "const {ty} {} = {ty}({});",
+            uniform.name,
+            values.join(", "),
+            ty = ty
+        );
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Type of the uniform `name`, if `line` declares it
+fn declared_type(line: &str, name: &str) -> Option<String> {
+    let declaration = line.trim().strip_suffix(';')?;
+    let mut words: Vec<&str> = declaration.split_whitespace().collect();
+    words.retain(|word| !matches!(*word, "lowp" | "mediump" | "highp"));
+    match words[..] {
+        ["uniform", ty, declared] if declared == name => Some(ty.into()),
+        _ => None,
+    }
+}
+
+/// Number of components of a scalar or vector type
+fn components(ty: &str) -> Result<usize> {
+    Ok(match ty {
+        "float" | "int" | "uint" | "bool" => 1,
+        _ => match ty.trim_start_matches(['i', 'u', 'b']) {
+            "vec2" => 2,
+            "vec3" => 3,
+            "vec4" => 4,
+            _ => bail!("Uniforms of type {} can't be set", ty),
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bind_uniforms() {
+        let source = "uniform float u_time;\nuniform highp vec3 u_color;\nuniform int u_steps;\n";
+        let uniforms: Vec<Uniform> = ["u_color=1,0.5,0", "u_steps = 4"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(
+            bind(source, &uniforms).unwrap(),
+            "uniform float u_time;\nconst vec3 u_color = vec3(1.0, 0.5, 0.0);\n\
+             const int u_steps = int(4.0);\n"
+        );
+
+        let missing: Uniform = "u_speed=1".parse().unwrap();
+        assert!(bind(source, &[missing]).is_err());
+        let wrong_size: Uniform = "u_color=1,2".parse().unwrap();
+        assert!(bind(source, &[wrong_size]).is_err());
+        assert!("u_color".parse::<Uniform>().is_err());
+        assert!("1x=2".parse::<Uniform>().is_err());
+        assert!("u_x=a".parse::<Uniform>().is_err());
+    }
+}
@@ -0,0 +1,274 @@
+use crate::animation::{ApngSink, GifSink};
+use crate::output::{channels, Sink};
+use crate::settings::Settings;
+use anyhow::{bail, Context, Result};
+use std::fs::File;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use std::process::{Child, ChildStdin, Command, Stdio};
+
+/// Open a video sink for `path` based on its extension; YUV4MPEG2 for ".y4m" or "-" (stdout),
+/// GIF or APNG for animations and ffmpeg otherwise
+pub fn open(cfg: &Settings, path: &Path) -> Result<Box<dyn Sink>> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_lowercase());
+
+    match extension.as_deref() {
+        Some("gif") => return Ok(Box::new(GifSink::new(cfg, path)?)),
+        Some("apng") | Some("png") => return Ok(Box::new(ApngSink::new(cfg, path)?)),
+        _ => (),
+    }
+
+    let is_y4m = path == Path::new("-") || extension.as_deref() == Some("y4m");
+
+    if is_y4m {
+        let writer: Box<dyn Write> = if path == Path::new("-") {
+            Box::new(std::io::stdout())
+        } else {
+            let file = File::create(path)
+                .with_context(|| format!("Failed to create video {}", path.display()))?;
+            Box::new(file)
+        };
+        Ok(Box::new(Y4mWriter::new(BufWriter::new(writer), cfg)?))
+    } else {
+        Ok(Box::new(FfmpegSink::spawn(cfg, path)?))
+    }
+}
+
+/// Writes frames as an uncompressed YUV4MPEG2 stream with 4:2:0 BT.709 limited range chroma
+pub struct Y4mWriter<W: Write> {
+    writer: W,
+    width: usize,
+    height: usize,
+    channels: usize,
+    bytes_per_channel: usize,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(mut writer: W, cfg: &Settings) -> Result<Self> {
+        let (num, den) = cfg.frame_rate();
+        writeln!(
+            writer,
+            "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C420jpeg XCOLORRANGE=LIMITED",
+            cfg.width, cfg.height, num, den
+        )?;
+
+        Ok(Self {
+            writer,
+            width: cfg.width as usize,
+            height: cfg.height as usize,
+            channels: channels(cfg),
+            bytes_per_channel: cfg.depth.bytes_per_channel(),
+        })
+    }
+}
+
+impl<W: Write> Sink for Y4mWriter<W> {
+    fn write_frame(&mut self, _frame_idx: usize, data: &[u8]) -> Result<()> {
+        let (y, u, v) = rgb_to_yuv420(
+            data,
+            (self.width, self.height),
+            self.channels,
+            self.bytes_per_channel,
+        );
+        self.writer.write_all(b"FRAME\n")?;
+        self.writer.write_all(&y)?;
+        self.writer.write_all(&u)?;
+        self.writer.write_all(&v)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Convert interleaved RGB(A) to planar BT.709 limited range YUV with chroma averaged over 2x2
+/// blocks. Alpha is ignored and 16-bit samples are expected in native byte order
+pub fn rgb_to_yuv420(
+    data: &[u8],
+    (width, height): (usize, usize),
+    channels: usize,
+    bytes_per_channel: usize,
+) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let (chroma_width, chroma_height) = (width.div_ceil(2), height.div_ceil(2));
+    let mut y_plane = Vec::with_capacity(width * height);
+    let mut cb_sum = vec![0f32; chroma_width * chroma_height];
+    let mut cr_sum = vec![0f32; chroma_width * chroma_height];
+    let mut counts = vec![0u8; chroma_width * chroma_height];
+
+    let sample = |px: &[u8], c: usize| -> f32 {
+        match bytes_per_channel {
+            1 => px[c] as f32 / 255.,
+            _ => u16::from_ne_bytes([px[c * 2], px[c * 2 + 1]]) as f32 / 65535.,
+        }
+    };
+
+    let pixel_size = channels * bytes_per_channel;
+    for (row_idx, row) in data.chunks_exact(width * pixel_size).enumerate() {
+        for (col_idx, px) in row.chunks_exact(pixel_size).enumerate() {
+            let (r, g, b) = (sample(px, 0), sample(px, 1), sample(px, 2));
+            let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+            y_plane.push(quantize(16. + 219. * luma));
+
+            let chroma_idx = (row_idx / 2) * chroma_width + col_idx / 2;
+            cb_sum[chroma_idx] += (b - luma) / 1.8556;
+            cr_sum[chroma_idx] += (r - luma) / 1.5748;
+            counts[chroma_idx] += 1;
+        }
+    }
+
+    let finish_chroma = |sums: Vec<f32>| -> Vec<u8> {
+        sums.iter()
+            .zip(&counts)
+            .map(|(&sum, &count)| quantize(128. + 224. * sum / count as f32))
+            .collect()
+    };
+
+    (y_plane, finish_chroma(cb_sum), finish_chroma(cr_sum))
+}
+
+fn quantize(v: f32) -> u8 {
+    v.round().clamp(0., 255.) as u8
+}
+
+/// Pipes raw frames into an ffmpeg process
+pub struct FfmpegSink {
+    child: Child,
+    stdin: Option<ChildStdin>,
+}
+
+impl FfmpegSink {
+    pub fn spawn(cfg: &Settings, path: &Path) -> Result<Self> {
+        let input_pix_fmt = match (channels(cfg), cfg.depth.bytes_per_channel()) {
+            (3, 1) => "rgb24",
+            (4, 1) => "rgba",
+            (3, _) if cfg!(target_endian = "little") => "rgb48le",
+            (3, _) => "rgb48be",
+            (_, _) if cfg!(target_endian = "little") => "rgba64le",
+            (_, _) => "rgba64be",
+        };
+        let (num, den) = cfg.frame_rate();
+
+        let mut command = Command::new(&cfg.ffmpeg);
+        command
+            .args(["-hide_banner", "-loglevel", "error", "-y"])
+            .args(["-f", "rawvideo", "-pix_fmt", input_pix_fmt])
+            .arg("-s")
+            .arg(format!("{}x{}", cfg.width, cfg.height))
+            .arg("-r")
+            .arg(format!("{}/{}", num, den))
+            .args(["-i", "-"]);
+
+        if let Some(audio) = &cfg.audio {
+            command
+                .arg("-i")
+                .arg(audio)
+                .args(["-map", "0:v", "-map", "1:a"])
+                .args(["-c:a", "aac", "-shortest"]);
+        }
+
+        command.args(["-c:v", &cfg.codec]);
+        if let Some(crf) = cfg.crf {
+            command.arg("-crf").arg(crf.to_string());
+        }
+        command
+            .args(["-pix_fmt", &cfg.pix_fmt])
+            .arg(path)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null());
+
+        let mut child = match command.spawn() {
+            Ok(child) => child,
+            Err(e) if e.kind() == ErrorKind::NotFound => bail!(
+                "Could not find ffmpeg at \"{}\"; install it or pass --ffmpeg",
+                cfg.ffmpeg.display()
+            ),
+            Err(e) => return Err(e).context("Failed to start ffmpeg"),
+        };
+
+        let stdin = child.stdin.take();
+
+        Ok(Self { child, stdin })
+    }
+
+    /// Reap the process and report its exit status
+    fn wait(&mut self) -> Result<()> {
+        drop(self.stdin.take());
+        let status = self.child.wait().context("Waiting for ffmpeg")?;
+        if !status.success() {
+            bail!("ffmpeg exited with {}", status);
+        }
+        Ok(())
+    }
+}
+
+impl Sink for FfmpegSink {
+    fn write_frame(&mut self, _frame_idx: usize, data: &[u8]) -> Result<()> {
+        let stdin = self.stdin.as_mut().context("ffmpeg input already closed")?;
+        if let Err(e) = stdin.write_all(data) {
+            // ffmpeg quit early; its exit status is more useful than the broken pipe
+            self.wait()?;
+            return Err(e).context("Writing to ffmpeg");
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.wait()
+    }
+}
+
+impl Drop for FfmpegSink {
+    fn drop(&mut self) {
+        // Dropped unfinished after an error. Stop ffmpeg rather than let it finalize a truncated
+        // video, and reap it
+        if let Some(stdin) = self.stdin.take() {
+            let _ = self.child.kill();
+            drop(stdin);
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_yuv_primaries() {
+        // White, black, red and blue in one 2x2 block, followed by a lone white column
+        #[rustfmt::skip]
+        let data = [
+            255, 255, 255,  0, 0, 0,    255, 255, 255,
+            255, 0, 0,      0, 0, 255,  255, 255, 255,
+        ];
+        let (y, u, v) = rgb_to_yuv420(&data, (3, 2), 3, 1);
+
+        assert_eq!(y, vec![235, 16, 235, 63, 32, 235]);
+        assert_eq!(u.len(), 2);
+        assert_eq!(v.len(), 2);
+        assert_eq!((u[1], v[1]), (128, 128));
+
+        // Average of the first block: (0 + 0 + Cb(red) + Cb(blue)) / 4
+        let cb = (-0.2126 / 1.8556 + 0.5) / 4.;
+        let cr = (0.5 + -0.0722 / 1.5748) / 4.;
+        assert_eq!(u[0], quantize(128. + 224. * cb));
+        assert_eq!(v[0], quantize(128. + 224. * cr));
+    }
+
+    #[test]
+    fn test_yuv_sixteen_bit() {
+        let data: Vec<u8> = [
+            65535u16, 65535, 65535, 0, 0, 0, 0, 0, 0, 65535, 65535, 65535,
+        ]
+        .iter()
+        .flat_map(|s| s.to_ne_bytes())
+        .collect();
+        let (y, u, v) = rgb_to_yuv420(&data, (2, 2), 3, 2);
+        assert_eq!(y, vec![235, 16, 16, 235]);
+        assert_eq!((u[0], v[0]), (128, 128));
+    }
+}
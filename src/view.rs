@@ -0,0 +1,166 @@
+use anyhow::{bail, ensure, Context, Error, Result};
+use std::str::FromStr;
+
+/// 2x3 affine transform, row-major: `[a, b, c, d, e, f]` maps `(x, y)` to
+/// `(a * x + b * y + c, d * x + e * y + f)`
+pub type Affine = [f32; 6];
+
+/// Transform from output pixel coordinates (y up, as the shader sees them) to the coordinates
+/// passed to the shader
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    /// An explicit matrix
+    Matrix(Affine),
+    /// Show the region around `center` (defaulting to the middle of the image) magnified by
+    /// `zoom`, through a view turned counterclockwise by `rotate` degrees and optionally mirrored
+    /// horizontally
+    Shorthand {
+        center: Option<(f32, f32)>,
+        zoom: f32,
+        rotate: f32,
+        mirror: bool,
+    },
+}
+
+impl FromStr for View {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split(',').map(str::trim).collect();
+
+        // Six bare numbers are a matrix
+        if parts.iter().all(|p| p.parse::<f32>().is_ok()) {
+            ensure!(
+                parts.len() == 6,
+                "A view matrix needs 6 numbers (a,b,c,d,e,f), got {}",
+                parts.len()
+            );
+            let mut matrix = [0.; 6];
+            for (m, p) in matrix.iter_mut().zip(&parts) {
+                *m = p.parse()?;
+            }
+            return Ok(View::Matrix(matrix));
+        }
+
+        let number = |key: &str, value: &str| -> Result<f32> {
+            value
+                .parse()
+                .with_context(|| format!("Invalid {} \"{}\" in view", key, value))
+        };
+
+        let mut center = None;
+        let mut zoom = 1.;
+        let mut rotate = 0.;
+        let mut mirror = false;
+        for part in parts {
+            match part.split_once('=') {
+                Some(("center", value)) => {
+                    let (x, y) = value
+                        .split_once(':')
+                        .context("View center must be given as x:y")?;
+                    center = Some((number("center", x)?, number("center", y)?));
+                }
+                Some(("zoom", value)) => zoom = number("zoom", value)?,
+                Some(("rotate", value)) => rotate = number("rotate", value)?,
+                None if part == "mirror" => mirror = true,
+                _ => bail!(
+                    "Unknown view setting \"{}\", expected center=x:y, zoom=, rotate= or mirror",
+                    part
+                ),
+            }
+        }
+        ensure!(zoom != 0., "View zoom must not be zero");
+
+        Ok(View::Shorthand {
+            center,
+            zoom,
+            rotate,
+            mirror,
+        })
+    }
+}
+
+impl View {
+    /// The transform as a matrix, for an image of the given size
+    pub fn matrix(&self, (width, height): (u32, u32)) -> Affine {
+        match *self {
+            View::Matrix(matrix) => matrix,
+            View::Shorthand {
+                center,
+                zoom,
+                rotate,
+                mirror,
+            } => {
+                let middle = (width as f32 / 2., height as f32 / 2.);
+                let (cx, cy) = center.unwrap_or(middle);
+                let (sin, cos) = rotate.to_radians().sin_cos();
+                let flip = if mirror { -1. } else { 1. };
+
+                // Move the middle of the image to the origin, mirror, rotate, scale down, then
+                // move to the center of interest
+                let (a, b) = (flip * cos / zoom, -sin / zoom);
+                let (d, e) = (flip * sin / zoom, cos / zoom);
+                [
+                    a,
+                    b,
+                    cx - a * middle.0 - b * middle.1,
+                    d,
+                    e,
+                    cy - d * middle.0 - e * middle.1,
+                ]
+            }
+        }
+    }
+}
+
+/// Apply a transform to a point
+pub fn transform(m: &Affine, (x, y): (f32, f32)) -> (f32, f32) {
+    (m[0] * x + m[1] * y + m[2], m[3] * x + m[4] * y + m[5])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_near((x, y): (f32, f32), (ex, ey): (f32, f32)) {
+        assert!(
+            (x - ex).abs() < 1e-3 && (y - ey).abs() < 1e-3,
+            "({}, {}) != ({}, {})",
+            x,
+            y,
+            ex,
+            ey
+        );
+    }
+
+    #[test]
+    fn test_parse_matrix() {
+        let view: View = "1,0,10,0,-1,20".parse().unwrap();
+        assert_eq!(view, View::Matrix([1., 0., 10., 0., -1., 20.]));
+        assert!("1,0,10".parse::<View>().is_err());
+    }
+
+    #[test]
+    fn test_shorthand_zoom() {
+        let view: View = "zoom=4,center=100:50".parse().unwrap();
+        let m = view.matrix((800, 600));
+
+        // The middle of the image shows the center of interest, and the edges are 4x closer
+        assert_near(transform(&m, (400., 300.)), (100., 50.));
+        assert_near(transform(&m, (800., 300.)), (200., 50.));
+        assert_near(transform(&m, (400., 0.)), (100., -25.));
+    }
+
+    #[test]
+    fn test_shorthand_rotate_mirror() {
+        let m = "rotate=90".parse::<View>().unwrap().matrix((200, 200));
+        // A step right on screen is a step up on the canvas
+        assert_near(transform(&m, (110., 100.)), (100., 110.));
+
+        let m = "mirror".parse::<View>().unwrap().matrix((200, 100));
+        assert_near(transform(&m, (0., 30.)), (200., 30.));
+        assert_near(transform(&m, (100., 30.)), (100., 30.));
+
+        assert!("zoom=0".parse::<View>().is_err());
+        assert!("spin=3".parse::<View>().is_err());
+    }
+}